@@ -6,11 +6,21 @@ use super::Attest;
 use anyhow::*;
 use async_trait::async_trait;
 use jsonwebtoken::{decode, decode_header, jwk, DecodingKey, Validation};
-use kbs_types::{Attestation, Tee};
+use kbs_types::Tee;
 use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::BufReader;
+use std::time::Duration;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The evidence of the `Attestation` payload, whatever its TEE key type.
+#[derive(Deserialize, Debug)]
+struct AmberAttestation {
+    #[serde(rename = "tee-evidence")]
+    tee_evidence: String,
+}
 
 #[derive(Deserialize, Debug)]
 struct TdxEvidence {
@@ -44,16 +54,18 @@ pub struct AmberConfig {
 pub struct Amber {
     config: AmberConfig,
     certs: jwk::JwkSet,
+    // The client keeps a connection pool and can be shared by concurrent requests.
+    client: reqwest::Client,
 }
 
 #[async_trait]
 impl Attest for Amber {
-    async fn verify(&mut self, tee: Tee, _nonce: &str, attestation: &str) -> Result<String> {
+    async fn verify(&self, tee: Tee, _nonce: &str, attestation: &str) -> Result<String> {
         if tee != Tee::Tdx {
             bail!("Only implement for tdx now");
         }
         // get quote
-        let attestation = serde_json::from_str::<Attestation>(attestation)
+        let attestation = serde_json::from_str::<AmberAttestation>(attestation)
             .map_err(|e| anyhow!("Deserialize Attestation failed: {:?}", e))?;
         let tdx_evidence = serde_json::from_str::<TdxEvidence>(&attestation.tee_evidence)
             .map_err(|e| anyhow!("Deserialize TDX Evidence failed: {:?}", e))?;
@@ -68,8 +80,8 @@ impl Attest for Amber {
 
         // send attest request
         log::info!("post attestation request ...");
-        let client = reqwest::Client::new();
-        let resp = client
+        let resp = self
+            .client
             .post(format!("{}/appraisal/v1/attest", &self.config.base_url))
             .header(CONTENT_TYPE, "application/json")
             .header(ACCEPT, "application/json")
@@ -114,6 +126,22 @@ impl Attest for Amber {
 
         Ok(resp_data.token.clone())
     }
+
+    async fn check(&self) -> Result<()> {
+        let resp = self
+            .client
+            .get(&self.config.base_url)
+            .timeout(CHECK_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Reach Amber failed: {:?}", e))?;
+
+        if resp.status().is_server_error() {
+            bail!("Amber is unavailable: response status={}", resp.status());
+        }
+
+        Ok(())
+    }
 }
 
 impl Amber {
@@ -126,6 +154,7 @@ impl Amber {
             config: config.clone(),
             certs: serde_json::from_reader(reader)
                 .map_err(|e| anyhow!("Deserialize certs failed: {:?}", e))?,
+            client: reqwest::Client::new(),
         })
     }
 }
@@ -7,25 +7,32 @@ use anyhow::*;
 use async_trait::async_trait;
 use attestation_service::{config::Config as AsConfig, AttestationService};
 use kbs_types::Tee;
+use tokio::sync::RwLock;
 
 pub struct Native {
-    inner: AttestationService,
+    // Evaluation only needs shared access, so verifications run in parallel
+    // and only policy updates take the write lock.
+    inner: RwLock<AttestationService>,
 }
 
 #[async_trait]
 impl Attest for Native {
-    async fn set_policy(&mut self, input: as_types::SetPolicyInput) -> Result<()> {
-        self.inner.set_policy(input).await
+    async fn set_policy(&self, input: as_types::SetPolicyInput) -> Result<()> {
+        self.inner.write().await.set_policy(input).await
     }
-    async fn verify(&mut self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
-        self.inner.evaluate(tee, nonce, attestation).await
+    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
+        self.inner
+            .read()
+            .await
+            .evaluate(tee, nonce, attestation)
+            .await
     }
 }
 
 impl Native {
     pub fn new(config: &AsConfig) -> Result<Self> {
         Ok(Self {
-            inner: AttestationService::new(config.clone())?,
+            inner: RwLock::new(AttestationService::new(config.clone())?),
         })
     }
 }
@@ -8,7 +8,8 @@ use async_trait::async_trait;
 use kbs_types::Tee;
 use log::info;
 use serde::Deserialize;
-use tonic::transport::Channel;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
 
 use self::attestation::{
     attestation_service_client::AttestationServiceClient, AttestationRequest, SetPolicyRequest,
@@ -23,6 +24,8 @@ mod attestation {
 
 pub const DEFAULT_AS_ADDR: &str = "http://127.0.0.1:50004";
 
+const CHECK_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
 fn to_grpc_tee(tee: Tee) -> GrpcTee {
     match tee {
         Tee::AzSnpVtpm => GrpcTee::AzSnpVtpm,
@@ -50,7 +53,10 @@ impl Default for GrpcConfig {
 }
 
 pub struct Grpc {
+    // Cloning the client is cheap and shares the underlying channel, which
+    // multiplexes concurrent requests. Each call works on its own clone.
     inner: AttestationServiceClient<Channel>,
+    as_addr: String,
 }
 
 impl Grpc {
@@ -64,20 +70,21 @@ impl Grpc {
         };
 
         info!("connect to remote AS [{as_addr}]");
-        let inner = AttestationServiceClient::connect(as_addr).await?;
-        Ok(Self { inner })
+        let inner = AttestationServiceClient::connect(as_addr.clone()).await?;
+        Ok(Self { inner, as_addr })
     }
 }
 
 #[async_trait]
 impl Attest for Grpc {
-    async fn set_policy(&mut self, input: as_types::SetPolicyInput) -> Result<()> {
+    async fn set_policy(&self, input: as_types::SetPolicyInput) -> Result<()> {
         let req = tonic::Request::new(SetPolicyRequest {
             input: serde_json::to_string(&input)?,
         });
 
         let _ = self
             .inner
+            .clone()
             .set_attestation_policy(req)
             .await
             .map_err(|e| anyhow!("Set Policy Failed: {:?}", e))?;
@@ -85,7 +92,7 @@ impl Attest for Grpc {
         Ok(())
     }
 
-    async fn verify(&mut self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
+    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
         let req = tonic::Request::new(AttestationRequest {
             tee: to_grpc_tee(tee) as i32,
             nonce: String::from(nonce),
@@ -94,6 +101,7 @@ impl Attest for Grpc {
 
         let token = self
             .inner
+            .clone()
             .attestation_evaluate(req)
             .await?
             .into_inner()
@@ -101,4 +109,16 @@ impl Attest for Grpc {
 
         Ok(token)
     }
+
+    async fn check(&self) -> Result<()> {
+        // The shared channel reconnects lazily, so probe the AS with a new
+        // connection.
+        Endpoint::from_shared(self.as_addr.clone())?
+            .connect_timeout(CHECK_CONNECT_TIMEOUT)
+            .connect()
+            .await
+            .map_err(|e| anyhow!("Connect to remote AS failed: {:?}", e))?;
+
+        Ok(())
+    }
 }
@@ -12,7 +12,6 @@ use attestation_service::config::Config as AsConfig;
 use coco::grpc::GrpcConfig;
 use kbs_types::Tee;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 #[cfg(feature = "coco-as")]
 #[allow(missing_docs)]
@@ -24,28 +23,36 @@ pub mod amber;
 /// Interface for Attestation Services.
 ///
 /// Attestation Service implementations should implement this interface.
+/// The methods take `&self` so that evidence from different guests can be
+/// verified concurrently. Implementations must synchronize any mutable
+/// state internally.
 #[async_trait]
 pub trait Attest: Send + Sync {
     /// Set Attestation Policy
-    async fn set_policy(&mut self, _input: as_types::SetPolicyInput) -> Result<()> {
+    async fn set_policy(&self, _input: as_types::SetPolicyInput) -> Result<()> {
         Err(anyhow!("Set Policy API is unimplemented"))
     }
 
     /// Verify Attestation Evidence
     /// Return Attestation Results Token
-    async fn verify(&mut self, tee: Tee, nonce: &str, attestation: &str) -> Result<String>;
+    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String>;
+
+    /// Check that the attestation backend can be reached. An in-process
+    /// backend is ready once initialized.
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Attestation Service
 #[derive(Clone)]
-pub struct AttestationService(pub Arc<Mutex<dyn Attest>>);
+pub struct AttestationService(pub Arc<dyn Attest>);
 
 impl AttestationService {
     /// Create and initialize AttestationService.
     #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
     pub fn new(config: &AsConfig) -> Result<Self> {
-        let attestation_service: Arc<Mutex<dyn Attest>> =
-            Arc::new(Mutex::new(coco::builtin::Native::new(config)?));
+        let attestation_service: Arc<dyn Attest> = Arc::new(coco::builtin::Native::new(config)?);
 
         Ok(Self(attestation_service))
     }
@@ -53,8 +60,7 @@ impl AttestationService {
     /// Create and initialize AttestationService.
     #[cfg(feature = "coco-as-grpc")]
     pub async fn new(config: &GrpcConfig) -> Result<Self> {
-        let attestation_service: Arc<Mutex<dyn Attest>> =
-            Arc::new(Mutex::new(coco::grpc::Grpc::new(config).await?));
+        let attestation_service: Arc<dyn Attest> = Arc::new(coco::grpc::Grpc::new(config).await?);
 
         Ok(Self(attestation_service))
     }
@@ -62,9 +68,64 @@ impl AttestationService {
     /// Create and initialize AttestationService.
     #[cfg(feature = "amber-as")]
     pub fn new(config: &AmberConfig) -> Result<Self> {
-        let attestation_service: Arc<Mutex<dyn Attest>> =
-            Arc::new(Mutex::new(amber::Amber::new(config)?));
+        let attestation_service: Arc<dyn Attest> = Arc::new(amber::Amber::new(config)?);
 
         Ok(Self(attestation_service))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const BACKEND_LATENCY: Duration = Duration::from_millis(200);
+    const CONCURRENT_ATTESTATIONS: usize = 64;
+
+    /// A backend that takes a fixed time to verify any evidence, like a
+    /// remote Attestation Service would, and records how many verifications
+    /// were in flight at once.
+    #[derive(Default)]
+    struct SlowBackend {
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Attest for SlowBackend {
+        async fn verify(&self, _tee: Tee, nonce: &str, _attestation: &str) -> Result<String> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(BACKEND_LATENCY).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(nonce.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_verify() {
+        let backend = Arc::new(SlowBackend::default());
+        let attestation_service = AttestationService(backend.clone());
+
+        let tasks: Vec<_> = (0..CONCURRENT_ATTESTATIONS)
+            .map(|i| {
+                let attestation_service = attestation_service.clone();
+                tokio::spawn(async move {
+                    attestation_service
+                        .0
+                        .verify(Tee::Sample, &i.to_string(), "")
+                        .await
+                })
+            })
+            .collect();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            let token = task.await.unwrap().expect("verify failed");
+            assert_eq!(token, i.to_string());
+        }
+
+        // Serialized verification would never have more than one in flight.
+        assert_eq!(backend.peak.load(Ordering::SeqCst), CONCURRENT_ATTESTATIONS);
+    }
+}
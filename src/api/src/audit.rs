@@ -0,0 +1,405 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Audit trail of the resource requests.
+
+use crate::resource::ResourceDesc;
+use anyhow::{bail, Context, Result};
+use kbs_types::Tee;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::path::PathBuf;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixDatagram;
+use tokio::sync::Mutex;
+
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Syslog priority of the audit events: `authpriv` facility, `info` severity.
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// Suffix of the hash-chained events, preceding their hash.
+const HASH_FIELD: &str = ",\"hash\":\"";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditConfig {
+    pub sink: AuditSinkConfig,
+
+    /// JSON pointers to the attestation claims recorded in the events, e.g.
+    /// `/tcb-status/sgx.mr_enclave`.
+    #[serde(default)]
+    pub claims: Vec<String>,
+
+    /// Chain every event to the previous one through its hash, so that
+    /// removing or modifying an event is detected.
+    #[serde(default)]
+    pub hash_chain: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum AuditSinkConfig {
+    /// JSON-lines file the events are appended to.
+    File { path: PathBuf },
+
+    /// Local syslog daemon, listening on a Unix datagram socket.
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: PathBuf,
+    },
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from(DEFAULT_SYSLOG_SOCKET)
+}
+
+#[cfg_attr(not(feature = "policy"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PolicyDecision {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Released,
+    Failed,
+}
+
+/// A resource request, as recorded in the audit trail.
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditEvent {
+    timestamp: String,
+    session_id: Option<String>,
+    tee: Option<Tee>,
+    claims: Map<String, Value>,
+    resource: String,
+    version: Option<u64>,
+    decision: Option<PolicyDecision>,
+    outcome: Outcome,
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_hash: Option<String>,
+}
+
+impl AuditEvent {
+    pub(crate) fn new(resource_desc: &ResourceDesc) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            session_id: None,
+            tee: None,
+            claims: Map::new(),
+            resource: format!(
+                "{}/{}/{}",
+                resource_desc.repository_name,
+                resource_desc.resource_type,
+                resource_desc.resource_tag
+            ),
+            version: resource_desc.version,
+            decision: None,
+            outcome: Outcome::Failed,
+            error: None,
+            prev_hash: None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "as"), allow(dead_code))]
+    pub(crate) fn set_session(&mut self, session_id: &str, tee: Tee) {
+        self.session_id = Some(session_id.to_string());
+        self.tee = Some(tee);
+    }
+
+    #[cfg_attr(not(feature = "policy"), allow(dead_code))]
+    pub(crate) fn set_decision(&mut self, decision: PolicyDecision) {
+        self.decision = Some(decision);
+    }
+
+    pub(crate) fn set_outcome<T, E: Display>(&mut self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => {
+                self.outcome = Outcome::Released;
+                self.error = None;
+            }
+            Err(e) => {
+                self.outcome = Outcome::Failed;
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+enum Writer {
+    File(File),
+    Syslog(UnixDatagram, PathBuf),
+}
+
+impl Writer {
+    async fn write(&mut self, line: &str) -> Result<()> {
+        match self {
+            Self::File(file) => {
+                file.write_all(format!("{line}\n").as_bytes()).await?;
+                file.sync_data().await?;
+            }
+            Self::Syslog(socket, path) => {
+                let message = format!("<{SYSLOG_PRIORITY}>kbs[{}]: {line}", std::process::id());
+                socket.send_to(message.as_bytes(), &path).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct AuditSink {
+    writer: Writer,
+    /// Hash of the last event, when the events are hash-chained.
+    last_hash: Option<String>,
+}
+
+/// Append-only audit trail of the resource requests. Nothing is recorded when
+/// no sink is configured.
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    claims: Vec<String>,
+    sink: Option<Mutex<AuditSink>>,
+}
+
+impl AuditLog {
+    pub(crate) async fn new(config: Option<&AuditConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        if let Some(claim) = config.claims.iter().find(|claim| !claim.starts_with('/')) {
+            bail!("claim {claim} is not a JSON pointer");
+        }
+
+        let (writer, last_hash) = match &config.sink {
+            AuditSinkConfig::File { path } => {
+                // Carry on the hash chain of the existing events.
+                let last_hash = match config.hash_chain {
+                    true => Some(last_hash(path).await?.unwrap_or_else(genesis_hash)),
+                    false => None,
+                };
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("open audit log {}", path.display()))?;
+                (Writer::File(file), last_hash)
+            }
+            AuditSinkConfig::Syslog { socket } => {
+                let datagram = UnixDatagram::unbound().context("create syslog socket")?;
+                let last_hash = config.hash_chain.then(genesis_hash);
+                (Writer::Syslog(datagram, socket.clone()), last_hash)
+            }
+        };
+
+        Ok(Self {
+            claims: config.claims.clone(),
+            sink: Some(Mutex::new(AuditSink { writer, last_hash })),
+        })
+    }
+
+    /// Record the configured attestation claims in the event.
+    pub(crate) fn select_claims(&self, event: &mut AuditEvent, claims: &Value) {
+        for pointer in &self.claims {
+            if let Some(claim) = claims.pointer(pointer) {
+                event.claims.insert(pointer.clone(), claim.clone());
+            }
+        }
+    }
+
+    /// Append the event to the audit trail.
+    pub(crate) async fn record(&self, mut event: AuditEvent) -> Result<()> {
+        let Some(sink) = &self.sink else {
+            return Ok(());
+        };
+
+        let mut sink = sink.lock().await;
+        event.prev_hash = sink.last_hash.clone();
+        let mut line = serde_json::to_string(&event).context("serialize audit event")?;
+        let hash = match event.prev_hash {
+            Some(_) => {
+                let hash = hex(&Sha256::digest(&line));
+                // Insert the hash of the event line as its last field.
+                line.pop();
+                line = format!("{line}{HASH_FIELD}{hash}\"}}");
+                Some(hash)
+            }
+            None => None,
+        };
+
+        sink.writer
+            .write(&line)
+            .await
+            .context("write audit event")?;
+        if hash.is_some() {
+            sink.last_hash = hash;
+        }
+
+        Ok(())
+    }
+}
+
+/// Hash of the last event of a JSON-lines audit log, if any.
+async fn last_hash(path: &PathBuf) -> Result<Option<String>> {
+    let events = match tokio::fs::read_to_string(path).await {
+        Ok(events) => events,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("read audit log {}", path.display())),
+    };
+    let Some(last_event) = events.lines().rev().find(|line| !line.is_empty()) else {
+        return Ok(None);
+    };
+
+    let last_event: Value = serde_json::from_str(last_event).context("parse last audit event")?;
+    Ok(last_event["hash"].as_str().map(String::from))
+}
+
+/// Hash preceding the first event of a hash chain.
+fn genesis_hash() -> String {
+    "0".repeat(64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn resource_desc() -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+            version: None,
+        }
+    }
+
+    fn config(sink: AuditSinkConfig, hash_chain: bool) -> AuditConfig {
+        AuditConfig {
+            sink,
+            claims: vec!["/tcb-status/sgx.mr_enclave".into(), "/missing".into()],
+            hash_chain,
+        }
+    }
+
+    async fn record_events(audit_log: &AuditLog, count: usize) {
+        for i in 0..count {
+            let mut event = AuditEvent::new(&resource_desc());
+            event.set_session("1234", Tee::Sgx);
+            event.set_decision(PolicyDecision::Allow);
+            audit_log.select_claims(
+                &mut event,
+                &json!({"tcb-status": {"sgx.mr_enclave": format!("8f173e46{i}")}}),
+            );
+            event.set_outcome::<(), &str>(&Ok(()));
+            audit_log.record(event).await.expect("record event failed");
+        }
+    }
+
+    /// Check the hash chain of a JSON-lines audit log.
+    fn verify_chain(path: &Path) -> bool {
+        let events = std::fs::read_to_string(path).unwrap();
+        let mut last_hash = genesis_hash();
+        for line in events.lines() {
+            let Some((event, hash)) = line.rsplit_once(HASH_FIELD) else {
+                return false;
+            };
+            let event = format!("{event}}}");
+            let hash = hash.trim_end_matches("\"}");
+            let prev_hash = serde_json::from_str::<Value>(&event).unwrap()["prev_hash"].clone();
+            if prev_hash != last_hash || hash != hex(&Sha256::digest(&event)) {
+                return false;
+            }
+            last_hash = hash.to_string();
+        }
+
+        true
+    }
+
+    #[tokio::test]
+    async fn file_sink() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let path = tmp_dir.path().join("audit.jsonl");
+        let config = config(AuditSinkConfig::File { path: path.clone() }, false);
+
+        record_events(&AuditLog::new(Some(&config)).await.unwrap(), 2).await;
+
+        let events = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<Value> = events
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["session_id"], "1234");
+        assert_eq!(events[1]["tee"], "sgx");
+        assert_eq!(
+            events[1]["claims"],
+            json!({"/tcb-status/sgx.mr_enclave": "8f173e461"})
+        );
+        assert_eq!(events[1]["resource"], "default/key/1");
+        assert_eq!(events[1]["decision"], "allow");
+        assert_eq!(events[1]["outcome"], "released");
+        assert!(events[1].get("hash").is_none());
+    }
+
+    #[tokio::test]
+    async fn hash_chain() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let path = tmp_dir.path().join("audit.jsonl");
+        let config = config(AuditSinkConfig::File { path: path.clone() }, true);
+
+        record_events(&AuditLog::new(Some(&config)).await.unwrap(), 2).await;
+        // The chain carries on after a restart.
+        record_events(&AuditLog::new(Some(&config)).await.unwrap(), 2).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+        assert!(verify_chain(&path));
+
+        // Removing or modifying an event breaks the chain.
+        let events = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = events.lines().collect();
+        std::fs::write(&path, [lines[0], lines[2], lines[3]].join("\n")).unwrap();
+        assert!(!verify_chain(&path));
+        std::fs::write(&path, events.replacen("8f173e461", "3d7a2c10", 1)).unwrap();
+        assert!(!verify_chain(&path));
+    }
+
+    #[tokio::test]
+    async fn syslog_sink() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let socket = tmp_dir.path().join("log");
+        let syslog = UnixDatagram::bind(&socket).unwrap();
+        let config = config(AuditSinkConfig::Syslog { socket }, false);
+
+        record_events(&AuditLog::new(Some(&config)).await.unwrap(), 1).await;
+
+        let mut message = vec![0; 4096];
+        let len = syslog.recv(&mut message).await.unwrap();
+        let message = String::from_utf8(message[..len].to_vec()).unwrap();
+        let (header, event) = message.split_once(": ").unwrap();
+        assert!(header.starts_with("<86>kbs["));
+        let event: Value = serde_json::from_str(event).unwrap();
+        assert_eq!(event["resource"], "default/key/1");
+    }
+
+    #[tokio::test]
+    async fn no_sink() {
+        let audit_log = AuditLog::new(None).await.unwrap();
+        record_events(&audit_log, 1).await;
+    }
+}
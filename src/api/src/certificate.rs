@@ -0,0 +1,354 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Issuance of X.509 certificates to attested workloads.
+
+use anyhow::{anyhow, bail, Context, Result};
+use rand::RngCore;
+use rcgen::{
+    Certificate, CertificateParams, CertificateSigningRequest, DistinguishedName, DnType, DnValue,
+    ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose, SanType,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use time::{Duration, OffsetDateTime};
+
+/// Resource path of the certificate issuance, given to the resource policy.
+#[cfg(feature = "policy")]
+pub(crate) const CERTIFICATE_POLICY_PATH: &str = "certificate/x509/csr";
+
+const DEFAULT_VALIDITY_DAYS: u32 = 30;
+
+/// Certificate issuer configuration.
+///
+/// The `common_name` and `dns_names` templates may refer to the attestation
+/// claims with `{<JSON pointer>}` placeholders, e.g. `{/tcb-status/svn}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CertificateIssuerConfig {
+    /// Path to the PEM CA certificate.
+    pub ca_cert_path: PathBuf,
+
+    /// Path to the PEM (PKCS#8) CA private key, signing the certificates.
+    pub ca_key_path: PathBuf,
+
+    /// Validity period of the certificates, in days.
+    #[serde(default = "default_validity_days")]
+    pub validity_days: u32,
+
+    /// Template of the certificates subject common name. When omitted, the
+    /// subject is empty, whatever the CSR common name.
+    pub common_name: Option<String>,
+
+    /// Templates of the DNS names a certificate may carry as subject
+    /// alternative names.
+    #[serde(default)]
+    pub dns_names: Vec<String>,
+}
+
+fn default_validity_days() -> u32 {
+    DEFAULT_VALIDITY_DAYS
+}
+
+/// Certificate issuer, signing the CSRs of attested workloads with a CA key.
+pub(crate) struct CertificateIssuer {
+    ca: Certificate,
+    ca_cert_pem: String,
+    config: CertificateIssuerConfig,
+}
+
+impl CertificateIssuer {
+    pub fn new(config: &CertificateIssuerConfig) -> Result<Self> {
+        let ca_cert_pem = std::fs::read_to_string(&config.ca_cert_path)
+            .context("read certificate issuer CA certificate")?;
+        let ca_key_pem = std::fs::read_to_string(&config.ca_key_path)
+            .context("read certificate issuer CA key")?;
+
+        let key_pair = KeyPair::from_pem(&ca_key_pem).context("parse certificate issuer CA key")?;
+        let alg = key_pair.algorithm();
+        let mut params = CertificateParams::from_ca_cert_pem(&ca_cert_pem, key_pair)
+            .context("parse certificate issuer CA certificate")?;
+        // The certificates are signed with the CA key algorithm, whatever
+        // signed the CA certificate.
+        params.alg = alg;
+        let ca = Certificate::from_params(params).context("load certificate issuer CA")?;
+
+        for template in config.common_name.iter().chain(&config.dns_names) {
+            render(template, &Value::Null)
+                .or_else(|e| match e.downcast_ref::<MissingClaim>() {
+                    Some(_) => Ok(String::new()),
+                    None => Err(e),
+                })
+                .with_context(|| format!("invalid certificate template {template}"))?;
+        }
+
+        Ok(Self {
+            ca,
+            ca_cert_pem,
+            config: config.clone(),
+        })
+    }
+
+    /// Sign the PEM `csr` of a workload with the given attestation claims.
+    /// Returns the PEM certificate chain, from the workload certificate to the
+    /// CA certificate.
+    pub fn issue(&self, csr: &str, claims: &Value) -> Result<String> {
+        let mut csr = CertificateSigningRequest::from_pem(csr).context("parse CSR")?;
+
+        let requested_name = match csr.params.distinguished_name.get(&DnType::CommonName) {
+            Some(DnValue::Utf8String(name) | DnValue::PrintableString(name)) => Some(name.clone()),
+            Some(_) => bail!("unsupported CSR common name encoding"),
+            None => None,
+        };
+        let common_name = match &self.config.common_name {
+            Some(template) => {
+                let common_name = render(template, claims)?;
+                if requested_name.is_some_and(|name| name != common_name) {
+                    bail!("the CSR common name is not allowed, expected {common_name}");
+                }
+                Some(common_name)
+            }
+            // A workload cannot choose its subject.
+            None => None,
+        };
+
+        let allowed_names = self
+            .config
+            .dns_names
+            .iter()
+            .map(|template| render(template, claims))
+            .collect::<Result<Vec<_>>>()?;
+        let mut dns_names = Vec::new();
+        for san in &csr.params.subject_alt_names {
+            match san {
+                SanType::DnsName(name) if allowed_names.contains(name) => {
+                    dns_names.push(name.clone())
+                }
+                SanType::DnsName(name) => {
+                    bail!("the CSR subject alternative name {name} is not allowed")
+                }
+                _ => bail!("only DNS subject alternative names are allowed"),
+            }
+        }
+        // Without requested names, the certificate carries all the allowed ones.
+        if csr.params.subject_alt_names.is_empty() {
+            dns_names = allowed_names;
+        }
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(common_name) = common_name {
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+        }
+        params.subject_alt_names = dns_names.into_iter().map(SanType::DnsName).collect();
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + Duration::days(i64::from(self.config.validity_days));
+        let mut serial_number = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut serial_number);
+        // Keep the serial number positive.
+        serial_number[0] &= 0x7f;
+        params.serial_number = Some(serial_number.into());
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        params.use_authority_key_identifier_extension = true;
+        csr.params = params;
+
+        let certificate = csr
+            .serialize_pem_with_signer(&self.ca)
+            .context("sign certificate")?;
+
+        Ok(format!("{certificate}{}", self.ca_cert_pem))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("no string claim {0} in the attestation claims")]
+struct MissingClaim(String);
+
+/// Replace the `{<JSON pointer>}` placeholders of `template` with the
+/// string, number or boolean claims they point to.
+fn render(template: &str, claims: &Value) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unclosed placeholder"))?
+            + start;
+        let pointer = &rest[start + 1..end];
+        if !pointer.starts_with('/') {
+            bail!("placeholder {pointer} is not a JSON pointer");
+        }
+
+        match claims.pointer(pointer) {
+            Some(Value::String(claim)) => rendered.push_str(claim),
+            Some(claim @ (Value::Number(_) | Value::Bool(_))) => {
+                rendered.push_str(&claim.to_string())
+            }
+            _ => return Err(MissingClaim(pointer.to_string()).into()),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+    use x509_parser::prelude::*;
+
+    fn claims() -> Value {
+        json!({
+            "tee-pubkey": {},
+            "tcb-status": {
+                "sgx.mr_enclave": "8f173e46",
+                "svn": 3,
+            },
+        })
+    }
+
+    /// Create a self-signed CA and its issuer configuration.
+    fn issuer(
+        common_name: Option<&str>,
+        dns_names: &[&str],
+    ) -> (tempfile::TempDir, CertificateIssuer) {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "KBS test CA");
+        let ca = Certificate::from_params(params).unwrap();
+
+        let config = CertificateIssuerConfig {
+            ca_cert_path: tmp_dir.path().join("ca.pem"),
+            ca_key_path: tmp_dir.path().join("ca.key"),
+            validity_days: 7,
+            common_name: common_name.map(String::from),
+            dns_names: dns_names.iter().map(|name| name.to_string()).collect(),
+        };
+        std::fs::write(&config.ca_cert_path, ca.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.ca_key_path, ca.serialize_private_key_pem()).unwrap();
+
+        let issuer = CertificateIssuer::new(&config).expect("create certificate issuer failed");
+        (tmp_dir, issuer)
+    }
+
+    fn csr(common_name: &str, dns_names: &[&str]) -> String {
+        let mut params = CertificateParams::new(
+            dns_names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        );
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+
+        Certificate::from_params(params)
+            .unwrap()
+            .serialize_request_pem()
+            .unwrap()
+    }
+
+    #[test]
+    fn issue_certificate() {
+        let (_tmp_dir, issuer) = issuer(
+            Some("workload-{/tcb-status/sgx.mr_enclave}"),
+            &[
+                "{/tcb-status/sgx.mr_enclave}.svc.example.com",
+                "example.com",
+            ],
+        );
+
+        let chain = issuer
+            .issue(
+                &csr("workload-8f173e46", &["8f173e46.svc.example.com"]),
+                &claims(),
+            )
+            .expect("issue certificate failed");
+        let pems = Pem::iter_from_buffer(chain.as_bytes())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pems.len(), 2);
+
+        let certificate = pems[0].parse_x509().unwrap();
+        let ca = pems[1].parse_x509().unwrap();
+        assert!(certificate.verify_signature(Some(ca.public_key())).is_ok());
+        assert_eq!(certificate.issuer(), ca.subject());
+        assert_eq!(certificate.subject().to_string(), "CN=workload-8f173e46");
+        let san = certificate.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            vec![GeneralName::DNSName("8f173e46.svc.example.com")]
+        );
+        let validity = certificate.validity().time_to_expiration().unwrap();
+        assert!(validity <= Duration::days(7) && validity > Duration::days(6));
+    }
+
+    #[test]
+    fn default_names() {
+        let (_tmp_dir, issuer) = issuer(None, &["{/tcb-status/svn}.example.com"]);
+
+        let chain = issuer
+            .issue(&csr("requested", &[]), &claims())
+            .expect("issue certificate failed");
+        let pem = Pem::iter_from_buffer(chain.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let certificate = pem.parse_x509().unwrap();
+        // The CSR common name is dropped.
+        assert_eq!(certificate.subject().iter_common_name().count(), 0);
+        let san = certificate.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            vec![GeneralName::DNSName("3.example.com")]
+        );
+    }
+
+    #[rstest]
+    #[case::common_name(csr("other", &[]))]
+    #[case::dns_name(csr("workload-8f173e46", &["other.example.com"]))]
+    #[case::invalid_csr("invalid".to_string())]
+    fn reject_csr(#[case] csr: String) {
+        let (_tmp_dir, issuer) = issuer(
+            Some("workload-{/tcb-status/sgx.mr_enclave}"),
+            &["example.com"],
+        );
+
+        assert!(issuer.issue(&csr, &claims()).is_err());
+    }
+
+    #[test]
+    fn missing_claim() {
+        let (_tmp_dir, issuer) = issuer(Some("{/init-data}"), &[]);
+
+        assert!(issuer.issue(&csr("workload", &[]), &claims()).is_err());
+    }
+
+    #[rstest]
+    #[case("plain", Some("plain"))]
+    #[case("{/tcb-status/svn}-{/tcb-status/sgx.mr_enclave}", Some("3-8f173e46"))]
+    #[case("{/tcb-status}", None)]
+    #[case("{tcb-status}", None)]
+    #[case("{/tcb-status/svn", None)]
+    fn render_template(#[case] template: &str, #[case] expected: Option<&str>) {
+        assert_eq!(render(template, &claims()).ok().as_deref(), expected);
+    }
+}
@@ -6,12 +6,21 @@
 use crate::attestation::amber::AmberConfig;
 #[cfg(feature = "coco-as-grpc")]
 use crate::attestation::coco::grpc::GrpcConfig;
+#[cfg(feature = "resource")]
+use crate::audit::AuditConfig;
+#[cfg(feature = "certificate")]
+use crate::certificate::CertificateIssuerConfig;
+#[cfg(feature = "resource")]
+use crate::jwe::JweConfig;
+use crate::metrics::MetricsConfig;
 #[cfg(feature = "policy")]
 use crate::policy_engine::PolicyEngineConfig;
 #[cfg(feature = "resource")]
-use crate::resource::RepositoryConfig;
+use crate::resource::{KeyDerivationConfig, RepositoryConfig, RepositoryEncryptionConfig};
+#[cfg(feature = "as")]
+use crate::session::SessionConfig;
 #[cfg(feature = "resource")]
-use crate::token::AttestationTokenVerifierType;
+use crate::token::{AttestationTokenVerifierConfig, AttestationTokenVerifierType};
 use anyhow::anyhow;
 #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
 use attestation_service::config::Config as AsConfig;
@@ -35,6 +44,11 @@ pub struct KbsConfig {
     #[cfg(feature = "resource")]
     pub repository_config: Option<RepositoryConfig>,
 
+    /// Resource repository encryption at rest configuration.
+    /// The resources are stored in plaintext when omitted.
+    #[cfg(feature = "resource")]
+    pub repository_encryption_config: Option<RepositoryEncryptionConfig>,
+
     /// Attestation token result broker type.
     ///
     /// Possible values:
@@ -42,6 +56,29 @@ pub struct KbsConfig {
     #[cfg(feature = "resource")]
     pub attestation_token_type: AttestationTokenVerifierType,
 
+    /// Attestation token verifier configuration, e.g. the trusted token signing keys.
+    #[cfg(feature = "resource")]
+    pub attestation_token_config: Option<AttestationTokenVerifierConfig>,
+
+    /// Resource response encryption configuration.
+    #[cfg(feature = "resource")]
+    pub jwe_config: Option<JweConfig>,
+
+    /// Resources derived per workload from a master secret and the
+    /// attestation claims.
+    #[cfg(feature = "resource")]
+    pub key_derivation_config: Option<KeyDerivationConfig>,
+
+    /// Audit trail of the resource requests. Nothing is recorded when
+    /// omitted.
+    #[cfg(feature = "resource")]
+    pub audit_config: Option<AuditConfig>,
+
+    /// Certificate issuer configuration. Attested workloads cannot request
+    /// certificates when omitted.
+    #[cfg(feature = "certificate")]
+    pub certificate_issuer_config: Option<CertificateIssuerConfig>,
+
     /// Configuration for the built-in Attestation Service.
     #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
     pub as_config: Option<AsConfig>,
@@ -60,6 +97,10 @@ pub struct KbsConfig {
     /// HTTPS session timeout in minutes.
     pub timeout: i64,
 
+    /// Limits of the sessions kept by the KBS.
+    #[cfg(feature = "as")]
+    pub session_config: Option<SessionConfig>,
+
     /// HTTPS private key.
     pub private_key: Option<PathBuf>,
 
@@ -79,6 +120,10 @@ pub struct KbsConfig {
     /// verifying the JWK.
     pub insecure_api: bool,
 
+    /// Prometheus metrics configuration. The metrics are not served when
+    /// omitted.
+    pub metrics_config: Option<MetricsConfig>,
+
     /// Policy engine configuration used for evaluating whether the TCB status has access to
     /// specific resources.
     #[cfg(feature = "policy")]
@@ -2,7 +2,12 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(feature = "resource")]
+use crate::jwe::{supported_algorithms, SUPPORTED_CURVES};
+use crate::metrics::{ATTEST_REQUESTS, ATTEST_VERIFY_DURATION, AUTH_REQUESTS};
 use crate::raise_error;
+use crate::session::SessionConfig;
+use crate::tee_key::TeeKey;
 
 use super::*;
 
@@ -10,27 +15,77 @@ use anyhow::anyhow;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use log::{error, info};
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// `Attestation` payload. Unlike `kbs_types::Attestation`, whose TEE key
+/// can only be an RSA key, it accepts all the TEE keys of the `/auth`
+/// challenge.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Attestation {
+    #[serde(rename = "tee-pubkey")]
+    tee_pubkey: TeeKey,
+    #[serde(rename = "tee-evidence")]
+    tee_evidence: String,
+}
 
 /// POST /auth
 pub(crate) async fn auth(
     request: web::Json<Request>,
-    map: web::Data<SessionMap<'_>>,
+    map: web::Data<Arc<dyn SessionStore>>,
     timeout: web::Data<i64>,
+    session_config: web::Data<SessionConfig>,
+    #[cfg(feature = "resource")] jwe_config: web::Data<JweConfig>,
 ) -> Result<HttpResponse> {
     info!("request: {:?}", &request);
 
-    let session = Session::from_request(&request, *timeout.into_inner())
-        .map_err(|e| Error::FailedAuthentication(format!("Session: {e}")))?;
-    let response = HttpResponse::Ok().cookie(session.cookie()).json(Challenge {
+    let session = Session::from_request(
+        &request,
+        *timeout.into_inner(),
+        session_config.pending_timeout,
+    )
+    .map_err(|e| Error::FailedAuthentication(format!("Session: {e}")));
+    count_request(&AUTH_REQUESTS, &tee_label(&request.tee), &session);
+    let session = session?;
+
+    #[allow(unused_mut)]
+    let mut extra_params = Map::new();
+
+    // Advertise the TEE key types the resource responses can be encrypted to.
+    #[cfg(feature = "resource")]
+    {
+        extra_params.insert(
+            "supported-tee-key-algorithms".to_string(),
+            json!(supported_algorithms(&jwe_config)),
+        );
+        extra_params.insert(
+            "supported-tee-key-curves".to_string(),
+            json!(SUPPORTED_CURVES),
+        );
+    }
+
+    let mut response = HttpResponse::Ok();
+    match session.transport() {
+        SessionTransport::Cookie => {
+            response.cookie(session.cookie(&session_config.cookie));
+        }
+        SessionTransport::Header => {
+            extra_params.insert(SESSION_ID_PARAM.to_string(), json!(session.id()));
+        }
+    }
+
+    let extra_params = match extra_params.is_empty() {
+        true => String::new(),
+        false => Value::Object(extra_params).to_string(),
+    };
+    let response = response.json(Challenge {
         nonce: session.nonce().to_string(),
-        extra_params: "".to_string(),
+        extra_params,
     });
 
-    map.sessions
-        .write()
+    map.insert(session)
         .await
-        .insert(session.id().to_string(), Arc::new(Mutex::new(session)));
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
 
     Ok(response)
 }
@@ -39,15 +94,42 @@ pub(crate) async fn auth(
 pub(crate) async fn attest(
     attestation: web::Json<Attestation>,
     request: HttpRequest,
-    map: web::Data<SessionMap<'_>>,
+    map: web::Data<Arc<dyn SessionStore>>,
+    session_config: web::Data<SessionConfig>,
     attestation_service: web::Data<AttestationService>,
 ) -> Result<HttpResponse> {
-    let cookie = request.cookie(KBS_SESSION_ID).ok_or(Error::MissingCookie)?;
+    let tee = match request_session(&request, &map).await {
+        Some((_, tee)) => tee_label(&tee),
+        None => UNKNOWN_TEE.to_string(),
+    };
+
+    let response = verify_attestation(
+        attestation,
+        &request,
+        map,
+        &session_config,
+        attestation_service,
+    )
+    .await;
+    count_request(&ATTEST_REQUESTS, &tee, &response);
 
-    let sessions = map.sessions.read().await;
-    let locked_session = sessions.get(cookie.value()).ok_or(Error::InvalidCookie)?;
+    response
+}
+
+async fn verify_attestation(
+    attestation: web::Json<Attestation>,
+    request: &HttpRequest,
+    map: web::Data<Arc<dyn SessionStore>>,
+    session_config: &SessionConfig,
+    attestation_service: web::Data<AttestationService>,
+) -> Result<HttpResponse> {
+    let id = session_id(request).ok_or(Error::MissingCookie)?;
 
-    let mut session = locked_session.lock().await;
+    let mut session = map
+        .get(&id)
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?
+        .ok_or(Error::InvalidCookie)?;
 
     info!("Cookie {} attestation {:?}", session.id(), attestation);
 
@@ -55,17 +137,26 @@ pub(crate) async fn attest(
         raise_error!(Error::ExpiredCookie);
     }
 
-    let token = attestation_service
-        .0
-        .lock()
-        .await
-        .verify(
-            session.tee(),
-            session.nonce(),
-            &serde_json::to_string(&attestation).unwrap(),
-        )
-        .await
-        .map_err(|e| Error::AttestationFailed(e.to_string()))?;
+    if !session.is_authenticated() {
+        map.touch_pending(session.id())
+            .await
+            .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
+    }
+
+    let token = {
+        let _timer = ATTEST_VERIFY_DURATION
+            .with_label_values(&[&tee_label(&session.tee())])
+            .start_timer();
+        attestation_service
+            .0
+            .verify(
+                session.tee(),
+                session.nonce(),
+                &serde_json::to_string(&attestation).unwrap(),
+            )
+            .await
+    }
+    .map_err(|e| Error::AttestationFailed(e.to_string()))?;
 
     let claims_b64 = token
         .split('.')
@@ -81,14 +172,38 @@ pub(crate) async fn attest(
     session.set_tee_public_key(attestation.tee_pubkey.clone());
     session.set_authenticated();
     session.set_attestation_claims(claims);
+    if session_config.bind_tee_key {
+        session.bind_tee_key();
+    }
+    let mut response = HttpResponse::Ok();
+    if session.transport() == SessionTransport::Cookie {
+        response.cookie(session.cookie(&session_config.cookie));
+    }
+    map.update(session)
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
 
     let body = serde_json::to_string(&json!({
         "token": token,
     }))
     .map_err(|e| Error::TokenIssueFailed(format!("Serialize token failed {e}")))?;
 
-    Ok(HttpResponse::Ok()
-        .cookie(session.cookie())
-        .content_type("application/json")
-        .body(body))
+    Ok(response.content_type("application/json").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attestation_with_ec_tee_key() {
+        let payload = json!({
+            "tee-pubkey": {"kty": "EC", "alg": "ECDH-ES", "crv": "P-256", "x": "AA", "y": "AQ"},
+            "tee-evidence": "{}",
+        });
+
+        let attestation: Attestation = serde_json::from_value(payload.clone()).unwrap();
+        assert!(matches!(attestation.tee_pubkey, TeeKey::Ec { .. }));
+        assert_eq!(serde_json::to_value(&attestation).unwrap(), payload);
+    }
 }
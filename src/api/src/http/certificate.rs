@@ -0,0 +1,49 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "policy")]
+use crate::certificate::CERTIFICATE_POLICY_PATH;
+
+use super::*;
+
+/// POST /certificate
+///
+/// The request body is a PEM CSR, and the response the PEM certificate chain
+/// issued for it, wrapped in a JWE.
+pub(crate) async fn issue_certificate(
+    request: HttpRequest,
+    csr: web::Bytes,
+    issuer: web::Data<CertificateIssuer>,
+    #[cfg(feature = "as")] map: web::Data<Arc<dyn SessionStore>>,
+    token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
+    jwe_config: web::Data<JweConfig>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<PolicyEngine>,
+) -> Result<HttpResponse> {
+    let claims_str = attestation_claims(
+        &request,
+        #[cfg(feature = "as")]
+        map,
+        token_verifier,
+    )
+    .await?;
+    let claims = parse_claims(&claims_str)?;
+    let pubkey = tee_pubkey(&claims)?;
+
+    #[cfg(feature = "policy")]
+    evaluate_policy(
+        &policy_engine,
+        CERTIFICATE_POLICY_PATH.to_string(),
+        claims_str,
+    )
+    .await?;
+
+    let csr = std::str::from_utf8(&csr)
+        .map_err(|e| Error::InvalidRequest(format!("illegal CSR: {e}")))?;
+    let chain = issuer
+        .issue(csr, &claims)
+        .map_err(|e| Error::CertificateIssueFailed(format!("{e:#}")))?;
+    log::info!("Certificate issued");
+
+    jwe_response(pubkey, chain.into_bytes(), &jwe_config)
+}
@@ -26,8 +26,6 @@ pub(crate) async fn attestation_policy(
 
     attestation_service
         .0
-        .lock()
-        .await
         .set_policy(input.into_inner())
         .await
         .map_err(|e| Error::PolicyEndpoint(format!("Set policy error {e}")))?;
@@ -102,26 +100,516 @@ pub(crate) async fn set_resource(
         })?;
     }
 
+    let resource_description = resource_desc_from_request(&request)?;
+    // Every write creates a new version.
+    if resource_description.version.is_some() {
+        return Err(Error::InvalidRequest(
+            "a resource write cannot select a version".to_string(),
+        ));
+    }
+
+    set_secret_resource(&repository, resource_description, data.as_ref())
+        .await
+        .map_err(|e| Error::SetSecretFailed(format!("{e}")))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(""))
+}
+
+#[cfg(feature = "resource")]
+/// DELETE /resource/{repository}/{type}/{tag}
+/// DELETE /resource/{type}/{tag}
+pub(crate) async fn delete_resource(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    delete_secret_resource(&repository, resource_description)
+        .await
+        .map_err(|e| Error::DeleteSecretFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource
+pub(crate) async fn list_repositories(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let repositories = repository
+        .read()
+        .await
+        .list_repositories()
+        .await
+        .map_err(list_error)?;
+    Ok(HttpResponse::Ok().json(repositories))
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource/{repository}
+pub(crate) async fn list_resource_types(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let repository_name = path_component(&request, "repository")?;
+    let types = repository
+        .read()
+        .await
+        .list_resource_types(repository_name)
+        .await
+        .map_err(list_error)?;
+    Ok(HttpResponse::Ok().json(types))
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource/{repository}/{type}
+pub(crate) async fn list_resource_tags(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let repository_name = path_component(&request, "repository")?;
+    let resource_type = path_component(&request, "type")?;
+    let tags = repository
+        .read()
+        .await
+        .list_resource_tags(repository_name, resource_type)
+        .await
+        .map_err(list_error)?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource/{repository}/{type}/{tag}[?version={version}]
+pub(crate) async fn get_resource_metadata(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    let metadata = repository
+        .read()
+        .await
+        .get_resource_metadata(resource_description)
+        .await
+        .map_err(|e| Error::ReadSecretFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok().json(metadata))
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource/{repository}/{type}/{tag}/versions
+pub(crate) async fn list_resource_versions(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    let versions = repository
+        .read()
+        .await
+        .list_resource_versions(resource_description)
+        .await
+        .map_err(list_error)?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[cfg(feature = "resource")]
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ResourceVersion {
+    version: u64,
+}
+
+#[cfg(feature = "resource")]
+/// POST /admin/resource/{repository}/{type}/{tag}/rollback
+///
+/// Write the content of the requested version as the new latest version, and
+/// return that new version.
+pub(crate) async fn rollback_resource(
+    request: HttpRequest,
+    input: web::Json<ResourceVersion>,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    let version = rollback_secret_resource(&repository, resource_description, input.version)
+        .await
+        .map_err(|e| Error::SetSecretFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok().json(ResourceVersion { version }))
+}
+
+#[cfg(feature = "resource")]
+/// GET /admin/resource/{repository}/{type}/{tag}/limits
+///
+/// Return the access limits of the resource, and how many times it was read.
+pub(crate) async fn get_resource_limits(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    let access = repository
+        .read()
+        .await
+        .get_resource_access(resource_description)
+        .await
+        .map_err(|e| Error::ReadSecretFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok().json(access))
+}
+
+#[cfg(feature = "resource")]
+/// POST /admin/resource/{repository}/{type}/{tag}/limits
+///
+/// Set the access limits of the resource, and reset its read count.
+pub(crate) async fn set_resource_limits(
+    request: HttpRequest,
+    input: web::Json<ResourceLimits>,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let resource_description = resource_desc_from_request(&request)?;
+    repository
+        .write()
+        .await
+        .set_resource_limits(resource_description, input.into_inner())
+        .await
+        .map_err(|e| Error::SetSecretFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "as")]
+/// A KBS session, as listed to the KBS user. The times are Unix timestamps,
+/// in seconds.
+#[derive(serde::Serialize)]
+pub(crate) struct SessionInfo {
+    id: String,
+    tee: Tee,
+    authenticated: bool,
+    created: i64,
+    expires: i64,
+    /// `tcb-status` of the attestation claims, once authenticated.
+    claims: Option<serde_json::Value>,
+}
+
+#[cfg(feature = "as")]
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        Self {
+            id: session.id().to_string(),
+            tee: session.tee(),
+            authenticated: session.is_authenticated(),
+            created: session.created_at(),
+            expires: session.expires_at(),
+            claims: session_claims(session).and_then(|claims| claims.get("tcb-status").cloned()),
+        }
+    }
+}
+
+#[cfg(feature = "as")]
+/// Sessions to revoke, by their attestation claims.
+#[derive(serde::Deserialize)]
+pub(crate) struct SessionFilter {
+    /// Values of the attestation claims by JSON pointer, e.g.
+    /// `{"/tcb-status/svn": "1"}`. A session matches when all of them do.
+    claims: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(feature = "as")]
+impl SessionFilter {
+    fn matches(&self, session: &Session) -> bool {
+        let Some(claims) = session_claims(session) else {
+            return false;
+        };
+
+        self.claims
+            .iter()
+            .all(|(pointer, value)| claims.pointer(pointer) == Some(value))
+    }
+}
+
+#[cfg(feature = "as")]
+fn session_claims(session: &Session) -> Option<serde_json::Value> {
+    serde_json::from_str(&session.attestation_claims()?).ok()
+}
+
+#[cfg(feature = "as")]
+/// GET /admin/session
+///
+/// Return the sessions that did not expire.
+pub(crate) async fn list_sessions(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    map: web::Data<Arc<dyn SessionStore>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let sessions = map
+        .list()
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
+    let sessions: Vec<SessionInfo> = sessions.iter().map(SessionInfo::from).collect();
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+#[cfg(feature = "as")]
+/// DELETE /admin/session/{id}
+pub(crate) async fn revoke_session(
+    request: HttpRequest,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    map: web::Data<Arc<dyn SessionStore>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    let id = request
+        .match_info()
+        .get("id")
+        .ok_or_else(|| Error::InvalidRequest(String::from("no `id` in url")))?;
+    let removed = map
+        .remove(id)
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
+    if !removed {
+        return Err(Error::SessionNotFound(id.to_string()));
+    }
+
+    log::info!("Revoked session {id}");
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "as")]
+/// POST /admin/session/revoke
+///
+/// Revoke the sessions whose attestation claims match the filter, and return
+/// their ids.
+pub(crate) async fn revoke_sessions(
+    request: HttpRequest,
+    input: web::Json<SessionFilter>,
+    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    insecure: web::Data<bool>,
+    map: web::Data<Arc<dyn SessionStore>>,
+) -> Result<HttpResponse> {
+    authenticate_admin(&request, &user_pub_key, &insecure)?;
+
+    // An empty filter would match every authenticated session.
+    if input.claims.is_empty() {
+        return Err(Error::InvalidRequest(String::from(
+            "no claims in the session filter",
+        )));
+    }
+
+    let sessions = map
+        .list()
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
+    let mut revoked = Vec::new();
+    for session in sessions.iter().filter(|session| input.matches(session)) {
+        let removed = map
+            .remove(session.id())
+            .await
+            .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?;
+        if removed {
+            log::info!("Revoked session {}", session.id());
+            revoked.push(session.id().to_string());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(revoked))
+}
+
+#[cfg(feature = "resource")]
+/// Report the listing of a missing repository, resource type or resource as
+/// not found, and any other repository failure as a listing failure.
+fn list_error(e: anyhow::Error) -> Error {
+    match e.downcast_ref::<ResourceNotFound>() {
+        Some(not_found) => Error::ResourceNotFound(not_found.to_string()),
+        None => Error::ListResourcesFailed(format!("{e:#}")),
+    }
+}
+
+/// Check that the request is authenticated by the KBS user (administrator)
+/// JWT, unless the insecure API is enabled.
+fn authenticate_admin(
+    request: &HttpRequest,
+    user_pub_key: &Option<Ed25519PublicKey>,
+    insecure: &bool,
+) -> Result<()> {
+    if *insecure {
+        return Ok(());
+    }
+
+    let user_pub_key = user_pub_key
+        .as_ref()
+        .ok_or(Error::UserPublicKeyNotProvided)?;
+
+    validate_auth(request, user_pub_key).map_err(|e| {
+        Error::FailedAuthentication(format!("Requester is not an authorized user: {e}"))
+    })
+}
+
+#[cfg(feature = "resource")]
+fn path_component<'a>(request: &'a HttpRequest, name: &str) -> Result<&'a str> {
+    let component = request
+        .match_info()
+        .get(name)
+        .ok_or_else(|| Error::InvalidRequest(format!("no `{name}` in url")))?;
+    if !is_valid_path_component(component) {
+        return Err(Error::InvalidRequest(format!("Invalid `{name}` in url")));
+    }
+
+    Ok(component)
+}
+
+#[cfg(feature = "resource")]
+fn resource_desc_from_request(request: &HttpRequest) -> Result<ResourceDesc> {
     let resource_description = ResourceDesc {
         repository_name: request
             .match_info()
             .get("repository")
             .unwrap_or("default")
             .to_string(),
-        resource_type: request
-            .match_info()
-            .get("type")
-            .ok_or_else(|| Error::InvalidRequest(String::from("no `type` in url")))?
-            .to_string(),
-        resource_tag: request
-            .match_info()
-            .get("tag")
-            .ok_or_else(|| Error::InvalidRequest(String::from("no `tag` in url")))?
-            .to_string(),
+        resource_type: path_component(request, "type")?.to_string(),
+        resource_tag: path_component(request, "tag")?.to_string(),
+        version: resource_version(request)?,
     };
 
-    set_secret_resource(&repository, resource_description, data.as_ref())
+    if !resource_description.is_valid() {
+        return Err(Error::InvalidRequest("Invalid resource path".to_string()));
+    }
+
+    Ok(resource_description)
+}
+
+#[cfg(all(test, feature = "as"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn session(claims: Option<serde_json::Value>) -> Session {
+        let request = Request {
+            version: "0.1.0".to_string(),
+            tee: Tee::Sample,
+            extra_params: String::new(),
+        };
+        let mut session = Session::from_request(&request, 5, 60).unwrap();
+        if let Some(claims) = claims {
+            session.set_authenticated();
+            session.set_attestation_claims(claims.to_string());
+        }
+        session
+    }
+
+    #[test]
+    fn session_info() {
+        let info = SessionInfo::from(&session(Some(json!({
+            "tee-pubkey": {},
+            "tcb-status": {"svn": "1"},
+        }))));
+        assert!(info.authenticated);
+        assert_eq!(info.claims, Some(json!({"svn": "1"})));
+
+        let info = SessionInfo::from(&session(None));
+        assert!(!info.authenticated);
+        assert_eq!(info.claims, None);
+    }
+
+    #[test]
+    fn session_filter() {
+        let filter: SessionFilter = serde_json::from_value(json!({
+            "claims": {
+                "/tcb-status/sgx.mr_enclave": "8f173e46",
+                "/tcb-status/svn": "1",
+            }
+        }))
+        .unwrap();
+
+        let claims = json!({"tcb-status": {"sgx.mr_enclave": "8f173e46", "svn": "1"}});
+        assert!(filter.matches(&session(Some(claims))));
+
+        let claims = json!({"tcb-status": {"sgx.mr_enclave": "8f173e46", "svn": "2"}});
+        assert!(!filter.matches(&session(Some(claims))));
+
+        let claims = json!({"tcb-status": {"sgx.mr_enclave": "8f173e46"}});
+        assert!(!filter.matches(&session(Some(claims))));
+
+        assert!(!filter.matches(&session(None)));
+    }
+
+    #[cfg(feature = "resource")]
+    #[actix_web::test]
+    async fn set_resource_path() {
+        use crate::resource::{LocalFsRepoDesc, RepositoryConfig};
+        use actix_web::{http::StatusCode, test, App};
+
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repository = RepositoryConfig::LocalFs(LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+        })
+        .initialize(None)
         .await
-        .map_err(|e| Error::SetSecretFailed(format!("{e}")))?;
-    Ok(HttpResponse::Ok().content_type("application/json").body(""))
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(None::<Ed25519PublicKey>))
+                .app_data(web::Data::new(true))
+                .app_data(web::Data::new(repository))
+                .route(
+                    "/resource/{repository}/{type}/{tag}",
+                    web::post().to(set_resource),
+                ),
+        )
+        .await;
+
+        for uri in [
+            "/resource/default/.versions/x",
+            "/resource/default/key/.access",
+            "/resource/default/key/1?version=1",
+        ] {
+            let request = test::TestRequest::post()
+                .uri(uri)
+                .set_payload("data")
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+        assert!(!tmp_dir.path().join("default/.versions").exists());
+        assert!(!tmp_dir.path().join("default/key").exists());
+
+        let request = test::TestRequest::post()
+            .uri("/resource/default/key/1")
+            .set_payload("data")
+            .to_request();
+        assert!(test::call_service(&app, request)
+            .await
+            .status()
+            .is_success());
+    }
 }
@@ -33,6 +33,15 @@ pub enum Error {
     #[error("Received illegal attestation claims: {0}")]
     AttestationClaimsParseFailed(String),
 
+    #[error("Audit trail record failed: {0}")]
+    AuditFailed(String),
+
+    #[error("Certificate issue failed: {0}")]
+    CertificateIssueFailed(String),
+
+    #[error("Delete secret failed: {0}")]
+    DeleteSecretFailed(String),
+
     #[error("The cookie is expired")]
     ExpiredCookie,
 
@@ -48,9 +57,15 @@ pub enum Error {
     #[error("Json Web Encryption failed: {0}")]
     JWEFailed(String),
 
+    #[error("List resources failed: {0}")]
+    ListResourcesFailed(String),
+
     #[error("The cookie is missing")]
     MissingCookie,
 
+    #[error("Access denied by resource policy: {0}")]
+    PolicyDeny(String),
+
     #[error("Policy error: {0}")]
     PolicyEndpoint(String),
 
@@ -63,6 +78,21 @@ pub enum Error {
     #[error("Read secret failed: {0}")]
     ReadSecretFailed(String),
 
+    #[error("Resource not found: {0}")]
+    ResourceNotFound(String),
+
+    #[error("Resource unavailable: {0}")]
+    ResourceUnavailable(String),
+
+    #[error("Session not found: {0}")]
+    SessionNotFound(String),
+
+    #[error("Session proof verification failed: {0}")]
+    SessionProofFailed(String),
+
+    #[error("Session store access failed: {0}")]
+    SessionStoreFailed(String),
+
     #[error("Set secret failed: {0}")]
     SetSecretFailed(String),
 
@@ -112,7 +142,13 @@ impl ResponseError for Error {
 
         // Due to the definition of KBS attestation protocol, we set the http code.
         let mut res = match self {
-            Error::ReadSecretFailed(_) => HttpResponse::NotFound(),
+            Error::ReadSecretFailed(_) | Error::ResourceNotFound(_) | Error::SessionNotFound(_) => {
+                HttpResponse::NotFound()
+            }
+            Error::PolicyDeny(_) | Error::ResourceUnavailable(_) => HttpResponse::Forbidden(),
+            Error::AuditFailed(_)
+            | Error::ListResourcesFailed(_)
+            | Error::SessionStoreFailed(_) => HttpResponse::InternalServerError(),
             _ => HttpResponse::Unauthorized(),
         };
 
@@ -128,6 +164,9 @@ mod tests {
 
     #[rstest]
     #[case(Error::AttestationFailed("test".into()))]
+    #[case(Error::AuditFailed("test".into()))]
+    #[case(Error::CertificateIssueFailed("test".into()))]
+    #[case(Error::DeleteSecretFailed("test".into()))]
     #[case(Error::ExpiredCookie)]
     #[case(Error::FailedAuthentication("test".into()))]
     #[case(Error::InvalidCookie)]
@@ -135,9 +174,16 @@ mod tests {
     #[case(Error::MissingCookie)]
     #[case(Error::InvalidRequest("test".into()))]
     #[case(Error::JWEFailed("test".into()))]
+    #[case(Error::ListResourcesFailed("test".into()))]
+    #[case(Error::PolicyDeny("test".into()))]
     #[case(Error::PolicyEndpoint("test".into()))]
     #[case(Error::PublicKeyGetFailed("test".into()))]
     #[case(Error::ReadSecretFailed("test".into()))]
+    #[case(Error::ResourceNotFound("test".into()))]
+    #[case(Error::ResourceUnavailable("test".into()))]
+    #[case(Error::SessionNotFound("test".into()))]
+    #[case(Error::SessionProofFailed("test".into()))]
+    #[case(Error::SessionStoreFailed("test".into()))]
     #[case(Error::SetSecretFailed("test".into()))]
     #[case(Error::TokenIssueFailed("test".into()))]
     #[case(Error::TokenParseFailed("test".into()))]
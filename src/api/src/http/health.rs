@@ -0,0 +1,157 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use super::*;
+
+/// Time given to all the components to answer a readiness probe.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Check = (&'static str, JoinHandle<anyhow::Result<()>>);
+
+/// GET /health/live
+pub(crate) async fn live() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// GET /health/ready
+///
+/// Check that the attestation backend can be reached, that the resource
+/// repository can be read and that the resource policy can be evaluated.
+pub(crate) async fn ready(
+    #[cfg(feature = "as")] attestation_service: web::Data<AttestationService>,
+    #[cfg(feature = "resource")] repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<PolicyEngine>,
+) -> HttpResponse {
+    #[allow(unused_mut)]
+    let mut checks: Vec<Check> = Vec::new();
+
+    #[cfg(feature = "as")]
+    checks.push((
+        "attestation",
+        tokio::spawn(async move { attestation_service.0.check().await }),
+    ));
+
+    #[cfg(feature = "resource")]
+    checks.push((
+        "repository",
+        tokio::spawn(async move {
+            repository
+                .read()
+                .await
+                .list_repositories()
+                .await
+                .map(|_| ())
+        }),
+    ));
+
+    #[cfg(feature = "policy")]
+    checks.push((
+        "policy",
+        tokio::spawn(async move { policy_engine.0.lock().await.check().await }),
+    ));
+
+    readiness(checks, READINESS_TIMEOUT).await
+}
+
+/// Wait for the concurrently running `checks`, and report the status of each
+/// component.
+async fn readiness(checks: Vec<Check>, timeout: Duration) -> HttpResponse {
+    let deadline = Instant::now() + timeout;
+    let mut ready = true;
+    let mut components = Map::new();
+
+    for (component, mut check) in checks {
+        let status = match timeout_at(deadline, &mut check).await {
+            Ok(Ok(Ok(()))) => json!({ "status": "ok" }),
+            Ok(Ok(Err(e))) => json!({ "status": "error", "error": format!("{e:#}") }),
+            Ok(Err(e)) => json!({ "status": "error", "error": e.to_string() }),
+            Err(_) => {
+                check.abort();
+                json!({ "status": "error", "error": "timed out" })
+            }
+        };
+        if status["status"] != "ok" {
+            log::warn!("Component {component} is not ready: {}", status["error"]);
+            ready = false;
+        }
+        components.insert(component.to_string(), status);
+    }
+
+    let body = json!({
+        "status": if ready { "ok" } else { "error" },
+        "components": Value::Object(components),
+    });
+    match ready {
+        true => HttpResponse::Ok().json(body),
+        false => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, http::StatusCode};
+
+    async fn body(response: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ready_components() {
+        let checks: Vec<Check> = vec![
+            ("attestation", tokio::spawn(async { Ok(()) })),
+            ("repository", tokio::spawn(async { Ok(()) })),
+        ];
+
+        let response = readiness(checks, Duration::from_secs(1)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            json!({
+                "status": "ok",
+                "components": {
+                    "attestation": {"status": "ok"},
+                    "repository": {"status": "ok"},
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unready_components() {
+        let checks: Vec<Check> = vec![
+            ("attestation", tokio::spawn(async { Ok(()) })),
+            (
+                "repository",
+                tokio::spawn(async { Err(anyhow::anyhow!("permission denied")) }),
+            ),
+            (
+                "policy",
+                tokio::spawn(async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                }),
+            ),
+        ];
+
+        let response = readiness(checks, Duration::from_millis(100)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body(response).await,
+            json!({
+                "status": "error",
+                "components": {
+                    "attestation": {"status": "ok"},
+                    "repository": {"status": "error", "error": "permission denied"},
+                    "policy": {"status": "error", "error": "timed out"},
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,19 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use log::error;
+use prometheus::TEXT_FORMAT;
+
+use super::*;
+
+/// GET /metrics
+pub(crate) async fn metrics() -> HttpResponse {
+    match crate::metrics::encode() {
+        Ok(metrics) => HttpResponse::Ok().content_type(TEXT_FORMAT).body(metrics),
+        Err(e) => {
+            error!("Encode metrics failed: {e:#}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
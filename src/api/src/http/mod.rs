@@ -4,19 +4,38 @@
 
 #[cfg(feature = "as")]
 use crate::attestation::AttestationService;
+#[cfg(feature = "resource")]
+use crate::audit::{AuditEvent, AuditLog};
 use crate::auth::validate_auth;
+#[cfg(feature = "certificate")]
+use crate::certificate::CertificateIssuer;
+#[cfg(feature = "resource")]
+use crate::jwe::{jwe, JweConfig, TeeKey};
+use crate::metrics::{tee_label, SUCCESS, UNKNOWN_TEE};
 #[cfg(feature = "policy")]
-use crate::policy_engine::PolicyEngine;
+use crate::policy_engine::{deny_reasons, PolicyEngine};
 #[cfg(feature = "resource")]
-use crate::resource::{set_secret_resource, Repository, ResourceDesc};
+use crate::resource::{
+    delete_secret_resource, is_valid_path_component, rollback_secret_resource, set_secret_resource,
+    KeyDerivationConfig, Repository, ResourceDesc, ResourceLimits, ResourceNotFound,
+    ResourceUnavailable,
+};
+#[cfg(all(feature = "as", feature = "resource"))]
+use crate::session::verify_proof;
 #[cfg(feature = "as")]
-use crate::session::{Session, SessionMap, KBS_SESSION_ID};
+use crate::session::{
+    Session, SessionStore, SessionTransport, KBS_SESSION_ID, KBS_SESSION_ID_HEADER,
+    SESSION_ID_PARAM,
+};
 #[cfg(feature = "resource")]
 use crate::token::AttestationTokenVerifier;
+use actix_web::http::header::Header;
 use actix_web::Responder;
 use actix_web::{body::BoxBody, web, HttpRequest, HttpResponse};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
 use jwt_simple::prelude::Ed25519PublicKey;
-use kbs_types::{Attestation, Challenge, ErrorInformation, Request};
+use kbs_types::{Challenge, ErrorInformation, Request, Tee};
+use prometheus::IntCounterVec;
 use std::sync::Arc;
 use strum_macros::EnumString;
 use tokio::sync::{Mutex, RwLock};
@@ -24,8 +43,13 @@ use tokio::sync::{Mutex, RwLock};
 #[cfg(feature = "as")]
 mod attest;
 
+#[cfg(feature = "certificate")]
+mod certificate;
+
 mod config;
 mod error;
+mod health;
+mod metrics;
 
 #[cfg(feature = "resource")]
 mod resource;
@@ -34,6 +58,10 @@ mod resource;
 /// RESTful APIs that related to attestation
 pub use attest::*;
 
+#[cfg(feature = "certificate")]
+/// RESTful APIs that issue certificates, need attestation verification
+pub use certificate::*;
+
 /// RESTful APIs that configure KBS and AS, require user authentication
 pub use self::config::*;
 
@@ -42,3 +70,95 @@ pub use self::config::*;
 pub use resource::*;
 
 pub use error::*;
+
+/// Liveness and readiness probes
+pub use health::*;
+
+/// Prometheus metrics endpoint
+pub use metrics::*;
+
+/// Count a request in `counter`, by TEE type and outcome.
+fn count_request<T>(counter: &IntCounterVec, tee: &str, result: &Result<T>) {
+    let outcome = match result {
+        Ok(_) => SUCCESS,
+        Err(e) => e.as_ref(),
+    };
+    counter.with_label_values(&[tee, outcome]).inc();
+}
+
+#[cfg(feature = "as")]
+/// Get the KBS session id of the request, from its `kbs-session-id` cookie,
+/// else from its `KBS-Session-Id` header, else from its bearer
+/// `Authorization` header.
+fn session_id(request: &HttpRequest) -> Option<String> {
+    if let Some(cookie) = request.cookie(KBS_SESSION_ID) {
+        return Some(cookie.value().to_string());
+    }
+
+    if let Some(id) = request
+        .headers()
+        .get(KBS_SESSION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Some(id.to_string());
+    }
+
+    // A bearer token that is not a session id is an attestation token.
+    Authorization::<Bearer>::parse(request)
+        .ok()
+        .map(|authorization| authorization.into_scheme().token().to_string())
+}
+
+#[cfg(feature = "as")]
+/// Get the id and TEE type of the KBS session of the request, if any.
+async fn request_session(
+    request: &HttpRequest,
+    map: &Arc<dyn SessionStore>,
+) -> Option<(String, Tee)> {
+    let id = session_id(request)?;
+    let session = map.get(&id).await.ok()??;
+
+    Some((session.id().to_string(), session.tee()))
+}
+
+#[cfg(feature = "resource")]
+#[derive(serde::Deserialize)]
+struct ResourceQuery {
+    version: Option<u64>,
+}
+
+#[cfg(feature = "resource")]
+/// Get the resource version selected by the `version` query parameter, if any.
+fn resource_version(request: &HttpRequest) -> Result<Option<u64>> {
+    let query = web::Query::<ResourceQuery>::from_query(request.query_string())
+        .map_err(|e| Error::InvalidRequest(format!("illegal resource query: {e}")))?;
+    Ok(query.version)
+}
+
+#[cfg(all(test, feature = "as"))]
+mod tests {
+    use super::*;
+    use actix_web::{cookie::Cookie, test::TestRequest};
+
+    #[test]
+    fn request_session_id() {
+        let request = TestRequest::default()
+            .cookie(Cookie::new(KBS_SESSION_ID, "cookie-id"))
+            .insert_header((KBS_SESSION_ID_HEADER, "header-id"))
+            .to_http_request();
+        assert_eq!(session_id(&request), Some("cookie-id".to_string()));
+
+        let request = TestRequest::default()
+            .insert_header((KBS_SESSION_ID_HEADER, "header-id"))
+            .insert_header(("Authorization", "Bearer bearer-id"))
+            .to_http_request();
+        assert_eq!(session_id(&request), Some("header-id".to_string()));
+
+        let request = TestRequest::default()
+            .insert_header(("Authorization", "Bearer bearer-id"))
+            .to_http_request();
+        assert_eq!(session_id(&request), Some("bearer-id".to_string()));
+
+        assert_eq!(session_id(&TestRequest::default().to_http_request()), None);
+    }
+}
@@ -2,59 +2,31 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use actix_web::{http::header::Header, web::Bytes};
+use actix_web::http::header::Header;
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
-use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
-use anyhow::{anyhow, bail};
-use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-use base64::Engine;
-use kbs_types::{Response, TeePubKey};
 use log::{error, info};
-use rand::{rngs::OsRng, Rng};
-use rsa::{BigUint, Pkcs1v15Encrypt, RsaPublicKey};
 use serde::Deserialize;
-use serde_json::{json, Deserializer, Value};
+use serde_json::Value;
 
+#[cfg(feature = "policy")]
+use crate::metrics::POLICY_EVALUATION_DURATION;
+use crate::metrics::{REPOSITORY_READ_ERRORS, RESOURCE_REQUESTS};
 use crate::raise_error;
 
 use super::*;
 
-#[allow(unused_assignments)]
-/// GET /resource/{repository}/{type}/{tag}
-/// GET /resource/{type}/{tag}
+/// GET /resource/{repository}/{type}/{tag}[?version={version}]
+/// GET /resource/{type}/{tag}[?version={version}]
 pub(crate) async fn get_resource(
     request: HttpRequest,
     repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
-    #[cfg(feature = "as")] map: web::Data<SessionMap<'_>>,
+    #[cfg(feature = "as")] map: web::Data<Arc<dyn SessionStore>>,
     token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
+    jwe_config: web::Data<JweConfig>,
+    key_derivation: web::Data<KeyDerivationConfig>,
+    audit_log: web::Data<AuditLog>,
     #[cfg(feature = "policy")] policy_engine: web::Data<PolicyEngine>,
 ) -> Result<HttpResponse> {
-    #[allow(unused_mut)]
-    let mut claims_option = None;
-    #[cfg(feature = "as")]
-    {
-        claims_option = get_attest_claims_from_session(&request, map).await.ok();
-    }
-    let claims_str = if let Some(c) = claims_option {
-        info!("Get pkey from session.");
-        c
-    } else {
-        info!("Get pkey from auth header");
-        get_attest_claims_from_header(&request, token_verifier).await?
-    };
-    let claims: Value = serde_json::from_str(&claims_str).map_err(|e| {
-        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
-    })?;
-
-    let pkey_value = claims
-        .get("tee-pubkey")
-        .ok_or(Error::AttestationClaimsParseFailed(String::from(
-            "No `tee-pubkey` in the attestation claims",
-        )))?;
-    let pubkey = TeePubKey::deserialize(pkey_value).map_err(|e| {
-        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
-    })?;
-
     let resource_description = ResourceDesc {
         repository_name: request
             .match_info()
@@ -71,6 +43,7 @@ pub(crate) async fn get_resource(
             .get("tag")
             .ok_or_else(|| Error::InvalidRequest(String::from("no `tag` in url")))?
             .to_string(),
+        version: resource_version(&request)?,
     };
 
     if !resource_description.is_valid() {
@@ -79,6 +52,68 @@ pub(crate) async fn get_resource(
 
     info!("Resource description: {:?}", &resource_description);
 
+    let mut audit_event = AuditEvent::new(&resource_description);
+    #[allow(unused_mut)]
+    let mut tee = UNKNOWN_TEE.to_string();
+    #[cfg(feature = "as")]
+    if let Some((session_id, session_tee)) = request_session(&request, &map).await {
+        tee = tee_label(&session_tee);
+        audit_event.set_session(&session_id, session_tee);
+    }
+
+    let response = release_resource(
+        &request,
+        resource_description,
+        repository,
+        #[cfg(feature = "as")]
+        map,
+        token_verifier,
+        &jwe_config,
+        &key_derivation,
+        &audit_log,
+        &mut audit_event,
+        #[cfg(feature = "policy")]
+        &policy_engine,
+    )
+    .await;
+
+    // No resource is released without being recorded in the audit trail.
+    audit_event.set_outcome(&response);
+    let response = match audit_log.record(audit_event).await {
+        Ok(()) => response,
+        Err(e) => {
+            error!("Audit trail record failed: {e:#}");
+            Err(Error::AuditFailed(format!("{e:#}")))
+        }
+    };
+    count_request(&RESOURCE_REQUESTS, &tee, &response);
+
+    response
+}
+
+async fn release_resource(
+    request: &HttpRequest,
+    resource_description: ResourceDesc,
+    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    #[cfg(feature = "as")] map: web::Data<Arc<dyn SessionStore>>,
+    token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
+    jwe_config: &JweConfig,
+    key_derivation: &KeyDerivationConfig,
+    audit_log: &AuditLog,
+    audit_event: &mut AuditEvent,
+    #[cfg(feature = "policy")] policy_engine: &PolicyEngine,
+) -> Result<HttpResponse> {
+    let claims_str = attestation_claims(
+        request,
+        #[cfg(feature = "as")]
+        map,
+        token_verifier,
+    )
+    .await?;
+    let claims = parse_claims(&claims_str)?;
+    audit_log.select_claims(audit_event, &claims);
+    let pubkey = tee_pubkey(&claims)?;
+
     #[cfg(feature = "policy")]
     {
         let resource_path = format!(
@@ -87,23 +122,127 @@ pub(crate) async fn get_resource(
             resource_description.resource_type,
             resource_description.resource_tag
         );
-        policy_engine
-            .0
-            .lock()
+        let decision = evaluate_policy(policy_engine, resource_path, claims_str).await;
+        match &decision {
+            Ok(()) => audit_event.set_decision(crate::audit::PolicyDecision::Allow),
+            Err(Error::PolicyDeny(_)) => {
+                audit_event.set_decision(crate::audit::PolicyDecision::Deny)
+            }
+            Err(_) => (),
+        }
+        decision?;
+    }
+
+    // The master secrets only feed the key derivation, whatever the policy.
+    if key_derivation.is_master_secret(&resource_description) {
+        return Err(Error::ResourceUnavailable(
+            "the master secret of derived keys is not released".to_string(),
+        ));
+    }
+
+    let repository = repository.read().await;
+    let resource_byte = match key_derivation.key(&resource_description) {
+        Some(derived_key) => derived_key
+            .derive(&*repository, &resource_description, &claims)
             .await
-            .evaluate(resource_path, claims_str)
+            .map_err(|e| Error::ReadSecretFailed(format!("{e:#}")))?,
+        None => repository
+            .release_secret_resource(resource_description)
             .await
-            .map_err(|e| Error::PolicyEngineFailed(e.to_string()))?;
+            .map_err(|e| match e.downcast_ref::<ResourceUnavailable>() {
+                Some(unavailable) => Error::ResourceUnavailable(unavailable.to_string()),
+                None => {
+                    REPOSITORY_READ_ERRORS.inc();
+                    Error::ReadSecretFailed(e.to_string())
+                }
+            })?,
+    };
+
+    jwe_response(pubkey, resource_byte, jwe_config)
+}
+
+/// Get the attestation claims of the requester, from its KBS session cookie
+/// or else from the attestation token in its `Authorization` header.
+#[allow(unused_assignments)]
+pub(crate) async fn attestation_claims(
+    request: &HttpRequest,
+    #[cfg(feature = "as")] map: web::Data<Arc<dyn SessionStore>>,
+    token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
+) -> Result<String> {
+    #[allow(unused_mut)]
+    let mut claims_option = None;
+    #[cfg(feature = "as")]
+    {
+        claims_option = match get_attest_claims_from_session(request, map).await {
+            Ok(claims) => Some(claims),
+            // A key bound session is not used without its proof, rather
+            // than trying the `Authorization` header instead.
+            Err(e @ Error::SessionProofFailed(_)) => return Err(e),
+            Err(_) => None,
+        };
     }
+    if let Some(c) = claims_option {
+        info!("Get pkey from session.");
+        Ok(c)
+    } else {
+        info!("Get pkey from auth header");
+        get_attest_claims_from_header(request, token_verifier).await
+    }
+}
 
-    let resource_byte = repository
-        .read()
-        .await
-        .read_secret_resource(resource_description)
-        .await
-        .map_err(|e| Error::ReadSecretFailed(e.to_string()))?;
+pub(crate) fn parse_claims(claims_str: &str) -> Result<Value> {
+    serde_json::from_str(claims_str).map_err(|e| {
+        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+    })
+}
 
-    let jwe = jwe(pubkey, resource_byte)?;
+/// Get the TEE public key, which the responses are encrypted to, from the
+/// attestation claims.
+pub(crate) fn tee_pubkey(claims: &Value) -> Result<TeeKey> {
+    let pkey_value = claims
+        .get("tee-pubkey")
+        .ok_or(Error::AttestationClaimsParseFailed(String::from(
+            "No `tee-pubkey` in the attestation claims",
+        )))?;
+    TeeKey::deserialize(pkey_value).map_err(|e| {
+        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+    })
+}
+
+/// Check that the resource policy grants access to `resource_path`.
+#[cfg(feature = "policy")]
+pub(crate) async fn evaluate_policy(
+    policy_engine: &PolicyEngine,
+    resource_path: String,
+    claims_str: String,
+) -> Result<()> {
+    let policy_engine = policy_engine.0.lock().await;
+    let timer = POLICY_EVALUATION_DURATION.start_timer();
+    let evaluation = policy_engine.evaluate(resource_path, claims_str).await;
+    timer.observe_duration();
+    let (allow, policy_output) =
+        evaluation.map_err(|e| Error::PolicyEngineFailed(e.to_string()))?;
+
+    if !allow {
+        let reasons = deny_reasons(&policy_output);
+        error!("Resource policy denied access: {:?}", reasons);
+        raise_error!(Error::PolicyDeny(if reasons.is_empty() {
+            "no reasons given by the resource policy".to_string()
+        } else {
+            reasons.join("; ")
+        }));
+    }
+
+    Ok(())
+}
+
+/// Respond with `data` wrapped in a JWE, encrypted to the TEE public key.
+pub(crate) fn jwe_response(
+    pubkey: TeeKey,
+    data: Vec<u8>,
+    jwe_config: &JweConfig,
+) -> Result<HttpResponse> {
+    let jwe = jwe(pubkey, data, jwe_config).map_err(|e| Error::JWEFailed(format!("{e:#}")))?;
 
     let res = serde_json::to_string(&jwe).map_err(|e| Error::JWEFailed(e.to_string()))?;
 
@@ -115,29 +254,25 @@ pub(crate) async fn get_resource(
 #[cfg(feature = "as")]
 async fn get_attest_claims_from_session(
     request: &HttpRequest,
-    map: web::Data<SessionMap<'_>>,
+    map: web::Data<Arc<dyn SessionStore>>,
 ) -> Result<String> {
-    // check cookie
-    let cookie = request
-        .cookie(KBS_SESSION_ID)
-        .ok_or(Error::UnAuthenticatedCookie)?;
+    let id = session_id(request).ok_or(Error::UnAuthenticatedCookie)?;
 
-    let session_map = map.sessions.read().await;
-    let locked_session = session_map
-        .get(cookie.value())
+    let session = map
+        .get(&id)
+        .await
+        .map_err(|e| Error::SessionStoreFailed(format!("{e:#}")))?
         .ok_or(Error::UnAuthenticatedCookie)?;
 
-    let session = locked_session.lock().await;
-
     info!("Cookie {} request to get resource", session.id());
 
     if !session.is_authenticated() {
-        error!("UnAuthenticated KBS cookie {}", cookie.value());
+        error!("UnAuthenticated KBS cookie {id}");
         raise_error!(Error::UnAuthenticatedCookie);
     }
 
     if session.is_expired() {
-        error!("Expired KBS cookie {}", cookie.value());
+        error!("Expired KBS cookie {id}");
         raise_error!(Error::ExpiredCookie);
     }
 
@@ -147,6 +282,16 @@ async fn get_attest_claims_from_session(
             "No attestation claims in the session".into(),
         ))?;
 
+    if session.is_tee_key_bound() {
+        let tee_key = tee_pubkey(&parse_claims(&claims)?)?;
+        verify_proof(request, &tee_key, map.get_ref().as_ref(), &id)
+            .await
+            .map_err(|e| {
+                error!("Invalid proof for KBS session {id}: {e:#}");
+                Error::SessionProofFailed(format!("{e:#}"))
+            })?;
+    }
+
     Ok(claims)
 }
 
@@ -167,59 +312,3 @@ async fn get_attest_claims_from_header(
         .map_err(|e| Error::TokenParseFailed(format!("verify token failed: {e}")))?;
     Ok(claims)
 }
-
-const RSA_ALGORITHM: &str = "RSA1_5";
-const AES_GCM_256_ALGORITHM: &str = "A256GCM";
-
-pub(crate) fn jwe(tee_pub_key: TeePubKey, payload_data: Vec<u8>) -> Result<Response> {
-    if tee_pub_key.alg != *RSA_ALGORITHM {
-        raise_error!(Error::JWEFailed(format!(
-            "algorithm is not {RSA_ALGORITHM} but {}",
-            tee_pub_key.alg
-        )));
-    }
-
-    let mut rng = rand::thread_rng();
-
-    let aes_sym_key = Aes256Gcm::generate_key(&mut OsRng);
-    let cipher = Aes256Gcm::new(&aes_sym_key);
-    let iv = rng.gen::<[u8; 12]>();
-    let nonce = Nonce::from_slice(&iv);
-    let encrypted_payload_data = cipher
-        .encrypt(nonce, payload_data.as_slice())
-        .map_err(|e| Error::JWEFailed(format!("AES encrypt Resource payload failed: {e:?}")))?;
-
-    let k_mod = URL_SAFE_NO_PAD
-        .decode(&tee_pub_key.k_mod)
-        .map_err(|e| Error::JWEFailed(format!("base64 decode k_mod failed: {e:?}")))?;
-    let n = BigUint::from_bytes_be(&k_mod);
-    let k_exp = URL_SAFE_NO_PAD
-        .decode(&tee_pub_key.k_exp)
-        .map_err(|e| Error::JWEFailed(format!("base64 decode k_exp failed: {e:?}")))?;
-    let e = BigUint::from_bytes_be(&k_exp);
-
-    let rsa_pub_key = RsaPublicKey::new(n, e).map_err(|e| {
-        Error::JWEFailed(format!(
-            "Building RSA key from modulus and exponent failed: {e:?}"
-        ))
-    })?;
-    let sym_key: &[u8] = aes_sym_key.as_slice();
-    let wrapped_sym_key = rsa_pub_key
-        .encrypt(&mut rng, Pkcs1v15Encrypt, sym_key)
-        .map_err(|e| Error::JWEFailed(format!("RSA encrypt sym key failed: {e:?}")))?;
-
-    let protected_header = json!(
-    {
-       "alg": RSA_ALGORITHM.to_string(),
-       "enc": AES_GCM_256_ALGORITHM.to_string(),
-    });
-
-    Ok(Response {
-        protected: serde_json::to_string(&protected_header)
-            .map_err(|e| Error::JWEFailed(format!("serde protected_header failed: {e}")))?,
-        encrypted_key: URL_SAFE_NO_PAD.encode(wrapped_sym_key),
-        iv: URL_SAFE_NO_PAD.encode(iv),
-        ciphertext: URL_SAFE_NO_PAD.encode(encrypted_payload_data),
-        tag: "".to_string(),
-    })
-}
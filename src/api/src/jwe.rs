@@ -0,0 +1,508 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON Web Encryption (RFC 7516) of the resources released to a TEE.
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use aes_kw::KekAes256;
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use kbs_types::Response;
+use p256::elliptic_curve::{
+    ecdh::EphemeralSecret,
+    sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    AffinePoint, CurveArithmetic, FieldBytesSize, PublicKey,
+};
+use p256::NistP256;
+use p384::NistP384;
+use rand::{rngs::OsRng, Rng};
+use rsa::{BigUint, Oaep, Pkcs1v15Encrypt, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub(crate) use crate::tee_key::TeeKey;
+
+const RSA1_5_ALGORITHM: &str = "RSA1_5";
+const RSA_OAEP_ALGORITHM: &str = "RSA-OAEP";
+const RSA_OAEP_256_ALGORITHM: &str = "RSA-OAEP-256";
+const ECDH_ES_ALGORITHM: &str = "ECDH-ES";
+const ECDH_ES_A256KW_ALGORITHM: &str = "ECDH-ES+A256KW";
+const AES_GCM_256_ALGORITHM: &str = "A256GCM";
+const AES_GCM_256_KEY_SIZE: usize = 32;
+const AES_GCM_TAG_SIZE: usize = 16;
+
+const P256_CURVE: &str = "P-256";
+const P384_CURVE: &str = "P-384";
+const X25519_CURVE: &str = "X25519";
+const X25519_KEY_SIZE: usize = 32;
+
+/// Curves supported for `EC` and `OKP` TEE keys.
+#[cfg(feature = "as")]
+pub(crate) const SUPPORTED_CURVES: [&str; 3] = [P256_CURVE, P384_CURVE, X25519_CURVE];
+
+/// Resource response encryption configuration.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct JweConfig {
+    /// Accept `RSA1_5` TEE keys, for clients that predate RSA-OAEP support.
+    /// Such responses keep the legacy KBS format: a plain JSON protected
+    /// header, not used as AAD, and the GCM tag appended to the ciphertext.
+    /// WARNING: `RSA1_5` is vulnerable to padding oracle attacks.
+    #[serde(default)]
+    pub allow_legacy_rsa1_5: bool,
+}
+
+/// Key management algorithms accepted for TEE keys, advertised to clients.
+#[cfg(feature = "as")]
+pub(crate) fn supported_algorithms(config: &JweConfig) -> Vec<&'static str> {
+    let mut algorithms = vec![
+        RSA_OAEP_ALGORITHM,
+        RSA_OAEP_256_ALGORITHM,
+        ECDH_ES_ALGORITHM,
+        ECDH_ES_A256KW_ALGORITHM,
+    ];
+    if config.allow_legacy_rsa1_5 {
+        algorithms.push(RSA1_5_ALGORITHM);
+    }
+
+    algorithms
+}
+
+/// Encrypt `payload_data` for the TEE owning `tee_key`.
+///
+/// The payload is encrypted with an `A256GCM` content encryption key, which is
+/// either wrapped with the TEE key or agreed with it, as given by its `alg`.
+pub(crate) fn jwe(tee_key: TeeKey, payload_data: Vec<u8>, config: &JweConfig) -> Result<Response> {
+    let alg = tee_key.alg();
+    let legacy = alg == RSA1_5_ALGORITHM;
+    if legacy && !config.allow_legacy_rsa1_5 {
+        bail!("{RSA1_5_ALGORITHM} is only accepted in legacy mode");
+    }
+
+    let mut protected_header = json!(
+    {
+       "alg": alg,
+       "enc": AES_GCM_256_ALGORITHM.to_string(),
+    });
+
+    let mut rng = rand::thread_rng();
+
+    let (cek, encrypted_key) = match (&tee_key, alg) {
+        (
+            TeeKey::Rsa { n, e, .. },
+            RSA_OAEP_ALGORITHM | RSA_OAEP_256_ALGORITHM | RSA1_5_ALGORITHM,
+        ) => {
+            let cek = rng.gen::<[u8; AES_GCM_256_KEY_SIZE]>();
+            let rsa_pub_key = rsa_public_key(n, e)?;
+            let wrapped_cek = match alg {
+                RSA_OAEP_ALGORITHM => rsa_pub_key.encrypt(&mut rng, Oaep::new::<Sha1>(), &cek),
+                RSA_OAEP_256_ALGORITHM => {
+                    rsa_pub_key.encrypt(&mut rng, Oaep::new::<Sha256>(), &cek)
+                }
+                _ => rsa_pub_key.encrypt(&mut rng, Pkcs1v15Encrypt, &cek),
+            }
+            .map_err(|e| anyhow!("RSA encrypt sym key failed: {e:?}"))?;
+            (cek.to_vec(), wrapped_cek)
+        }
+        (TeeKey::Ec { .. } | TeeKey::Okp { .. }, ECDH_ES_ALGORITHM) => {
+            // Direct key agreement, the derived key is the content encryption key.
+            let (z, epk) = ecdh(&tee_key)?;
+            protected_header["epk"] = epk;
+            (
+                concat_kdf(&z, AES_GCM_256_ALGORITHM, AES_GCM_256_KEY_SIZE, &[], &[]),
+                Vec::new(),
+            )
+        }
+        (TeeKey::Ec { .. } | TeeKey::Okp { .. }, ECDH_ES_A256KW_ALGORITHM) => {
+            let (z, epk) = ecdh(&tee_key)?;
+            protected_header["epk"] = epk;
+            let kek = concat_kdf(&z, ECDH_ES_A256KW_ALGORITHM, AES_GCM_256_KEY_SIZE, &[], &[]);
+            let cek = rng.gen::<[u8; AES_GCM_256_KEY_SIZE]>();
+            let wrapped_cek = KekAes256::try_from(kek.as_slice())
+                .and_then(|kek| kek.wrap_vec(&cek))
+                .map_err(|e| anyhow!("AES key wrap failed: {e}"))?;
+            (cek.to_vec(), wrapped_cek)
+        }
+        (_, alg) => bail!("unsupported key management algorithm {alg} for this TEE key type"),
+    };
+
+    let protected_header = protected_header.to_string();
+
+    // RFC 7516 binds the BASE64URL encoded protected header to the
+    // ciphertext, as the AAD of the content encryption.
+    let protected = match legacy {
+        true => protected_header,
+        false => URL_SAFE_NO_PAD.encode(protected_header),
+    };
+    let aad = match legacy {
+        true => &[][..],
+        false => protected.as_bytes(),
+    };
+
+    let cipher = Aes256Gcm::new_from_slice(&cek)
+        .map_err(|e| anyhow!("Illegal content encryption key: {e}"))?;
+    let iv = rng.gen::<[u8; 12]>();
+    let nonce = Nonce::from_slice(&iv);
+    let mut ciphertext = cipher
+        .encrypt(
+            nonce,
+            Payload {
+                msg: payload_data.as_slice(),
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("AES encrypt Resource payload failed: {e:?}"))?;
+
+    // The AES-GCM output is the ciphertext followed by the tag.
+    let tag = match legacy {
+        true => Vec::new(),
+        false => ciphertext.split_off(ciphertext.len() - AES_GCM_TAG_SIZE),
+    };
+
+    Ok(Response {
+        protected,
+        encrypted_key: URL_SAFE_NO_PAD.encode(encrypted_key),
+        iv: URL_SAFE_NO_PAD.encode(iv),
+        ciphertext: URL_SAFE_NO_PAD.encode(ciphertext),
+        tag: URL_SAFE_NO_PAD.encode(tag),
+    })
+}
+
+fn rsa_public_key(k_mod: &str, k_exp: &str) -> Result<RsaPublicKey> {
+    let k_mod = URL_SAFE_NO_PAD
+        .decode(k_mod)
+        .map_err(|e| anyhow!("base64 decode k_mod failed: {e:?}"))?;
+    let n = BigUint::from_bytes_be(&k_mod);
+    let k_exp = URL_SAFE_NO_PAD
+        .decode(k_exp)
+        .map_err(|e| anyhow!("base64 decode k_exp failed: {e:?}"))?;
+    let e = BigUint::from_bytes_be(&k_exp);
+
+    RsaPublicKey::new(n, e)
+        .map_err(|e| anyhow!("Building RSA key from modulus and exponent failed: {e:?}"))
+}
+
+/// Generate an ephemeral key on the TEE key curve, and agree on a shared
+/// secret with the TEE key. Returns the shared secret and the ephemeral
+/// public key, as a JWK.
+fn ecdh(tee_key: &TeeKey) -> Result<(Vec<u8>, Value)> {
+    match tee_key {
+        TeeKey::Ec { crv, x, y, .. } => {
+            let x = URL_SAFE_NO_PAD.decode(x)?;
+            let y = URL_SAFE_NO_PAD.decode(y)?;
+            match crv.as_str() {
+                P256_CURVE => ecdh_nist::<NistP256>(crv, &x, &y),
+                P384_CURVE => ecdh_nist::<NistP384>(crv, &x, &y),
+                crv => bail!("unsupported EC curve {crv}"),
+            }
+        }
+        TeeKey::Okp { crv, x, .. } if crv == X25519_CURVE => {
+            let x: [u8; X25519_KEY_SIZE] = URL_SAFE_NO_PAD
+                .decode(x)?
+                .try_into()
+                .map_err(|_| anyhow!("illegal X25519 public key size"))?;
+            let ephemeral_secret = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+            let epk = x25519_dalek::PublicKey::from(&ephemeral_secret);
+            let z = ephemeral_secret.diffie_hellman(&x25519_dalek::PublicKey::from(x));
+            if !z.was_contributory() {
+                bail!("illegal X25519 public key");
+            }
+
+            Ok((
+                z.as_bytes().to_vec(),
+                json!({
+                    "kty": "OKP",
+                    "crv": X25519_CURVE,
+                    "x": URL_SAFE_NO_PAD.encode(epk.as_bytes()),
+                }),
+            ))
+        }
+        TeeKey::Okp { crv, .. } => bail!("unsupported OKP curve {crv}"),
+        TeeKey::Rsa { .. } => bail!("ECDH is not supported for RSA keys"),
+    }
+}
+
+fn ecdh_nist<C>(crv: &str, x: &[u8], y: &[u8]) -> Result<(Vec<u8>, Value)>
+where
+    C: CurveArithmetic,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+{
+    // Uncompressed SEC1 point encoding.
+    let sec1_point = [&[0x04], x, y].concat();
+    let tee_pub_key = PublicKey::<C>::from_sec1_bytes(&sec1_point)
+        .map_err(|_| anyhow!("illegal {crv} public key"))?;
+
+    let ephemeral_secret = EphemeralSecret::<C>::random(&mut OsRng);
+    let z = ephemeral_secret.diffie_hellman(&tee_pub_key);
+    let epk = ephemeral_secret.public_key().to_encoded_point(false);
+    let (Some(epk_x), Some(epk_y)) = (epk.x(), epk.y()) else {
+        bail!("illegal ephemeral {crv} public key");
+    };
+
+    Ok((
+        z.raw_secret_bytes().to_vec(),
+        json!({
+            "kty": "EC",
+            "crv": crv,
+            "x": URL_SAFE_NO_PAD.encode(epk_x),
+            "y": URL_SAFE_NO_PAD.encode(epk_y),
+        }),
+    ))
+}
+
+/// Concat KDF (RFC 7518 section 4.6.2) with SHA-256, deriving a key of up to
+/// 256 bits, in a single round, from the ECDH shared secret `z`.
+fn concat_kdf(z: &[u8], algorithm_id: &str, key_size: usize, apu: &[u8], apv: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(z);
+    for info in [algorithm_id.as_bytes(), apu, apv] {
+        hasher.update((info.len() as u32).to_be_bytes());
+        hasher.update(info);
+    }
+    hasher.update(((key_size * 8) as u32).to_be_bytes());
+
+    hasher.finalize()[..key_size].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::elliptic_curve::SecretKey;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+    use rstest::rstest;
+
+    const TEST_DATA: &[u8] = b"testdata";
+
+    /// TEE private key, decrypting the responses in tests.
+    enum TeePrivateKey {
+        Rsa(Box<RsaPrivateKey>),
+        P256(SecretKey<NistP256>),
+        P384(SecretKey<NistP384>),
+        X25519(x25519_dalek::StaticSecret),
+    }
+
+    fn tee_key(alg: &str) -> (TeePrivateKey, TeeKey) {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let pub_key = TeeKey::Rsa {
+            alg: alg.to_string(),
+            n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        };
+        (TeePrivateKey::Rsa(Box::new(key)), pub_key)
+    }
+
+    fn ec_tee_key(alg: &str, crv: &str) -> (TeePrivateKey, TeeKey) {
+        let (key, point) = match crv {
+            P256_CURVE => {
+                let key = SecretKey::<NistP256>::random(&mut OsRng);
+                let point = key.public_key().to_encoded_point(false);
+                (TeePrivateKey::P256(key), point.as_bytes().to_vec())
+            }
+            P384_CURVE => {
+                let key = SecretKey::<NistP384>::random(&mut OsRng);
+                let point = key.public_key().to_encoded_point(false);
+                (TeePrivateKey::P384(key), point.as_bytes().to_vec())
+            }
+            X25519_CURVE => {
+                let key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+                let pub_key = TeeKey::Okp {
+                    alg: alg.to_string(),
+                    crv: crv.to_string(),
+                    x: URL_SAFE_NO_PAD.encode(x25519_dalek::PublicKey::from(&key).as_bytes()),
+                };
+                return (TeePrivateKey::X25519(key), pub_key);
+            }
+            crv => panic!("unexpected crv {crv}"),
+        };
+
+        // Uncompressed SEC1 point: 0x04 || x || y
+        let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+        let pub_key = TeeKey::Ec {
+            alg: alg.to_string(),
+            crv: crv.to_string(),
+            x: URL_SAFE_NO_PAD.encode(x),
+            y: URL_SAFE_NO_PAD.encode(y),
+        };
+        (key, pub_key)
+    }
+
+    /// ECDH shared secret between the TEE key and the ephemeral key `epk`.
+    fn shared_secret(key: &TeePrivateKey, epk: &Value) -> Vec<u8> {
+        let coordinate = |name: &str| URL_SAFE_NO_PAD.decode(epk[name].as_str().unwrap()).unwrap();
+        match key {
+            TeePrivateKey::P256(key) => {
+                let epk = [vec![0x04], coordinate("x"), coordinate("y")].concat();
+                let epk = PublicKey::<NistP256>::from_sec1_bytes(&epk).unwrap();
+                p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine())
+                    .raw_secret_bytes()
+                    .to_vec()
+            }
+            TeePrivateKey::P384(key) => {
+                let epk = [vec![0x04], coordinate("x"), coordinate("y")].concat();
+                let epk = PublicKey::<NistP384>::from_sec1_bytes(&epk).unwrap();
+                p384::ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine())
+                    .raw_secret_bytes()
+                    .to_vec()
+            }
+            TeePrivateKey::X25519(key) => {
+                let epk: [u8; X25519_KEY_SIZE] = coordinate("x").try_into().unwrap();
+                key.diffie_hellman(&x25519_dalek::PublicKey::from(epk))
+                    .as_bytes()
+                    .to_vec()
+            }
+            TeePrivateKey::Rsa(_) => panic!("no ECDH with RSA keys"),
+        }
+    }
+
+    /// Decrypt a response the way a RFC 7516 compliant client does.
+    fn decrypt(key: &TeePrivateKey, response: &Response) -> Vec<u8> {
+        let header = URL_SAFE_NO_PAD.decode(&response.protected).unwrap();
+        let header: Value = serde_json::from_slice(&header).unwrap();
+        assert_eq!(header["enc"], AES_GCM_256_ALGORITHM);
+
+        let encrypted_key = URL_SAFE_NO_PAD.decode(&response.encrypted_key).unwrap();
+        let sym_key = match (key, header["alg"].as_str().unwrap()) {
+            (TeePrivateKey::Rsa(key), RSA_OAEP_ALGORITHM) => {
+                key.decrypt(Oaep::new::<Sha1>(), &encrypted_key).unwrap()
+            }
+            (TeePrivateKey::Rsa(key), RSA_OAEP_256_ALGORITHM) => {
+                key.decrypt(Oaep::new::<Sha256>(), &encrypted_key).unwrap()
+            }
+            (key, ECDH_ES_ALGORITHM) => {
+                assert!(encrypted_key.is_empty());
+                let z = shared_secret(key, &header["epk"]);
+                concat_kdf(&z, AES_GCM_256_ALGORITHM, AES_GCM_256_KEY_SIZE, &[], &[])
+            }
+            (key, ECDH_ES_A256KW_ALGORITHM) => {
+                let z = shared_secret(key, &header["epk"]);
+                let kek = concat_kdf(&z, ECDH_ES_A256KW_ALGORITHM, AES_GCM_256_KEY_SIZE, &[], &[]);
+                KekAes256::try_from(kek.as_slice())
+                    .unwrap()
+                    .unwrap_vec(&encrypted_key)
+                    .unwrap()
+            }
+            (_, alg) => panic!("unexpected alg {alg}"),
+        };
+
+        let mut ciphertext = URL_SAFE_NO_PAD.decode(&response.ciphertext).unwrap();
+        ciphertext.extend(URL_SAFE_NO_PAD.decode(&response.tag).unwrap());
+        let iv = URL_SAFE_NO_PAD.decode(&response.iv).unwrap();
+        Aes256Gcm::new_from_slice(&sym_key)
+            .unwrap()
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: &ciphertext,
+                    aad: response.protected.as_bytes(),
+                },
+            )
+            .unwrap()
+    }
+
+    #[rstest]
+    #[case(RSA_OAEP_ALGORITHM)]
+    #[case(RSA_OAEP_256_ALGORITHM)]
+    fn jwe_roundtrip(#[case] alg: &str) {
+        let (key, pub_key) = tee_key(alg);
+        let response = jwe(pub_key, TEST_DATA.to_vec(), &JweConfig::default()).unwrap();
+        assert_eq!(decrypt(&key, &response), TEST_DATA);
+    }
+
+    #[rstest]
+    #[case(ECDH_ES_ALGORITHM, P256_CURVE)]
+    #[case(ECDH_ES_ALGORITHM, P384_CURVE)]
+    #[case(ECDH_ES_ALGORITHM, X25519_CURVE)]
+    #[case(ECDH_ES_A256KW_ALGORITHM, P256_CURVE)]
+    #[case(ECDH_ES_A256KW_ALGORITHM, P384_CURVE)]
+    #[case(ECDH_ES_A256KW_ALGORITHM, X25519_CURVE)]
+    fn jwe_ecdh_roundtrip(#[case] alg: &str, #[case] crv: &str) {
+        let (key, pub_key) = ec_tee_key(alg, crv);
+        let response = jwe(pub_key, TEST_DATA.to_vec(), &JweConfig::default()).unwrap();
+        assert_eq!(decrypt(&key, &response), TEST_DATA);
+    }
+
+    #[test]
+    fn jwe_legacy_rsa1_5() {
+        let (key, pub_key) = tee_key(RSA1_5_ALGORITHM);
+        assert!(jwe(pub_key.clone(), TEST_DATA.to_vec(), &JweConfig::default()).is_err());
+
+        let config = JweConfig {
+            allow_legacy_rsa1_5: true,
+        };
+        let response = jwe(pub_key, TEST_DATA.to_vec(), &config).unwrap();
+        assert!(response.tag.is_empty());
+
+        let TeePrivateKey::Rsa(key) = key else {
+            unreachable!()
+        };
+        let header: Value = serde_json::from_str(&response.protected).unwrap();
+        assert_eq!(header["alg"], RSA1_5_ALGORITHM);
+        let encrypted_key = URL_SAFE_NO_PAD.decode(&response.encrypted_key).unwrap();
+        let sym_key = key.decrypt(Pkcs1v15Encrypt, &encrypted_key).unwrap();
+        let ciphertext = URL_SAFE_NO_PAD.decode(&response.ciphertext).unwrap();
+        let iv = URL_SAFE_NO_PAD.decode(&response.iv).unwrap();
+        let data = Aes256Gcm::new_from_slice(&sym_key)
+            .unwrap()
+            .decrypt(Nonce::from_slice(&iv), ciphertext.as_slice())
+            .unwrap();
+        assert_eq!(data, TEST_DATA);
+    }
+
+    #[rstest]
+    #[case(tee_key("RSA-OAEP-384").1)]
+    #[case(tee_key(ECDH_ES_ALGORITHM).1)]
+    #[case(ec_tee_key(RSA_OAEP_ALGORITHM, P256_CURVE).1)]
+    #[case(ec_tee_key("ECDH-ES+A128KW", P256_CURVE).1)]
+    fn jwe_unsupported_alg(#[case] pub_key: TeeKey) {
+        assert!(jwe(pub_key, TEST_DATA.to_vec(), &JweConfig::default()).is_err());
+    }
+
+    #[test]
+    fn jwe_unsupported_curve() {
+        let pub_key = TeeKey::Ec {
+            alg: ECDH_ES_ALGORITHM.to_string(),
+            crv: "P-521".to_string(),
+            x: URL_SAFE_NO_PAD.encode([1u8; 66]),
+            y: URL_SAFE_NO_PAD.encode([1u8; 66]),
+        };
+        assert!(jwe(pub_key, TEST_DATA.to_vec(), &JweConfig::default()).is_err());
+    }
+
+    #[test]
+    fn jwe_invalid_ec_point() {
+        let (_, pub_key) = ec_tee_key(ECDH_ES_ALGORITHM, P256_CURVE);
+        let TeeKey::Ec { alg, crv, x, .. } = pub_key else {
+            unreachable!()
+        };
+        let pub_key = TeeKey::Ec {
+            alg,
+            crv,
+            y: x.clone(),
+            x,
+        };
+        assert!(jwe(pub_key, TEST_DATA.to_vec(), &JweConfig::default()).is_err());
+    }
+
+    #[test]
+    fn concat_kdf_rfc7518_vector() {
+        // RFC 7518 Appendix C
+        let z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        let key = concat_kdf(&z, "A128GCM", 16, b"Alice", b"Bob");
+        assert_eq!(
+            key,
+            [86, 170, 141, 234, 248, 35, 109, 32, 92, 34, 40, 205, 113, 167, 16, 26]
+        );
+    }
+}
@@ -21,14 +21,21 @@ use actix_web::{middleware, web, App, HttpServer};
 use anyhow::{anyhow, bail, Context, Result};
 #[cfg(feature = "as")]
 use attestation::AttestationService;
+#[cfg(feature = "resource")]
+use audit::{AuditConfig, AuditLog};
+#[cfg(feature = "certificate")]
+use certificate::{CertificateIssuer, CertificateIssuerConfig};
+#[cfg(feature = "resource")]
+use jwe::JweConfig;
 use jwt_simple::prelude::Ed25519PublicKey;
+use metrics::MetricsConfig;
 #[cfg(feature = "resource")]
-use resource::RepositoryConfig;
+use resource::{KeyDerivationConfig, RepositoryConfig, RepositoryEncryptionConfig};
 use semver::{BuildMetadata, Prerelease, Version, VersionReq};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 #[cfg(feature = "resource")]
-use token::AttestationTokenVerifierType;
+use token::{AttestationTokenVerifierConfig, AttestationTokenVerifierType};
 
 #[cfg(feature = "rustls")]
 use rustls::ServerConfig;
@@ -37,7 +44,7 @@ use rustls::ServerConfig;
 use openssl::ssl::SslAcceptorBuilder;
 
 #[cfg(feature = "as")]
-use crate::session::SessionMap;
+use crate::session::{reap_sessions, SessionConfig};
 
 #[cfg(feature = "policy")]
 use crate::policy_engine::{PolicyEngine, PolicyEngineConfig};
@@ -50,13 +57,25 @@ pub mod attestation;
 /// KBS config
 pub mod config;
 
+#[cfg(feature = "resource")]
+mod audit;
+
 mod auth;
 #[allow(unused_imports)]
 mod http;
+mod metrics;
+
+#[cfg(feature = "certificate")]
+mod certificate;
+
+#[cfg(feature = "resource")]
+mod jwe;
 
 #[cfg(feature = "resource")]
 mod resource;
 
+mod tee_key;
+
 #[cfg(feature = "as")]
 mod session;
 
@@ -69,7 +88,7 @@ pub mod policy_engine;
 
 static KBS_PREFIX: &str = "/kbs";
 static KBS_MAJOR_VERSION: u64 = 0;
-static KBS_MINOR_VERSION: u64 = 1;
+static KBS_MINOR_VERSION: u64 = 2;
 static KBS_PATCH_VERSION: u64 = 0;
 
 lazy_static! {
@@ -106,13 +125,28 @@ pub struct ApiServer {
 
     #[cfg(feature = "as")]
     attestation_service: AttestationService,
+    #[cfg(feature = "as")]
+    session_config: SessionConfig,
 
     http_timeout: i64,
     insecure_api: bool,
+    metrics_config: Option<MetricsConfig>,
     #[cfg(feature = "resource")]
     repository_config: RepositoryConfig,
     #[cfg(feature = "resource")]
+    repository_encryption_config: Option<RepositoryEncryptionConfig>,
+    #[cfg(feature = "resource")]
     attestation_token_type: AttestationTokenVerifierType,
+    #[cfg(feature = "resource")]
+    attestation_token_config: AttestationTokenVerifierConfig,
+    #[cfg(feature = "resource")]
+    jwe_config: JweConfig,
+    #[cfg(feature = "resource")]
+    key_derivation_config: KeyDerivationConfig,
+    #[cfg(feature = "resource")]
+    audit_config: Option<AuditConfig>,
+    #[cfg(feature = "certificate")]
+    certificate_issuer_config: Option<CertificateIssuerConfig>,
     #[cfg(feature = "policy")]
     policy_engine_config: PolicyEngineConfig,
 }
@@ -127,11 +161,21 @@ impl ApiServer {
         insecure: bool,
 
         #[cfg(feature = "as")] attestation_service: &AttestationService,
+        #[cfg(feature = "as")] session_config: SessionConfig,
 
         http_timeout: i64,
         insecure_api: bool,
+        metrics_config: Option<MetricsConfig>,
         #[cfg(feature = "resource")] repository_config: RepositoryConfig,
+        #[cfg(feature = "resource")] repository_encryption_config: Option<
+            RepositoryEncryptionConfig,
+        >,
         #[cfg(feature = "resource")] attestation_token_type: AttestationTokenVerifierType,
+        #[cfg(feature = "resource")] attestation_token_config: AttestationTokenVerifierConfig,
+        #[cfg(feature = "resource")] jwe_config: JweConfig,
+        #[cfg(feature = "resource")] key_derivation_config: KeyDerivationConfig,
+        #[cfg(feature = "resource")] audit_config: Option<AuditConfig>,
+        #[cfg(feature = "certificate")] certificate_issuer_config: Option<CertificateIssuerConfig>,
         #[cfg(feature = "policy")] policy_engine_config: PolicyEngineConfig,
     ) -> Result<Self> {
         if !insecure && (private_key.is_none() || certificate.is_none()) {
@@ -153,13 +197,28 @@ impl ApiServer {
 
             #[cfg(feature = "as")]
             attestation_service: attestation_service.clone(),
+            #[cfg(feature = "as")]
+            session_config,
 
             http_timeout,
             insecure_api,
+            metrics_config,
             #[cfg(feature = "resource")]
             repository_config,
             #[cfg(feature = "resource")]
+            repository_encryption_config,
+            #[cfg(feature = "resource")]
             attestation_token_type,
+            #[cfg(feature = "resource")]
+            attestation_token_config,
+            #[cfg(feature = "resource")]
+            jwe_config,
+            #[cfg(feature = "resource")]
+            key_derivation_config,
+            #[cfg(feature = "resource")]
+            audit_config,
+            #[cfg(feature = "certificate")]
+            certificate_issuer_config,
             #[cfg(feature = "policy")]
             policy_engine_config,
         })
@@ -236,15 +295,64 @@ impl ApiServer {
         let attestation_service = web::Data::new(self.attestation_service.clone());
 
         #[cfg(feature = "as")]
-        let sessions = web::Data::new(SessionMap::new());
+        let sessions = web::Data::new(
+            self.session_config
+                .initialize()
+                .await
+                .context("initialize session store")?,
+        );
+
+        // The session cookies are only sent over HTTPS, unless configured otherwise.
+        #[cfg(feature = "as")]
+        let session_config = {
+            let mut session_config = self.session_config.clone();
+            session_config.cookie.secure.get_or_insert(!self.insecure);
+            web::Data::new(session_config)
+        };
+
+        #[cfg(feature = "as")]
+        tokio::spawn(reap_sessions(
+            sessions.get_ref().clone(),
+            self.session_config.reap_interval,
+        ));
 
         let http_timeout = self.http_timeout;
 
         #[cfg(feature = "resource")]
-        let repository = self.repository_config.initialize()?;
+        let repository = self
+            .repository_config
+            .initialize(self.repository_encryption_config.as_ref())
+            .await?;
+
+        #[cfg(feature = "resource")]
+        let token_verifier = self
+            .attestation_token_type
+            .to_token_verifier(&self.attestation_token_config)?;
+
+        #[cfg(feature = "resource")]
+        let jwe_config = web::Data::new(self.jwe_config.clone());
+
+        #[cfg(feature = "resource")]
+        let key_derivation_config = {
+            self.key_derivation_config.validate()?;
+            web::Data::new(self.key_derivation_config.clone())
+        };
 
         #[cfg(feature = "resource")]
-        let token_verifier = self.attestation_token_type.to_token_verifier()?;
+        let audit_log = web::Data::new(
+            AuditLog::new(self.audit_config.as_ref())
+                .await
+                .context("create audit log")?,
+        );
+
+        #[cfg(feature = "certificate")]
+        let certificate_issuer = self
+            .certificate_issuer_config
+            .as_ref()
+            .map(CertificateIssuer::new)
+            .transpose()
+            .context("create certificate issuer")?
+            .map(web::Data::new);
 
         #[cfg(feature = "policy")]
         let policy_engine = PolicyEngine::new(&self.policy_engine_config).await?;
@@ -266,38 +374,109 @@ impl ApiServer {
 
         let insecure_api = self.insecure_api;
 
+        // Without a dedicated listener, the metrics are served next to the KBS API.
+        let metrics_socket = self
+            .metrics_config
+            .as_ref()
+            .and_then(|config| config.socket);
+        let serve_metrics = self.metrics_config.is_some() && metrics_socket.is_none();
+
         let http_server = HttpServer::new(move || {
             #[allow(unused_mut)]
             let mut server_app = App::new()
                 .wrap(middleware::Logger::default())
                 .app_data(web::Data::new(http_timeout))
                 .app_data(web::Data::new(user_public_key.clone()))
-                .app_data(web::Data::new(insecure_api));
+                .app_data(web::Data::new(insecure_api))
+                .service(web::resource("/health/live").route(web::get().to(http::live)))
+                .service(web::resource("/health/ready").route(web::get().to(http::ready)));
+
+            if serve_metrics {
+                server_app = server_app
+                    .service(web::resource("/metrics").route(web::get().to(http::metrics)));
+            }
 
             cfg_if::cfg_if! {
                 if #[cfg(feature = "as")] {
                     server_app = server_app.app_data(web::Data::clone(&sessions))
+                    .app_data(web::Data::clone(&session_config))
                     .app_data(web::Data::clone(&attestation_service)).service(web::resource(kbs_path!("auth")).route(web::post().to(http::auth)))
                     .service(web::resource(kbs_path!("attest")).route(web::post().to(http::attest)))
                     .service(
                         web::resource(kbs_path!("attestation-policy"))
                             .route(web::post().to(http::attestation_policy)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/session"))
+                            .route(web::get().to(http::list_sessions)),
+                    )
+                    // Before `{id}`, which would match it as well.
+                    .service(
+                        web::resource(kbs_path!("admin/session/revoke"))
+                            .route(web::post().to(http::revoke_sessions)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/session/{id}"))
+                            .route(web::delete().to(http::revoke_session)),
                     );
             }}
             cfg_if::cfg_if! {
                 if #[cfg(feature = "resource")] {
                     server_app = server_app.app_data(web::Data::new(repository.clone()))
                     .app_data(web::Data::new(token_verifier.clone()))
+                    .app_data(web::Data::clone(&jwe_config))
+                    .app_data(web::Data::clone(&key_derivation_config))
+                    .app_data(web::Data::clone(&audit_log))
                     .service(
                         web::resource([
                             kbs_path!("resource/{repository}/{type}/{tag}"),
                             kbs_path!("resource/{type}/{tag}"),
                         ])
                         .route(web::get().to(http::get_resource))
-                        .route(web::post().to(http::set_resource)),
+                        .route(web::post().to(http::set_resource))
+                        .route(web::delete().to(http::delete_resource)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource"))
+                            .route(web::get().to(http::list_repositories)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}"))
+                            .route(web::get().to(http::list_resource_types)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}/{type}"))
+                            .route(web::get().to(http::list_resource_tags)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}/{type}/{tag}"))
+                            .route(web::get().to(http::get_resource_metadata)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}/{type}/{tag}/versions"))
+                            .route(web::get().to(http::list_resource_versions)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}/{type}/{tag}/rollback"))
+                            .route(web::post().to(http::rollback_resource)),
+                    )
+                    .service(
+                        web::resource(kbs_path!("admin/resource/{repository}/{type}/{tag}/limits"))
+                            .route(web::get().to(http::get_resource_limits))
+                            .route(web::post().to(http::set_resource_limits)),
                     );
                 }
             }
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "certificate")] {
+                    if let Some(certificate_issuer) = &certificate_issuer {
+                        server_app = server_app.app_data(web::Data::clone(certificate_issuer))
+                        .service(
+                            web::resource(kbs_path!("certificate")).route(web::post().to(http::issue_certificate)),
+                        );
+                    }
+                }
+            }
             cfg_if::cfg_if! {
                 if #[cfg(feature = "policy")] {
                     server_app = server_app.app_data(web::Data::new(policy_engine.clone()))
@@ -309,24 +488,31 @@ impl ApiServer {
             server_app
         });
 
-        if !self.insecure {
-            let tls_server = {
-                cfg_if::cfg_if! {
-                    if #[cfg(feature = "openssl")] {
-                        http_server.bind_openssl(&self.sockets[..], self.tls_config()?)?
-                    } else {
-                        http_server.bind_rustls(&self.sockets[..], self.tls_config()?)?
-                    }
+        let server = if !self.insecure {
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "openssl")] {
+                    http_server.bind_openssl(&self.sockets[..], self.tls_config()?)?.run()
+                } else {
+                    http_server.bind_rustls(&self.sockets[..], self.tls_config()?)?.run()
                 }
-            };
-
-            tls_server.run().await.map_err(anyhow::Error::from)
+            }
         } else {
-            http_server
-                .bind(&self.sockets[..])?
-                .run()
-                .await
-                .map_err(anyhow::Error::from)
-        }
+            http_server.bind(&self.sockets[..])?.run()
+        };
+
+        let Some(metrics_socket) = metrics_socket else {
+            return server.await.map_err(anyhow::Error::from);
+        };
+
+        log::info!("Starting metrics HTTP server at {metrics_socket}");
+        let metrics_server = HttpServer::new(|| {
+            App::new().service(web::resource("/metrics").route(web::get().to(http::metrics)))
+        })
+        .workers(1)
+        .bind(metrics_socket)?
+        .run();
+
+        tokio::try_join!(server, metrics_server)?;
+        Ok(())
     }
 }
@@ -0,0 +1,150 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prometheus metrics of the KBS.
+
+use anyhow::Result;
+use prometheus::{Encoder, Registry, TextEncoder};
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+use kbs_types::Tee;
+#[cfg(feature = "policy")]
+use prometheus::Histogram;
+#[cfg(any(feature = "as", feature = "policy"))]
+use prometheus::HistogramOpts;
+#[cfg(feature = "resource")]
+use prometheus::IntCounter;
+#[cfg(feature = "as")]
+use prometheus::{HistogramVec, IntGauge};
+#[cfg(any(feature = "as", feature = "resource"))]
+use prometheus::{IntCounterVec, Opts};
+
+/// Outcome label of the successful requests. The failed ones are labelled
+/// with their error type.
+pub(crate) const SUCCESS: &str = "success";
+
+/// TEE type label of the requests from an unknown TEE.
+pub(crate) const UNKNOWN_TEE: &str = "unknown";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MetricsConfig {
+    /// Socket address (IP:port) of a dedicated plain HTTP listener for the
+    /// metrics. When omitted, the metrics are served next to the KBS API.
+    pub socket: Option<SocketAddr>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Registry =
+        Registry::new_custom(Some("kbs".into()), None).expect("create metrics registry failed");
+}
+
+#[cfg(feature = "as")]
+lazy_static! {
+    pub(crate) static ref AUTH_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("auth_requests_total", "Number of auth requests."),
+            &["tee", "outcome"],
+        )
+        .expect("create metric failed")
+    );
+    pub(crate) static ref ATTEST_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("attest_requests_total", "Number of attest requests."),
+            &["tee", "outcome"],
+        )
+        .expect("create metric failed")
+    );
+    pub(crate) static ref ATTEST_VERIFY_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "attest_verify_duration_seconds",
+                "Attestation evidence verification latency."
+            ),
+            &["tee"],
+        )
+        .expect("create metric failed")
+    );
+    pub(crate) static ref SESSIONS: IntGauge = register(
+        IntGauge::new("sessions", "Number of live sessions.").expect("create metric failed")
+    );
+}
+
+#[cfg(feature = "resource")]
+lazy_static! {
+    pub(crate) static ref RESOURCE_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new("resource_requests_total", "Number of resource requests."),
+            &["tee", "outcome"],
+        )
+        .expect("create metric failed")
+    );
+    pub(crate) static ref REPOSITORY_READ_ERRORS: IntCounter = register(
+        IntCounter::new(
+            "repository_read_errors_total",
+            "Number of resource repository read errors."
+        )
+        .expect("create metric failed")
+    );
+}
+
+#[cfg(feature = "policy")]
+lazy_static! {
+    pub(crate) static ref POLICY_EVALUATION_DURATION: Histogram = register(
+        Histogram::with_opts(HistogramOpts::new(
+            "policy_evaluation_duration_seconds",
+            "Resource policy evaluation latency."
+        ))
+        .expect("create metric failed")
+    );
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("register metric failed");
+    metric
+}
+
+/// Label of a TEE type.
+#[cfg_attr(not(feature = "as"), allow(dead_code))]
+pub(crate) fn tee_label(tee: &Tee) -> String {
+    serde_json::to_value(tee)
+        .ok()
+        .and_then(|tee| tee.as_str().map(String::from))
+        .unwrap_or_else(|| format!("{tee:?}").to_lowercase())
+}
+
+/// Encode all the metrics in the Prometheus text format.
+pub(crate) fn encode() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "resource")]
+    #[test]
+    fn encode_metrics() {
+        RESOURCE_REQUESTS
+            .with_label_values(&[UNKNOWN_TEE, "ReadSecretFailed"])
+            .inc();
+        REPOSITORY_READ_ERRORS.inc();
+
+        let metrics = encode().expect("encode metrics failed");
+        assert!(metrics
+            .contains(r#"kbs_resource_requests_total{outcome="ReadSecretFailed",tee="unknown"}"#));
+        assert!(metrics.contains("kbs_repository_read_errors_total"));
+    }
+
+    #[test]
+    fn tee_labels() {
+        assert_eq!(tee_label(&Tee::Sgx), "sgx");
+        assert_eq!(tee_label(&Tee::Snp), "snp");
+    }
+}
@@ -5,6 +5,7 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::Deserialize;
+use serde_json::Value;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -14,6 +15,12 @@ mod opa;
 
 const DEFAULT_POLICY_PATH: &str = "/opa/confidential-containers/kbs/policy.rego";
 
+/// Resource path the policy is evaluated against to check it.
+const HEALTH_CHECK_RESOURCE_PATH: &str = "health/check/policy";
+
+/// Name of the optional policy output field holding the reasons of a deny decision.
+const DENY_REASONS_KEY: &str = "reasons";
+
 /// Resource policy engine interface
 #[async_trait]
 pub(crate) trait PolicyEngineInterface: Send + Sync {
@@ -25,12 +32,20 @@ pub(crate) trait PolicyEngineInterface: Send + Sync {
     /// return value:
     /// ([decide_result, extra_output])
     /// decide_result: Boolean value to present whether the evaluate is passed or not.
-    /// extra_output: original ouput from policy engine.
+    /// extra_output: original ouput from policy engine. It may carry a `reasons`
+    /// string array explaining a deny decision, see [`deny_reasons`].
     async fn evaluate(&self, resource_path: String, input_claims: String)
         -> Result<(bool, String)>;
 
     /// Set policy (Base64 encode)
     async fn set_policy(&mut self, policy: String) -> Result<()>;
+
+    /// Check that the policy can be evaluated, e.g. that it compiles.
+    async fn check(&self) -> Result<()> {
+        self.evaluate(HEALTH_CHECK_RESOURCE_PATH.to_string(), "{}".to_string())
+            .await
+            .map(|_| ())
+    }
 }
 
 /// Policy engine configuration.
@@ -68,3 +83,39 @@ impl PolicyEngine {
         Ok(Self(policy_engine))
     }
 }
+
+/// Extract the deny reasons from the original output of the policy engine.
+///
+/// Policies can explain a deny decision by setting a `reasons` string array,
+/// e.g. `data.policy.reasons` in OPA. Non string entries are ignored, and an
+/// output without reasons gives an empty list.
+pub(crate) fn deny_reasons(extra_output: &str) -> Vec<String> {
+    let Ok(output) = serde_json::from_str::<Value>(extra_output) else {
+        return Vec::new();
+    };
+
+    match &output[DENY_REASONS_KEY] {
+        Value::Array(reasons) => reasons
+            .iter()
+            .filter_map(|r| r.as_str().map(String::from))
+            .collect(),
+        Value::String(reason) => vec![reason.clone()],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::deny_reasons;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(r#"{"allow": false, "reasons": ["svn too low", "wrong product"]}"#, vec!["svn too low", "wrong product"])]
+    #[case(r#"{"allow": false, "reasons": "svn too low"}"#, vec!["svn too low"])]
+    #[case(r#"{"allow": false, "reasons": ["svn too low", 1]}"#, vec!["svn too low"])]
+    #[case(r#"{"allow": false}"#, vec![])]
+    #[case("Error:: not json", vec![])]
+    fn parse_deny_reasons(#[case] output: &str, #[case] expected: Vec<&str>) {
+        assert_eq!(deny_reasons(output), expected);
+    }
+}
@@ -30,6 +30,16 @@
 #	  ……
 # }
 # ```
+#
+# Besides the `allow` decision, a policy can optionally define a `reasons`
+# string array. When access is denied, KBS returns these reasons to the
+# requester in the error detail, for example:
+# ```
+# reasons[msg] {
+#     input["tcb-status"].svn < 2
+#     msg := "svn is lower than 2"
+# }
+# ```
 
 
 
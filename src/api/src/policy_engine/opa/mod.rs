@@ -143,6 +143,39 @@ mod tests {
         assert!(res.unwrap().0 == false, "allow should be false");
     }
 
+    #[tokio::test]
+    async fn test_evaluate_deny_reasons() {
+        let opa = Opa {
+            policy_path: PathBuf::from("../../test/data/policy_3.rego"),
+        };
+
+        let resource_path = "my_repo/Alice/key".to_string();
+
+        let (allow, output) = opa
+            .evaluate(resource_path, dummy_input("Bob", 1))
+            .await
+            .expect("OPA execution() should be success");
+        assert!(!allow, "allow should be false");
+        assert_eq!(
+            crate::policy_engine::deny_reasons(&output),
+            vec!["product Bob cannot access Alice".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let opa = Opa {
+            policy_path: PathBuf::from("../../test/data/policy_1.rego"),
+        };
+        assert!(opa.check().await.is_ok());
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let policy_path = tmp_dir.path().join("policy.rego");
+        std::fs::write(&policy_path, "package policy\nallow {").unwrap();
+        let opa = Opa { policy_path };
+        assert!(opa.check().await.is_err());
+    }
+
     #[tokio::test]
     async fn test_set_policy() {
         let mut opa = Opa::new(PathBuf::from("../../test/data/policy_2.rego")).unwrap();
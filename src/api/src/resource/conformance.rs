@@ -0,0 +1,589 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conformance suite that every `Repository` implementation must pass.
+//!
+//! A backend runs the suite with `repository_conformance_tests!`, given an
+//! expression creating an empty repository, as a `(guard, repository)` tuple.
+//! The guard keeps the repository storage alive for the test duration.
+//! The backends supporting access limits also run the
+//! `resource_access_conformance_tests!` suite.
+
+use super::{
+    Repository, ResourceAccess, ResourceDesc, ResourceLimits, ResourceNotFound, ResourceUnavailable,
+};
+
+const TEST_DATA: &[u8] = b"testdata";
+
+// sha256sum of "testdata"
+const TEST_DATA_SHA256: &str = "810ff2fb242a5dee4220f2cb0e6a519891fb67f2f828a6cab4ef8894633b1f50";
+
+macro_rules! repository_conformance_tests {
+    ($new_repository:expr) => {
+        $crate::resource::conformance::repository_conformance_tests!(
+            @tests $new_repository,
+            replace_resource_version
+        );
+    };
+    // For the backends whose resource versions cannot be modified once written.
+    (immutable_versions: $new_repository:expr) => {
+        $crate::resource::conformance::repository_conformance_tests!(
+            @tests $new_repository,
+            immutable_resource_version
+        );
+    };
+    (@tests $new_repository:expr, $replace_test:ident) => {
+        mod conformance {
+            use super::*;
+
+            #[tokio::test]
+            async fn write_and_read_resource() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::write_and_read_resource(&mut repository).await;
+            }
+
+            #[tokio::test]
+            async fn list_inspect_and_delete_resource() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::list_inspect_and_delete_resource(&mut repository)
+                    .await;
+            }
+
+            #[tokio::test]
+            async fn versions_and_rollback() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::versions_and_rollback(&mut repository).await;
+            }
+
+            #[tokio::test]
+            async fn $replace_test() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::$replace_test(&mut repository).await;
+            }
+
+            #[tokio::test]
+            async fn missing_resource() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::missing_resource(&mut repository).await;
+            }
+        }
+    };
+}
+
+pub(crate) use repository_conformance_tests;
+
+macro_rules! resource_access_conformance_tests {
+    ($new_repository:expr) => {
+        mod access_conformance {
+            use super::*;
+
+            #[tokio::test]
+            async fn read_limits() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::read_limits(&mut repository).await;
+            }
+
+            #[tokio::test]
+            async fn time_limits() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::time_limits(&mut repository).await;
+            }
+
+            #[tokio::test]
+            async fn missing_resource_access() {
+                let (_guard, mut repository) = $new_repository;
+                $crate::resource::conformance::missing_resource_access(&mut repository).await;
+            }
+        }
+    };
+}
+
+pub(crate) use resource_access_conformance_tests;
+
+/// Serve an in-process stand-in of a remote store on a local port, from its
+/// own thread, and return its URL.
+#[cfg(any(feature = "s3", feature = "vault", feature = "kubernetes"))]
+pub(crate) fn serve<F>(configure: F) -> String
+where
+    F: Fn(&mut actix_web::web::ServiceConfig) + Clone + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    std::thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            actix_web::HttpServer::new(move || actix_web::App::new().configure(configure.clone()))
+                .workers(1)
+                .listen(listener)
+                .unwrap()
+                .run()
+                .await
+        })
+    });
+
+    format!("http://127.0.0.1:{port}")
+}
+
+fn resource_desc(repository: &str, tag: &str) -> ResourceDesc {
+    ResourceDesc {
+        repository_name: repository.into(),
+        resource_type: "test".into(),
+        resource_tag: tag.into(),
+        version: None,
+    }
+}
+
+fn version(resource_desc: &ResourceDesc, version: u64) -> ResourceDesc {
+    ResourceDesc {
+        version: Some(version),
+        ..resource_desc.clone()
+    }
+}
+
+pub(crate) async fn write_and_read_resource(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "test");
+
+    repository
+        .write_secret_resource(resource_desc.clone(), TEST_DATA)
+        .await
+        .expect("write secret resource failed");
+    let data = repository
+        .read_secret_resource(resource_desc.clone())
+        .await
+        .expect("read secret resource failed");
+    assert_eq!(&data[..], TEST_DATA);
+
+    let data = repository
+        .release_secret_resource(resource_desc)
+        .await
+        .expect("release secret resource failed");
+    assert_eq!(&data[..], TEST_DATA);
+}
+
+pub(crate) async fn list_inspect_and_delete_resource(
+    repository: &mut (dyn Repository + Send + Sync),
+) {
+    for (repository_name, tag) in [("default", "b"), ("default", "a"), ("other", "c")] {
+        repository
+            .write_secret_resource(resource_desc(repository_name, tag), TEST_DATA)
+            .await
+            .expect("write secret resource failed");
+    }
+
+    assert_eq!(
+        repository.list_repositories().await.unwrap(),
+        vec!["default", "other"]
+    );
+    assert_eq!(
+        repository.list_resource_types("default").await.unwrap(),
+        vec!["test"]
+    );
+    assert_eq!(
+        repository
+            .list_resource_tags("default", "test")
+            .await
+            .unwrap(),
+        vec!["a", "b"]
+    );
+    assert_eq!(
+        not_found(repository.list_resource_types("missing").await),
+        ResourceNotFound::Repository("missing".to_string())
+    );
+
+    let resource_desc = resource_desc("default", "a");
+    let metadata = repository
+        .get_resource_metadata(resource_desc.clone())
+        .await
+        .expect("get resource metadata failed");
+    assert_eq!(metadata.version, 1);
+    assert_eq!(metadata.size, TEST_DATA.len() as u64);
+    assert_eq!(metadata.sha256, TEST_DATA_SHA256);
+
+    repository
+        .delete_secret_resource(resource_desc.clone())
+        .await
+        .expect("delete secret resource failed");
+    assert!(repository
+        .read_secret_resource(resource_desc.clone())
+        .await
+        .is_err());
+    assert!(repository
+        .delete_secret_resource(resource_desc)
+        .await
+        .is_err());
+    assert_eq!(
+        repository
+            .list_resource_tags("default", "test")
+            .await
+            .unwrap(),
+        vec!["b"]
+    );
+}
+
+pub(crate) async fn versions_and_rollback(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "test");
+
+    for data in [b"v1", b"v2", b"v3"] {
+        repository
+            .write_secret_resource(resource_desc.clone(), data)
+            .await
+            .expect("write secret resource failed");
+    }
+
+    assert_eq!(
+        repository
+            .read_secret_resource(resource_desc.clone())
+            .await
+            .unwrap(),
+        b"v3"
+    );
+    assert_eq!(
+        repository
+            .read_secret_resource(version(&resource_desc, 1))
+            .await
+            .unwrap(),
+        b"v1"
+    );
+    assert_eq!(
+        repository
+            .read_secret_resource(version(&resource_desc, 2))
+            .await
+            .unwrap(),
+        b"v2"
+    );
+    assert!(repository
+        .read_secret_resource(version(&resource_desc, 4))
+        .await
+        .is_err());
+
+    let versions = repository
+        .list_resource_versions(resource_desc.clone())
+        .await
+        .expect("list resource versions failed");
+    assert_eq!(
+        versions.iter().map(|m| m.version).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+
+    assert_eq!(
+        repository
+            .rollback_secret_resource(resource_desc.clone(), 1)
+            .await
+            .expect("rollback secret resource failed"),
+        4
+    );
+    assert_eq!(
+        repository
+            .read_secret_resource(resource_desc.clone())
+            .await
+            .unwrap(),
+        b"v1"
+    );
+    let metadata = repository
+        .get_resource_metadata(resource_desc.clone())
+        .await
+        .unwrap();
+    assert_eq!(metadata.version, 4);
+    assert_eq!(metadata.sha256, versions[0].sha256);
+
+    // Only the resource tags are listed, not their history.
+    assert_eq!(
+        repository
+            .list_resource_tags("default", "test")
+            .await
+            .unwrap(),
+        vec!["test"]
+    );
+
+    repository
+        .delete_secret_resource(resource_desc.clone())
+        .await
+        .expect("delete secret resource failed");
+    assert!(repository
+        .list_resource_versions(resource_desc.clone())
+        .await
+        .is_err());
+    assert!(repository
+        .read_secret_resource(version(&resource_desc, 1))
+        .await
+        .is_err());
+}
+
+pub(crate) async fn replace_resource_version(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "test");
+
+    for data in [b"v1", b"v2"] {
+        repository
+            .write_secret_resource(resource_desc.clone(), data)
+            .await
+            .expect("write secret resource failed");
+    }
+
+    repository
+        .replace_secret_resource_version(version(&resource_desc, 1), b"v1'")
+        .await
+        .expect("replace secret resource version failed");
+    assert_eq!(
+        repository
+            .read_secret_resource(version(&resource_desc, 1))
+            .await
+            .unwrap(),
+        b"v1'"
+    );
+    assert_eq!(
+        repository
+            .read_secret_resource(resource_desc.clone())
+            .await
+            .unwrap(),
+        b"v2"
+    );
+
+    repository
+        .replace_secret_resource_version(version(&resource_desc, 2), b"v2'")
+        .await
+        .expect("replace secret resource version failed");
+    assert_eq!(
+        repository
+            .read_secret_resource(resource_desc.clone())
+            .await
+            .unwrap(),
+        b"v2'"
+    );
+    assert_eq!(
+        repository
+            .list_resource_versions(resource_desc.clone())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    assert!(repository
+        .replace_secret_resource_version(version(&resource_desc, 3), b"v3")
+        .await
+        .is_err());
+    assert!(repository
+        .replace_secret_resource_version(resource_desc, b"v3")
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "vault")]
+pub(crate) async fn immutable_resource_version(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "test");
+
+    repository
+        .write_secret_resource(resource_desc.clone(), b"v1")
+        .await
+        .expect("write secret resource failed");
+
+    assert!(repository
+        .replace_secret_resource_version(version(&resource_desc, 1), b"v1'")
+        .await
+        .is_err());
+    assert_eq!(
+        repository
+            .read_secret_resource(version(&resource_desc, 1))
+            .await
+            .unwrap(),
+        b"v1"
+    );
+    assert_eq!(
+        repository
+            .list_resource_versions(resource_desc)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+pub(crate) async fn missing_resource(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "missing");
+
+    assert!(repository
+        .read_secret_resource(resource_desc.clone())
+        .await
+        .is_err());
+    assert!(repository
+        .get_resource_metadata(resource_desc.clone())
+        .await
+        .is_err());
+    assert_eq!(
+        not_found(
+            repository
+                .list_resource_versions(resource_desc.clone())
+                .await
+        ),
+        ResourceNotFound::Resource
+    );
+    assert!(repository
+        .delete_secret_resource(resource_desc)
+        .await
+        .is_err());
+    assert_eq!(
+        not_found(repository.list_resource_tags("default", "missing").await),
+        ResourceNotFound::ResourceType("default/missing".to_string())
+    );
+}
+
+fn not_found<T: std::fmt::Debug>(result: anyhow::Result<T>) -> ResourceNotFound {
+    let error = result.expect_err("missing resource listed");
+    error
+        .downcast::<ResourceNotFound>()
+        .expect("listing failed for another reason")
+}
+
+fn unavailable(result: anyhow::Result<Vec<u8>>) -> ResourceUnavailable {
+    let error = result.expect_err("resource released");
+    error
+        .downcast::<ResourceUnavailable>()
+        .expect("resource released or read failed")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub(crate) async fn read_limits(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "once");
+    repository
+        .write_secret_resource(resource_desc.clone(), TEST_DATA)
+        .await
+        .expect("write secret resource failed");
+
+    // The reads are only counted when limited.
+    repository
+        .release_secret_resource(resource_desc.clone())
+        .await
+        .expect("release secret resource failed");
+    assert_eq!(
+        repository
+            .get_resource_access(resource_desc.clone())
+            .await
+            .unwrap(),
+        ResourceAccess {
+            limits: ResourceLimits::default(),
+            reads: 0,
+        }
+    );
+
+    // Setting the limits resets the read count.
+    let limits = ResourceLimits {
+        max_reads: Some(1),
+        ..Default::default()
+    };
+    repository
+        .set_resource_limits(resource_desc.clone(), limits.clone())
+        .await
+        .expect("set resource limits failed");
+    assert_eq!(
+        repository
+            .release_secret_resource(resource_desc.clone())
+            .await
+            .unwrap(),
+        TEST_DATA
+    );
+    assert_eq!(
+        unavailable(
+            repository
+                .release_secret_resource(resource_desc.clone())
+                .await
+        ),
+        ResourceUnavailable::Exhausted(1)
+    );
+    assert_eq!(
+        repository
+            .get_resource_access(resource_desc.clone())
+            .await
+            .unwrap(),
+        ResourceAccess { limits, reads: 1 }
+    );
+
+    // The admin reads are not limited.
+    assert!(repository
+        .read_secret_resource(resource_desc.clone())
+        .await
+        .is_ok());
+
+    // Deleting the resource deletes its limits.
+    repository
+        .delete_secret_resource(resource_desc.clone())
+        .await
+        .unwrap();
+    repository
+        .write_secret_resource(resource_desc.clone(), TEST_DATA)
+        .await
+        .unwrap();
+    assert_eq!(
+        repository.get_resource_access(resource_desc).await.unwrap(),
+        ResourceAccess::default()
+    );
+}
+
+pub(crate) async fn time_limits(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "window");
+    repository
+        .write_secret_resource(resource_desc.clone(), TEST_DATA)
+        .await
+        .expect("write secret resource failed");
+
+    let now = now();
+    for (limits, expected) in [
+        (
+            ResourceLimits {
+                not_before: Some(now + 3600),
+                ..Default::default()
+            },
+            Some(ResourceUnavailable::NotYetValid(now + 3600)),
+        ),
+        (
+            ResourceLimits {
+                not_after: Some(now - 3600),
+                ..Default::default()
+            },
+            Some(ResourceUnavailable::Expired(now - 3600)),
+        ),
+        (
+            ResourceLimits {
+                not_before: Some(now - 3600),
+                not_after: Some(now + 3600),
+                ..Default::default()
+            },
+            None,
+        ),
+    ] {
+        repository
+            .set_resource_limits(resource_desc.clone(), limits)
+            .await
+            .expect("set resource limits failed");
+        let released = repository
+            .release_secret_resource(resource_desc.clone())
+            .await;
+        match expected {
+            Some(expected) => assert_eq!(unavailable(released), expected),
+            None => assert_eq!(released.unwrap(), TEST_DATA),
+        }
+    }
+}
+
+pub(crate) async fn missing_resource_access(repository: &mut (dyn Repository + Send + Sync)) {
+    let resource_desc = resource_desc("default", "missing");
+
+    assert!(repository
+        .release_secret_resource(resource_desc.clone())
+        .await
+        .is_err());
+    assert!(repository
+        .get_resource_access(resource_desc.clone())
+        .await
+        .is_err());
+    assert!(repository
+        .set_resource_limits(resource_desc, ResourceLimits::default())
+        .await
+        .is_err());
+}
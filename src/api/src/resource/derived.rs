@@ -0,0 +1,334 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{is_valid_path_component, Repository, ResourceDesc};
+use anyhow::{anyhow, bail, Context, Result};
+use hkdf::Hkdf;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+const DEFAULT_KEY_LENGTH: usize = 32;
+
+/// Largest HKDF-SHA256 output length.
+const MAX_KEY_LENGTH: usize = 255 * 32;
+
+/// Keys derived from a master secret and the attestation claims of the
+/// requester, instead of being read from the repository.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KeyDerivationConfig {
+    #[serde(default)]
+    pub keys: Vec<DerivedKeyConfig>,
+}
+
+/// Derivation of the keys with a `<repository>/<type>/<tag>` path starting
+/// with `prefix`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DerivedKeyConfig {
+    pub prefix: String,
+
+    /// `<repository>/<type>/<tag>` path of the resource holding the master
+    /// secret.
+    pub master_secret: String,
+
+    /// JSON pointers to the claims the keys are bound to, e.g.
+    /// `/tcb-status/sgx.mr_enclave`. At least one claim is required, or all
+    /// the requesters would get the same key.
+    pub claims: Vec<String>,
+
+    /// Key length, in bytes.
+    #[serde(default = "default_key_length")]
+    pub length: usize,
+}
+
+fn default_key_length() -> usize {
+    DEFAULT_KEY_LENGTH
+}
+
+impl KeyDerivationConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        for key in &self.keys {
+            key.validate()
+                .with_context(|| format!("invalid derived key {}", key.prefix))?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the derived key configuration with the longest prefix matching
+    /// the resource path.
+    pub(crate) fn key(&self, resource_desc: &ResourceDesc) -> Option<&DerivedKeyConfig> {
+        let path = resource_path(resource_desc);
+
+        self.keys
+            .iter()
+            .filter(|key| path.starts_with(&key.prefix))
+            .max_by_key(|key| key.prefix.len())
+    }
+
+    /// Whether the resource holds the master secret of a derived key, which
+    /// must not be released to the requesters.
+    pub(crate) fn is_master_secret(&self, resource_desc: &ResourceDesc) -> bool {
+        let path = resource_path(resource_desc);
+
+        self.keys.iter().any(|key| key.master_secret == path)
+    }
+}
+
+impl DerivedKeyConfig {
+    fn validate(&self) -> Result<()> {
+        master_secret_desc(&self.master_secret, None)?;
+        if self.length == 0 || self.length > MAX_KEY_LENGTH {
+            bail!(
+                "invalid key length {}, expected 1 to {MAX_KEY_LENGTH} bytes",
+                self.length
+            );
+        }
+        if self.claims.is_empty() {
+            bail!("no claims, the key would be the same for all the requesters");
+        }
+        if let Some(claim) = self.claims.iter().find(|claim| !claim.starts_with('/')) {
+            bail!("claim {claim} is not a JSON pointer");
+        }
+
+        Ok(())
+    }
+
+    /// Derive the key of `resource_desc` for a requester with the given
+    /// attestation claims. The version of `resource_desc` selects the version
+    /// of the master secret.
+    ///
+    /// The key is HKDF-SHA256 of the master secret, with the JSON array of the
+    /// resource path followed by the selected claims as info.
+    pub(crate) async fn derive(
+        &self,
+        repository: &(dyn Repository + Send + Sync),
+        resource_desc: &ResourceDesc,
+        claims: &Value,
+    ) -> Result<Vec<u8>> {
+        let mut info = vec![Value::String(resource_path(resource_desc))];
+        for pointer in &self.claims {
+            match claims.pointer(pointer) {
+                Some(claim @ (Value::String(_) | Value::Number(_) | Value::Bool(_))) => {
+                    info.push(claim.clone())
+                }
+                _ => bail!("no string claim {pointer} in the attestation claims"),
+            }
+        }
+        let info = serde_json::to_vec(&info)?;
+
+        let master_secret = repository
+            .read_secret_resource(master_secret_desc(
+                &self.master_secret,
+                resource_desc.version,
+            )?)
+            .await
+            .context("read master secret")?;
+
+        let mut key = vec![0; self.length];
+        Hkdf::<Sha256>::new(None, &master_secret)
+            .expand(&info, &mut key)
+            .map_err(|e| anyhow!("derive key: {e}"))?;
+
+        Ok(key)
+    }
+}
+
+fn resource_path(resource_desc: &ResourceDesc) -> String {
+    format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    )
+}
+
+fn master_secret_desc(path: &str, version: Option<u64>) -> Result<ResourceDesc> {
+    let components: Vec<&str> = path.split('/').collect();
+    let [repository_name, resource_type, resource_tag] = components[..] else {
+        bail!("master secret path {path} is not a <repository>/<type>/<tag> path");
+    };
+    if ![repository_name, resource_type, resource_tag]
+        .into_iter()
+        .all(is_valid_path_component)
+    {
+        bail!("invalid master secret path {path}");
+    }
+
+    Ok(ResourceDesc {
+        repository_name: repository_name.into(),
+        resource_type: resource_type.into(),
+        resource_tag: resource_tag.into(),
+        version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::local_fs::{LocalFs, LocalFsRepoDesc};
+    use rstest::rstest;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn repository() -> (TempDir, LocalFs) {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+        };
+        let mut repository = LocalFs::new(&repo_desc).expect("create local fs failed");
+
+        let master_secret = master_secret_desc("default/master/disk", None).unwrap();
+        for secret in [b"master secret v1", b"master secret v2"] {
+            repository
+                .write_secret_resource(master_secret.clone(), secret)
+                .await
+                .expect("write secret resource failed");
+        }
+
+        (tmp_dir, repository)
+    }
+
+    fn config() -> KeyDerivationConfig {
+        KeyDerivationConfig {
+            keys: vec![DerivedKeyConfig {
+                prefix: "default/disk-key/".into(),
+                master_secret: "default/master/disk".into(),
+                claims: vec![
+                    "/tcb-status/sgx.mr_enclave".into(),
+                    "/tcb-status/svn".into(),
+                ],
+                length: 32,
+            }],
+        }
+    }
+
+    fn claims(mr_enclave: &str) -> Value {
+        json!({
+            "tee-pubkey": {},
+            "tcb-status": {
+                "sgx.mr_enclave": mr_enclave,
+                "svn": 3,
+            },
+        })
+    }
+
+    fn resource_desc(resource_type: &str, resource_tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: resource_type.into(),
+            resource_tag: resource_tag.into(),
+            version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn derive_key() {
+        let (_tmp_dir, repository) = repository().await;
+        let config = config();
+        let resource_desc = resource_desc("disk-key", "root");
+        let key = config.key(&resource_desc).expect("no derived key");
+
+        let derived = key
+            .derive(&repository, &resource_desc, &claims("8f173e46"))
+            .await
+            .expect("derive key failed");
+        let mut expected = vec![0; 32];
+        Hkdf::<Sha256>::new(None, b"master secret v2")
+            .expand(br#"["default/disk-key/root","8f173e46",3]"#, &mut expected)
+            .unwrap();
+        assert_eq!(derived, expected);
+
+        // The same workload always gets the same key.
+        assert_eq!(
+            key.derive(&repository, &resource_desc, &claims("8f173e46"))
+                .await
+                .unwrap(),
+            derived
+        );
+
+        // Another workload, or another resource, gets another key.
+        assert_ne!(
+            key.derive(&repository, &resource_desc, &claims("3d7a2c10"))
+                .await
+                .unwrap(),
+            derived
+        );
+        assert_ne!(
+            key.derive(
+                &repository,
+                &ResourceDesc {
+                    resource_tag: "data".into(),
+                    ..resource_desc.clone()
+                },
+                &claims("8f173e46")
+            )
+            .await
+            .unwrap(),
+            derived
+        );
+
+        // The resource version selects the master secret version.
+        assert_ne!(
+            key.derive(
+                &repository,
+                &ResourceDesc {
+                    version: Some(1),
+                    ..resource_desc.clone()
+                },
+                &claims("8f173e46")
+            )
+            .await
+            .unwrap(),
+            derived
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_claim() {
+        let (_tmp_dir, repository) = repository().await;
+        let config = config();
+        let resource_desc = resource_desc("disk-key", "root");
+
+        assert!(config
+            .key(&resource_desc)
+            .unwrap()
+            .derive(&repository, &resource_desc, &json!({"tee-pubkey": {}}))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn derived_key_paths() {
+        let config = config();
+
+        assert!(config.key(&resource_desc("disk-key", "root")).is_some());
+        assert!(config.key(&resource_desc("master", "disk")).is_none());
+
+        assert!(config.is_master_secret(&resource_desc("master", "disk")));
+        assert!(config.is_master_secret(&ResourceDesc {
+            version: Some(1),
+            ..resource_desc("master", "disk")
+        }));
+        assert!(!config.is_master_secret(&resource_desc("master", "swap")));
+        assert!(!config.is_master_secret(&resource_desc("disk-key", "root")));
+    }
+
+    #[rstest]
+    #[case::master_secret_path("default/master", 32, &["/svn"])]
+    #[case::master_secret_component("default/../disk", 32, &["/svn"])]
+    #[case::length("default/master/disk", 0, &["/svn"])]
+    #[case::claim("default/master/disk", 32, &["svn"])]
+    #[case::no_claims("default/master/disk", 32, &[])]
+    fn invalid_config(#[case] master_secret: &str, #[case] length: usize, #[case] claims: &[&str]) {
+        let config = KeyDerivationConfig {
+            keys: vec![DerivedKeyConfig {
+                prefix: "default/disk-key/".into(),
+                master_secret: master_secret.into(),
+                claims: claims.iter().map(|claim| claim.to_string()).collect(),
+                length,
+            }],
+        };
+
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,827 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Envelope encryption of the resources at rest.
+//!
+//! Every resource is encrypted with its own data encryption key (DEK), and the
+//! DEK is wrapped with a key encryption key (KEK). The repository only stores
+//! the resulting envelope.
+
+use super::{
+    Repository, ResourceAccess, ResourceDesc, ResourceLimits, ResourceMetadata, ResourceNotFound,
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use aes_kw::KekAes256;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const DEK_SIZE: usize = 32;
+const KEK_SIZE: usize = 32;
+const IV_SIZE: usize = 12;
+
+/// Resource repository encryption configuration.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RepositoryEncryptionConfig {
+    /// KEK wrapping the DEKs of the written resources.
+    pub kek: KekConfig,
+
+    /// Former KEKs, only used to unwrap the DEKs of the resources written
+    /// before the last KEK rotations.
+    #[serde(default)]
+    pub previous_keks: Vec<KekConfig>,
+
+    /// Re-wrap, at start, the DEKs wrapped with a former KEK with the current
+    /// one, bind the former envelopes to their version, and encrypt the
+    /// resources stored in plaintext.
+    #[serde(default)]
+    pub rewrap: bool,
+}
+
+/// Key encryption key configuration.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum KekConfig {
+    /// 256 bits AES key, read from a file.
+    KeyFile {
+        /// KEK identifier, recorded in the envelopes.
+        id: String,
+        /// Path to the raw 32 bytes key.
+        path: PathBuf,
+    },
+
+    /// AES key held by a PKCS#11 token.
+    #[cfg(feature = "pkcs11")]
+    Pkcs11 {
+        /// KEK identifier, recorded in the envelopes.
+        id: String,
+        /// Path to the PKCS#11 module.
+        module: PathBuf,
+        /// Label of the token holding the key.
+        token_label: String,
+        /// User PIN of the token.
+        pin: String,
+        /// Label of the AES key object.
+        key_label: String,
+    },
+}
+
+impl KekConfig {
+    fn id(&self) -> &str {
+        match self {
+            KekConfig::KeyFile { id, .. } => id,
+            #[cfg(feature = "pkcs11")]
+            KekConfig::Pkcs11 { id, .. } => id,
+        }
+    }
+
+    fn to_kek(&self) -> Result<Box<dyn KeyEncryptionKey>> {
+        match self {
+            KekConfig::KeyFile { path, .. } => {
+                let key: [u8; KEK_SIZE] = std::fs::read(path)
+                    .with_context(|| format!("read KEK file {}", path.display()))?
+                    .try_into()
+                    .map_err(|_| anyhow!("KEK file must hold a {KEK_SIZE} bytes key"))?;
+                Ok(Box::new(KekAes256::from(key)))
+            }
+            #[cfg(feature = "pkcs11")]
+            KekConfig::Pkcs11 {
+                module,
+                token_label,
+                pin,
+                key_label,
+                ..
+            } => Ok(Box::new(pkcs11::Pkcs11Kek::new(
+                module,
+                token_label,
+                pin,
+                key_label,
+            )?)),
+        }
+    }
+}
+
+/// Key encryption key, wrapping the DEKs.
+trait KeyEncryptionKey: Send + Sync {
+    fn wrap(&self, dek: &[u8]) -> Result<Vec<u8>>;
+
+    fn unwrap(&self, wrapped_dek: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl KeyEncryptionKey for KekAes256 {
+    fn wrap(&self, dek: &[u8]) -> Result<Vec<u8>> {
+        self.wrap_vec(dek)
+            .map_err(|e| anyhow!("AES key wrap failed: {e}"))
+    }
+
+    fn unwrap(&self, wrapped_dek: &[u8]) -> Result<Vec<u8>> {
+        self.unwrap_vec(wrapped_dek)
+            .map_err(|e| anyhow!("AES key unwrap failed: {e}"))
+    }
+}
+
+/// Encrypted resource, as stored in the wrapped repository.
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    /// Identifier of the KEK wrapping the DEK.
+    kek_id: String,
+    wrapped_dek: String,
+    iv: String,
+    ciphertext: String,
+    /// Whether the AAD binds the resource version. Envelopes written before
+    /// it did only bind the resource path, until they are re-wrapped.
+    #[serde(default)]
+    versioned: bool,
+}
+
+/// `Repository` wrapper, encrypting the resources of the wrapped repository.
+pub(crate) struct EncryptedRepository {
+    inner: Box<dyn Repository + Send + Sync>,
+    kek_id: String,
+    keks: HashMap<String, Box<dyn KeyEncryptionKey>>,
+}
+
+impl EncryptedRepository {
+    pub(crate) fn new(
+        inner: Box<dyn Repository + Send + Sync>,
+        config: &RepositoryEncryptionConfig,
+    ) -> Result<Self> {
+        let mut keks = HashMap::new();
+        for kek_config in std::iter::once(&config.kek).chain(&config.previous_keks) {
+            if keks
+                .insert(kek_config.id().to_string(), kek_config.to_kek()?)
+                .is_some()
+            {
+                bail!("duplicated KEK id {}", kek_config.id());
+            }
+        }
+
+        Ok(Self {
+            inner,
+            kek_id: config.kek.id().to_string(),
+            keks,
+        })
+    }
+
+    fn kek(&self, kek_id: &str) -> Result<&dyn KeyEncryptionKey> {
+        self.keks
+            .get(kek_id)
+            .map(|kek| kek.as_ref())
+            .ok_or_else(|| anyhow!("unknown KEK {kek_id}"))
+    }
+
+    fn seal(&self, resource_desc: &ResourceDesc, version: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let mut rng = rand::thread_rng();
+        let dek = rng.gen::<[u8; DEK_SIZE]>();
+        let iv = rng.gen::<[u8; IV_SIZE]>();
+        let ciphertext = Aes256Gcm::new_from_slice(&dek)
+            .map_err(|e| anyhow!("Illegal DEK: {e}"))?
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: data,
+                    aad: aad(resource_desc, Some(version)).as_bytes(),
+                },
+            )
+            .map_err(|e| anyhow!("encrypt resource failed: {e}"))?;
+
+        let envelope = Envelope {
+            kek_id: self.kek_id.clone(),
+            wrapped_dek: STANDARD.encode(self.kek(&self.kek_id)?.wrap(&dek)?),
+            iv: STANDARD.encode(iv),
+            ciphertext: STANDARD.encode(ciphertext),
+            versioned: true,
+        };
+
+        serde_json::to_vec(&envelope).context("serialize resource envelope")
+    }
+
+    fn open(&self, resource_desc: &ResourceDesc, version: u64, envelope: &[u8]) -> Result<Vec<u8>> {
+        let envelope: Envelope =
+            serde_json::from_slice(envelope).context("illegal resource envelope")?;
+        let dek = self
+            .kek(&envelope.kek_id)?
+            .unwrap(&STANDARD.decode(&envelope.wrapped_dek)?)?;
+        let iv = STANDARD.decode(&envelope.iv)?;
+        if iv.len() != IV_SIZE {
+            bail!("illegal resource envelope IV");
+        }
+
+        Aes256Gcm::new_from_slice(&dek)
+            .map_err(|e| anyhow!("Illegal DEK: {e}"))?
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: &STANDARD.decode(&envelope.ciphertext)?,
+                    aad: aad(resource_desc, envelope.versioned.then_some(version)).as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("decrypt resource failed"))
+    }
+
+    /// Version selected by `resource_desc`, the latest one if none is.
+    async fn version(&self, resource_desc: &ResourceDesc) -> Result<u64> {
+        match resource_desc.version {
+            Some(version) => Ok(version),
+            None => Ok(self
+                .inner
+                .get_resource_metadata(resource_desc.clone())
+                .await?
+                .version),
+        }
+    }
+
+    /// Describe the plaintext content, rather than its envelope.
+    async fn plaintext_metadata(
+        &self,
+        resource_desc: ResourceDesc,
+        mut metadata: ResourceMetadata,
+    ) -> Result<ResourceMetadata> {
+        let resource_desc = ResourceDesc {
+            version: Some(metadata.version),
+            ..resource_desc
+        };
+        let data = self.read_secret_resource(resource_desc).await?;
+        metadata.size = data.len() as u64;
+        metadata.sha256 = ResourceMetadata::content_digest(&data);
+        Ok(metadata)
+    }
+
+    /// Re-wrap the DEKs wrapped with a former KEK, re-encrypt the envelopes
+    /// not bound to their version, and encrypt the plaintext resources, of all
+    /// the resource versions. Returns the number of updated
+    /// resource versions.
+    pub(crate) async fn rewrap(&mut self) -> Result<usize> {
+        let mut updated = 0;
+        for repository_name in self.inner.list_repositories().await? {
+            for resource_type in self.inner.list_resource_types(&repository_name).await? {
+                for resource_tag in self
+                    .inner
+                    .list_resource_tags(&repository_name, &resource_type)
+                    .await?
+                {
+                    let resource_desc = ResourceDesc {
+                        repository_name: repository_name.clone(),
+                        resource_type: resource_type.clone(),
+                        resource_tag,
+                        version: None,
+                    };
+                    for metadata in self
+                        .inner
+                        .list_resource_versions(resource_desc.clone())
+                        .await?
+                    {
+                        let resource_desc = ResourceDesc {
+                            version: Some(metadata.version),
+                            ..resource_desc.clone()
+                        };
+                        if self
+                            .rewrap_resource(resource_desc, metadata.version)
+                            .await?
+                        {
+                            updated += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(updated)
+    }
+
+    async fn rewrap_resource(&mut self, resource_desc: ResourceDesc, version: u64) -> Result<bool> {
+        let data = self
+            .inner
+            .read_secret_resource(resource_desc.clone())
+            .await?;
+
+        let sealed = match serde_json::from_slice::<Envelope>(&data) {
+            Ok(envelope) if envelope.kek_id == self.kek_id && envelope.versioned => {
+                return Ok(false)
+            }
+            Ok(mut envelope) if envelope.versioned => {
+                let dek = self
+                    .kek(&envelope.kek_id)?
+                    .unwrap(&STANDARD.decode(&envelope.wrapped_dek)?)
+                    .with_context(|| format!("unwrap DEK of {}", aad(&resource_desc, None)))?;
+                envelope.kek_id = self.kek_id.clone();
+                envelope.wrapped_dek = STANDARD.encode(self.kek(&self.kek_id)?.wrap(&dek)?);
+                serde_json::to_vec(&envelope).context("serialize resource envelope")?
+            }
+            Ok(_) => {
+                let plaintext = self
+                    .open(&resource_desc, version, &data)
+                    .with_context(|| format!("open {}", aad(&resource_desc, None)))?;
+                self.seal(&resource_desc, version, &plaintext)?
+            }
+            Err(_) => self.seal(&resource_desc, version, &data)?,
+        };
+
+        self.inner
+            .replace_secret_resource_version(resource_desc, &sealed)
+            .await?;
+        Ok(true)
+    }
+}
+
+/// The envelopes are bound to the resource path and version, so that they
+/// cannot be swapped between resources, nor between versions of a resource,
+/// e.g. to serve a revoked secret as the latest version.
+fn aad(resource_desc: &ResourceDesc, version: Option<u64>) -> String {
+    let path = format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    );
+    match version {
+        Some(version) => format!("{path}@{version}"),
+        None => path,
+    }
+}
+
+#[async_trait::async_trait]
+impl Repository for EncryptedRepository {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        let version = self.version(&resource_desc).await?;
+        let resource_desc = ResourceDesc {
+            version: Some(version),
+            ..resource_desc
+        };
+        let envelope = self
+            .inner
+            .read_secret_resource(resource_desc.clone())
+            .await?;
+        self.open(&resource_desc, version, &envelope)
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let latest = ResourceDesc {
+            version: None,
+            ..resource_desc.clone()
+        };
+        let version = match self.inner.get_resource_metadata(latest).await {
+            Ok(metadata) => metadata.version + 1,
+            Err(e) if e.is::<ResourceNotFound>() => 1,
+            Err(e) => return Err(e),
+        };
+        let envelope = self.seal(&resource_desc, version, data)?;
+        self.inner
+            .write_secret_resource(resource_desc.clone(), &envelope)
+            .await?;
+
+        // Another KBS sharing the repository may have written a version in
+        // between: bind the envelope to the version it was actually written as.
+        let digest = ResourceMetadata::content_digest(&envelope);
+        let written = ResourceDesc {
+            version: Some(version),
+            ..resource_desc.clone()
+        };
+        let metadata = self.inner.get_resource_metadata(written).await;
+        if matches!(metadata, Ok(metadata) if metadata.sha256 == digest) {
+            return Ok(());
+        }
+
+        let written = self
+            .inner
+            .list_resource_versions(resource_desc.clone())
+            .await?
+            .into_iter()
+            .rev()
+            .find(|metadata| metadata.sha256 == digest)
+            .context("written resource version not found")?
+            .version;
+        let resource_desc = ResourceDesc {
+            version: Some(written),
+            ..resource_desc
+        };
+        let envelope = self.seal(&resource_desc, written, data)?;
+        self.inner
+            .replace_secret_resource_version(resource_desc, &envelope)
+            .await
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let version = self.version(&resource_desc).await?;
+        let envelope = self.seal(&resource_desc, version, data)?;
+        self.inner
+            .replace_secret_resource_version(resource_desc, &envelope)
+            .await
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        self.inner.delete_secret_resource(resource_desc).await
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        let metadata = self
+            .inner
+            .get_resource_metadata(resource_desc.clone())
+            .await?;
+        self.plaintext_metadata(resource_desc, metadata).await
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        let mut versions = Vec::new();
+        for metadata in self
+            .inner
+            .list_resource_versions(resource_desc.clone())
+            .await?
+        {
+            versions.push(
+                self.plaintext_metadata(resource_desc.clone(), metadata)
+                    .await?,
+            );
+        }
+
+        Ok(versions)
+    }
+
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        let version = self.version(&resource_desc).await?;
+        let resource_desc = ResourceDesc {
+            version: Some(version),
+            ..resource_desc
+        };
+        let envelope = self
+            .inner
+            .release_secret_resource(resource_desc.clone())
+            .await?;
+        self.open(&resource_desc, version, &envelope)
+    }
+
+    async fn get_resource_access(&self, resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        self.inner.get_resource_access(resource_desc).await
+    }
+
+    async fn set_resource_limits(
+        &mut self,
+        resource_desc: ResourceDesc,
+        limits: ResourceLimits,
+    ) -> Result<()> {
+        self.inner.set_resource_limits(resource_desc, limits).await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.inner.list_repositories().await
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        self.inner.list_resource_types(repository_name).await
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        self.inner
+            .list_resource_tags(repository_name, resource_type)
+            .await
+    }
+}
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11 {
+    use super::KeyEncryptionKey;
+    use anyhow::{anyhow, Context, Result};
+    use cryptoki::context::{CInitializeArgs, Pkcs11};
+    use cryptoki::mechanism::Mechanism;
+    use cryptoki::object::{Attribute, ObjectClass, ObjectHandle};
+    use cryptoki::session::{Session, UserType};
+    use cryptoki::types::AuthPin;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    /// AES KEK held by a PKCS#11 token. The DEKs are wrapped by the token,
+    /// with `CKM_AES_KEY_WRAP`, so that the KEK never leaves it.
+    pub(super) struct Pkcs11Kek {
+        // PKCS#11 sessions must not be used concurrently.
+        session: Mutex<Session>,
+        key: ObjectHandle,
+    }
+
+    impl Pkcs11Kek {
+        pub(super) fn new(
+            module: &Path,
+            token_label: &str,
+            pin: &str,
+            key_label: &str,
+        ) -> Result<Self> {
+            let pkcs11 = Pkcs11::new(module)
+                .with_context(|| format!("load PKCS#11 module {}", module.display()))?;
+            pkcs11.initialize(CInitializeArgs::OsThreads)?;
+
+            let slot = pkcs11
+                .get_slots_with_token()?
+                .into_iter()
+                .find(|slot| {
+                    pkcs11
+                        .get_token_info(*slot)
+                        .map(|info| info.label() == token_label)
+                        .unwrap_or(false)
+                })
+                .ok_or_else(|| anyhow!("no PKCS#11 token labeled {token_label}"))?;
+
+            let session = pkcs11.open_rw_session(slot)?;
+            session.login(UserType::User, Some(&AuthPin::new(pin.to_string())))?;
+
+            let key = session
+                .find_objects(&[
+                    Attribute::Class(ObjectClass::SECRET_KEY),
+                    Attribute::Label(key_label.as_bytes().to_vec()),
+                ])?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("no PKCS#11 key labeled {key_label}"))?;
+
+            Ok(Self {
+                session: Mutex::new(session),
+                key,
+            })
+        }
+    }
+
+    impl KeyEncryptionKey for Pkcs11Kek {
+        fn wrap(&self, dek: &[u8]) -> Result<Vec<u8>> {
+            self.session
+                .lock()
+                .map_err(|_| anyhow!("PKCS#11 session poisoned"))?
+                .encrypt(&Mechanism::AesKeyWrap, self.key, dek)
+                .context("PKCS#11 key wrap failed")
+        }
+
+        fn unwrap(&self, wrapped_dek: &[u8]) -> Result<Vec<u8>> {
+            self.session
+                .lock()
+                .map_err(|_| anyhow!("PKCS#11 session poisoned"))?
+                .decrypt(&Mechanism::AesKeyWrap, self.key, wrapped_dek)
+                .context("PKCS#11 key unwrap failed")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::conformance::{
+        repository_conformance_tests, resource_access_conformance_tests,
+    };
+    use crate::resource::local_fs::{LocalFs, LocalFsRepoDesc};
+    use std::path::Path;
+
+    const TEST_DATA: &[u8] = b"testdata";
+
+    fn kek_config(dir: &Path, id: &str) -> KekConfig {
+        let path = dir.join(id);
+        if !path.exists() {
+            std::fs::write(&path, rand::thread_rng().gen::<[u8; KEK_SIZE]>()).unwrap();
+        }
+        KekConfig::KeyFile {
+            id: id.to_string(),
+            path,
+        }
+    }
+
+    fn encrypted_repository(
+        dir: &Path,
+        kek: &str,
+        previous_keks: &[&str],
+    ) -> Result<EncryptedRepository> {
+        let local_fs = LocalFs::new(&LocalFsRepoDesc {
+            dir_path: Some(dir.join("repository").to_string_lossy().to_string()),
+        })?;
+        let config = RepositoryEncryptionConfig {
+            kek: kek_config(dir, kek),
+            previous_keks: previous_keks.iter().map(|id| kek_config(dir, id)).collect(),
+            rewrap: false,
+        };
+        EncryptedRepository::new(Box::new(local_fs), &config)
+    }
+
+    fn resource_desc(tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "test".into(),
+            resource_tag: tag.into(),
+            version: None,
+        }
+    }
+
+    fn stored_kek_id(dir: &Path, tag: &str) -> String {
+        let data = std::fs::read(dir.join("repository/default/test").join(tag)).unwrap();
+        serde_json::from_slice::<Envelope>(&data).unwrap().kek_id
+    }
+
+    repository_conformance_tests!({
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+        (tmp_dir, repository)
+    });
+
+    resource_access_conformance_tests!({
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+        (tmp_dir, repository)
+    });
+
+    #[tokio::test]
+    async fn write_and_read_encrypted_resource() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+
+        repository
+            .write_secret_resource(resource_desc("a"), TEST_DATA)
+            .await
+            .expect("write secret resource failed");
+
+        let stored = std::fs::read(tmp_dir.path().join("repository/default/test/a")).unwrap();
+        assert!(!stored
+            .windows(TEST_DATA.len())
+            .any(|window| window == TEST_DATA));
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("a"))
+                .await
+                .unwrap(),
+            TEST_DATA
+        );
+
+        let metadata = repository
+            .get_resource_metadata(resource_desc("a"))
+            .await
+            .unwrap();
+        assert_eq!(metadata.size, TEST_DATA.len() as u64);
+        assert_eq!(metadata.sha256, ResourceMetadata::content_digest(TEST_DATA));
+
+        // An envelope is bound to its resource.
+        std::fs::copy(
+            tmp_dir.path().join("repository/default/test/a"),
+            tmp_dir.path().join("repository/default/test/b"),
+        )
+        .unwrap();
+        assert!(repository
+            .read_secret_resource(resource_desc("b"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rotate_and_rewrap() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+        repository
+            .write_secret_resource(resource_desc("a"), TEST_DATA)
+            .await
+            .unwrap();
+        repository
+            .write_secret_resource(resource_desc("a"), b"newdata")
+            .await
+            .unwrap();
+
+        // A resource stored before the encryption was enabled.
+        std::fs::write(tmp_dir.path().join("repository/default/test/b"), TEST_DATA).unwrap();
+
+        let mut repository = encrypted_repository(tmp_dir.path(), "kek-2", &["kek-1"]).unwrap();
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("a"))
+                .await
+                .unwrap(),
+            b"newdata"
+        );
+        assert!(repository
+            .read_secret_resource(resource_desc("b"))
+            .await
+            .is_err());
+
+        // 2 versions of `a`, and `b`.
+        assert_eq!(repository.rewrap().await.unwrap(), 3);
+        assert_eq!(repository.rewrap().await.unwrap(), 0);
+        assert_eq!(stored_kek_id(tmp_dir.path(), "a"), "kek-2");
+        assert_eq!(stored_kek_id(tmp_dir.path(), "b"), "kek-2");
+
+        let repository = encrypted_repository(tmp_dir.path(), "kek-2", &[]).unwrap();
+        let version_1 = ResourceDesc {
+            version: Some(1),
+            ..resource_desc("a")
+        };
+        assert_eq!(
+            repository.read_secret_resource(version_1).await.unwrap(),
+            TEST_DATA
+        );
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("a"))
+                .await
+                .unwrap(),
+            b"newdata"
+        );
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("b"))
+                .await
+                .unwrap(),
+            TEST_DATA
+        );
+    }
+
+    #[tokio::test]
+    async fn envelope_bound_to_version() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+        repository
+            .write_secret_resource(resource_desc("a"), TEST_DATA)
+            .await
+            .unwrap();
+        repository
+            .write_secret_resource(resource_desc("a"), b"newdata")
+            .await
+            .unwrap();
+
+        // Version 1 cannot be served as version 2.
+        let versions = tmp_dir.path().join("repository/default/test/.versions/a");
+        std::fs::copy(versions.join("1"), versions.join("2")).unwrap();
+        std::fs::copy(
+            versions.join("1"),
+            tmp_dir.path().join("repository/default/test/a"),
+        )
+        .unwrap();
+        assert!(repository
+            .read_secret_resource(resource_desc("a"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrap_unversioned_envelope() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut repository = encrypted_repository(tmp_dir.path(), "kek-1", &[]).unwrap();
+
+        // An envelope written before the AAD bound the version.
+        let dek = rand::thread_rng().gen::<[u8; DEK_SIZE]>();
+        let iv = rand::thread_rng().gen::<[u8; IV_SIZE]>();
+        let ciphertext = Aes256Gcm::new_from_slice(&dek)
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: TEST_DATA,
+                    aad: b"default/test/a",
+                },
+            )
+            .unwrap();
+        let envelope = Envelope {
+            kek_id: "kek-1".to_string(),
+            wrapped_dek: STANDARD.encode(repository.kek("kek-1").unwrap().wrap(&dek).unwrap()),
+            iv: STANDARD.encode(iv),
+            ciphertext: STANDARD.encode(ciphertext),
+            versioned: false,
+        };
+        std::fs::write(
+            tmp_dir.path().join("repository/default/test/a"),
+            serde_json::to_vec(&envelope).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("a"))
+                .await
+                .unwrap(),
+            TEST_DATA
+        );
+        assert_eq!(repository.rewrap().await.unwrap(), 1);
+        assert_eq!(repository.rewrap().await.unwrap(), 0);
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("a"))
+                .await
+                .unwrap(),
+            TEST_DATA
+        );
+    }
+
+    #[test]
+    fn duplicated_kek_id() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        assert!(encrypted_repository(tmp_dir.path(), "kek-1", &["kek-1"]).is_err());
+    }
+}
@@ -0,0 +1,770 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{
+    Repository, RepositoryConfig, ResourceAccess, ResourceDesc, ResourceLimits, ResourceMetadata,
+    ResourceNotFound,
+};
+use anyhow::{bail, Context, Result};
+use p256::pkcs8::{EncodePrivateKey, LineEnding};
+use rand::{rngs::OsRng, seq::SliceRandom, RngCore};
+use rsa::RsaPrivateKey;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+const DEFAULT_PASSWORD_CHARSET: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const DEFAULT_MAX_RESOURCES: usize = 1000;
+
+/// Backend storing the resources, and the generators minting the missing ones.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GeneratedRepoDesc {
+    #[serde(default)]
+    pub backend: Box<RepositoryConfig>,
+    #[serde(default)]
+    pub generators: Vec<GeneratorRule>,
+}
+
+/// Generator of the resources with a `<repository>/<type>/<tag>` path
+/// starting with `prefix`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GeneratorRule {
+    pub prefix: String,
+    /// Resources no longer generated once the backend holds that many
+    /// resources starting with `prefix`, as any tag can be requested.
+    #[serde(default = "default_max_resources")]
+    pub max_resources: usize,
+    #[serde(flatten)]
+    pub generator: Generator,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum Generator {
+    /// Raw AES key.
+    Aes {
+        #[serde(default = "default_aes_bits")]
+        bits: usize,
+    },
+    /// PKCS#8 PEM encoded RSA private key.
+    Rsa {
+        #[serde(default = "default_rsa_bits")]
+        bits: usize,
+    },
+    /// PKCS#8 PEM encoded EC private key.
+    Ec {
+        #[serde(default)]
+        curve: EcCurve,
+    },
+    /// Random password, drawn from `charset`.
+    Password {
+        #[serde(default = "default_password_length")]
+        length: usize,
+        #[serde(default = "default_password_charset")]
+        charset: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum EcCurve {
+    #[default]
+    P256,
+    P384,
+}
+
+fn default_aes_bits() -> usize {
+    256
+}
+
+fn default_rsa_bits() -> usize {
+    3072
+}
+
+fn default_password_length() -> usize {
+    32
+}
+
+fn default_password_charset() -> String {
+    DEFAULT_PASSWORD_CHARSET.to_string()
+}
+
+fn default_max_resources() -> usize {
+    DEFAULT_MAX_RESOURCES
+}
+
+impl Generator {
+    fn validate(&self) -> Result<()> {
+        match self {
+            Self::Aes { bits } if ![128, 192, 256].contains(bits) => {
+                bail!("invalid AES key size {bits}, expected 128, 192 or 256 bits")
+            }
+            Self::Rsa { bits } if *bits < 2048 => {
+                bail!("invalid RSA key size {bits}, expected at least 2048 bits")
+            }
+            Self::Password { length, .. } if *length == 0 => bail!("empty password length"),
+            Self::Password { charset, .. } if charset.is_empty() => bail!("empty password charset"),
+            _ => Ok(()),
+        }
+    }
+
+    async fn generate(&self) -> Result<Vec<u8>> {
+        let generator = self.clone();
+        // RSA key generation may take a while.
+        tokio::task::spawn_blocking(move || generator.generate_blocking()).await?
+    }
+
+    fn generate_blocking(&self) -> Result<Vec<u8>> {
+        let data = match self {
+            Self::Aes { bits } => {
+                let mut key = vec![0; bits / 8];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+            Self::Rsa { bits } => RsaPrivateKey::new(&mut OsRng, *bits)
+                .context("generate RSA key")?
+                .to_pkcs8_pem(LineEnding::LF)
+                .context("encode RSA key")?
+                .as_bytes()
+                .to_vec(),
+            Self::Ec {
+                curve: EcCurve::P256,
+            } => p256::SecretKey::random(&mut OsRng)
+                .to_pkcs8_pem(LineEnding::LF)
+                .context("encode EC key")?
+                .as_bytes()
+                .to_vec(),
+            Self::Ec {
+                curve: EcCurve::P384,
+            } => p384::SecretKey::random(&mut OsRng)
+                .to_pkcs8_pem(LineEnding::LF)
+                .context("encode EC key")?
+                .as_bytes()
+                .to_vec(),
+            Self::Password { length, charset } => {
+                let charset: Vec<char> = charset.chars().collect();
+                (0..*length)
+                    .map(|_| charset.choose(&mut OsRng).expect("empty password charset"))
+                    .collect::<String>()
+                    .into_bytes()
+            }
+        };
+
+        Ok(data)
+    }
+}
+
+/// Repository minting the missing resources matching a generator the first
+/// time they are read, and persisting them in its backend, which serves them
+/// afterwards.
+pub struct GeneratedRepository {
+    backend: Arc<RwLock<dyn Repository + Send + Sync>>,
+    generators: Vec<GeneratorRule>,
+}
+
+#[async_trait::async_trait]
+impl Repository for GeneratedRepository {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        // Only the latest version of a resource is generated.
+        let rule = match resource_desc.version {
+            Some(_) => None,
+            None => self.generator(&resource_desc),
+        };
+        let Some(rule) = rule else {
+            return self
+                .backend
+                .read()
+                .await
+                .read_secret_resource(resource_desc)
+                .await;
+        };
+
+        if let Some(data) = read_existing(&*self.backend.read().await, &resource_desc).await? {
+            return Ok(data);
+        }
+
+        // Concurrent requests may each generate one more resource.
+        if count_resources(&*self.backend.read().await, &rule.prefix).await? >= rule.max_resources {
+            bail!(
+                "{} resources already exist under the {} generator prefix",
+                rule.max_resources,
+                rule.prefix
+            );
+        }
+
+        // RSA key generation may take a while, do not block the backend.
+        let data = rule.generator.generate().await?;
+
+        let mut backend = self.backend.write().await;
+        // The resource may have been generated by a concurrent request.
+        if let Some(data) = read_existing(&*backend, &resource_desc).await? {
+            return Ok(data);
+        }
+
+        backend
+            .write_secret_resource(resource_desc.clone(), &data)
+            .await
+            .context("persist generated resource")?;
+        log::info!(
+            "Generated resource {}/{}/{}",
+            resource_desc.repository_name,
+            resource_desc.resource_type,
+            resource_desc.resource_tag
+        );
+
+        Ok(data)
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.backend
+            .write()
+            .await
+            .write_secret_resource(resource_desc, data)
+            .await
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.backend
+            .write()
+            .await
+            .replace_secret_resource_version(resource_desc, data)
+            .await
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        self.backend
+            .write()
+            .await
+            .delete_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        self.backend
+            .read()
+            .await
+            .get_resource_metadata(resource_desc)
+            .await
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        self.backend
+            .read()
+            .await
+            .list_resource_versions(resource_desc)
+            .await
+    }
+
+    async fn rollback_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        version: u64,
+    ) -> Result<u64> {
+        self.backend
+            .write()
+            .await
+            .rollback_secret_resource(resource_desc, version)
+            .await
+    }
+
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        // Generate the missing resource first, the backend counts the reads.
+        if resource_desc.version.is_none() && self.generator(&resource_desc).is_some() {
+            self.read_secret_resource(resource_desc.clone()).await?;
+        }
+
+        self.backend
+            .read()
+            .await
+            .release_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn get_resource_access(&self, resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        self.backend
+            .read()
+            .await
+            .get_resource_access(resource_desc)
+            .await
+    }
+
+    async fn set_resource_limits(
+        &mut self,
+        resource_desc: ResourceDesc,
+        limits: ResourceLimits,
+    ) -> Result<()> {
+        self.backend
+            .write()
+            .await
+            .set_resource_limits(resource_desc, limits)
+            .await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.backend.read().await.list_repositories().await
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        self.backend
+            .read()
+            .await
+            .list_resource_types(repository_name)
+            .await
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        self.backend
+            .read()
+            .await
+            .list_resource_tags(repository_name, resource_type)
+            .await
+    }
+}
+
+impl GeneratedRepository {
+    pub fn new(
+        backend: Arc<RwLock<dyn Repository + Send + Sync>>,
+        generators: Vec<GeneratorRule>,
+    ) -> Result<Self> {
+        for rule in &generators {
+            rule.generator
+                .validate()
+                .with_context(|| format!("invalid generator of {}", rule.prefix))?;
+        }
+
+        Ok(Self {
+            backend,
+            generators,
+        })
+    }
+
+    /// Find the generator with the longest prefix matching the resource path.
+    fn generator(&self, resource_desc: &ResourceDesc) -> Option<&GeneratorRule> {
+        let path = format!(
+            "{}/{}/{}",
+            resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+        );
+
+        self.generators
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+}
+
+/// Read the latest version of a resource from `backend`, or `None` when it
+/// does not exist. Any other failure is returned, so that an existing resource
+/// is never generated again.
+async fn read_existing(
+    backend: &(dyn Repository + Send + Sync),
+    resource_desc: &ResourceDesc,
+) -> Result<Option<Vec<u8>>> {
+    let error = match backend.read_secret_resource(resource_desc.clone()).await {
+        Ok(data) => return Ok(Some(data)),
+        Err(e) if e.is::<ResourceNotFound>() => return Ok(None),
+        Err(e) => e,
+    };
+
+    // Not all backends report a missing resource on read.
+    match backend.list_resource_versions(resource_desc.clone()).await {
+        Ok(_) => Err(error),
+        Err(e) if e.is::<ResourceNotFound>() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Count the resources of `backend` with a path starting with `prefix`,
+/// listing only the repositories and resource types the prefix covers.
+async fn count_resources(backend: &(dyn Repository + Send + Sync), prefix: &str) -> Result<usize> {
+    let repositories = match prefix.split_once('/') {
+        Some((repository_name, _)) => vec![repository_name.to_string()],
+        None => backend.list_repositories().await?,
+    };
+
+    let mut count = 0;
+    for repository_name in repositories {
+        let Some(type_prefix) = strip_component(prefix, &repository_name) else {
+            continue;
+        };
+        let resource_types = match type_prefix.split_once('/') {
+            Some((resource_type, _)) => vec![resource_type.to_string()],
+            None => or_empty(backend.list_resource_types(&repository_name).await)?,
+        };
+
+        for resource_type in resource_types {
+            let Some(tag_prefix) = strip_component(type_prefix, &resource_type) else {
+                continue;
+            };
+            count += or_empty(
+                backend
+                    .list_resource_tags(&repository_name, &resource_type)
+                    .await,
+            )?
+            .iter()
+            .filter(|tag| tag.starts_with(tag_prefix))
+            .count();
+        }
+    }
+
+    Ok(count)
+}
+
+/// Strip the leading `component` of `prefix`, returning the prefix of the
+/// next components, or `None` when `component` does not match `prefix`.
+fn strip_component<'a>(prefix: &'a str, component: &str) -> Option<&'a str> {
+    match prefix.strip_prefix(component) {
+        Some("") => Some(""),
+        Some(rest) => rest.strip_prefix('/'),
+        None if component.starts_with(prefix) => Some(""),
+        None => None,
+    }
+}
+
+/// List a missing repository or resource type as empty.
+fn or_empty(listing: Result<Vec<String>>) -> Result<Vec<String>> {
+    match listing {
+        Err(e) if e.is::<ResourceNotFound>() => Ok(Vec::new()),
+        listing => listing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{
+        conformance::{repository_conformance_tests, resource_access_conformance_tests},
+        local_fs::{LocalFs, LocalFsRepoDesc},
+    };
+    use p256::pkcs8::DecodePrivateKey;
+    use rstest::rstest;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
+
+    /// Local fs backend failing to read its resources while `unavailable`.
+    struct FlakyBackend {
+        inner: LocalFs,
+        unavailable: Arc<AtomicBool>,
+    }
+
+    impl FlakyBackend {
+        fn check(&self) -> Result<()> {
+            if self.unavailable.load(Ordering::SeqCst) {
+                bail!("backend unavailable");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Repository for FlakyBackend {
+        async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+            self.check()?;
+            self.inner.read_secret_resource(resource_desc).await
+        }
+
+        async fn write_secret_resource(
+            &mut self,
+            resource_desc: ResourceDesc,
+            data: &[u8],
+        ) -> Result<()> {
+            self.inner.write_secret_resource(resource_desc, data).await
+        }
+
+        async fn replace_secret_resource_version(
+            &mut self,
+            resource_desc: ResourceDesc,
+            data: &[u8],
+        ) -> Result<()> {
+            self.inner
+                .replace_secret_resource_version(resource_desc, data)
+                .await
+        }
+
+        async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+            self.inner.delete_secret_resource(resource_desc).await
+        }
+
+        async fn get_resource_metadata(
+            &self,
+            resource_desc: ResourceDesc,
+        ) -> Result<ResourceMetadata> {
+            self.check()?;
+            self.inner.get_resource_metadata(resource_desc).await
+        }
+
+        async fn list_resource_versions(
+            &self,
+            resource_desc: ResourceDesc,
+        ) -> Result<Vec<ResourceMetadata>> {
+            self.check()?;
+            self.inner.list_resource_versions(resource_desc).await
+        }
+
+        async fn list_repositories(&self) -> Result<Vec<String>> {
+            self.check()?;
+            self.inner.list_repositories().await
+        }
+
+        async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+            self.check()?;
+            self.inner.list_resource_types(repository_name).await
+        }
+
+        async fn list_resource_tags(
+            &self,
+            repository_name: &str,
+            resource_type: &str,
+        ) -> Result<Vec<String>> {
+            self.check()?;
+            self.inner
+                .list_resource_tags(repository_name, resource_type)
+                .await
+        }
+    }
+
+    fn generated_repository(generators: Vec<GeneratorRule>) -> (TempDir, GeneratedRepository) {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+        };
+        let backend = Arc::new(RwLock::new(
+            LocalFs::new(&repo_desc).expect("create local fs failed"),
+        ));
+
+        let repository =
+            GeneratedRepository::new(backend, generators).expect("create repository failed");
+        (tmp_dir, repository)
+    }
+
+    fn rule(prefix: &str, generator: Generator) -> GeneratorRule {
+        GeneratorRule {
+            prefix: prefix.into(),
+            max_resources: DEFAULT_MAX_RESOURCES,
+            generator,
+        }
+    }
+
+    fn resource_desc(resource_type: &str, resource_tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: resource_type.into(),
+            resource_tag: resource_tag.into(),
+            version: None,
+        }
+    }
+
+    // The conformance suite reads no resource matching this generator.
+    repository_conformance_tests!(generated_repository(vec![rule(
+        "generated/",
+        Generator::Aes { bits: 256 }
+    )]));
+
+    resource_access_conformance_tests!(generated_repository(vec![rule(
+        "generated/",
+        Generator::Aes { bits: 256 }
+    )]));
+
+    #[tokio::test]
+    async fn generate_once() {
+        let (tmp_dir, repository) = generated_repository(vec![
+            rule("default/keys/", Generator::Aes { bits: 256 }),
+            rule(
+                "default/keys/password-",
+                Generator::Password {
+                    length: 16,
+                    charset: "ab".into(),
+                },
+            ),
+        ]);
+
+        let key = repository
+            .read_secret_resource(resource_desc("keys", "disk"))
+            .await
+            .expect("generate resource failed");
+        assert_eq!(key.len(), 32);
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("keys", "disk"))
+                .await
+                .unwrap(),
+            key
+        );
+        assert_eq!(
+            std::fs::read(tmp_dir.path().join("default/keys/disk")).unwrap(),
+            key
+        );
+
+        // The longest prefix wins.
+        let password = repository
+            .read_secret_resource(resource_desc("keys", "password-db"))
+            .await
+            .expect("generate resource failed");
+        assert_eq!(password.len(), 16);
+        assert!(password.iter().all(|c| *c == b'a' || *c == b'b'));
+
+        // A resource matching no generator, or an older version, is not generated.
+        assert!(repository
+            .read_secret_resource(resource_desc("certs", "disk"))
+            .await
+            .is_err());
+        assert!(repository
+            .read_secret_resource(ResourceDesc {
+                version: Some(2),
+                ..resource_desc("keys", "disk")
+            })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn existing_resource() {
+        let (_tmp_dir, mut repository) =
+            generated_repository(vec![rule("default/", Generator::Aes { bits: 128 })]);
+
+        repository
+            .write_secret_resource(resource_desc("keys", "disk"), b"testdata")
+            .await
+            .expect("write secret resource failed");
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("keys", "disk"))
+                .await
+                .unwrap(),
+            b"testdata"
+        );
+    }
+
+    #[tokio::test]
+    async fn max_resources() {
+        let (_tmp_dir, mut repository) = generated_repository(vec![GeneratorRule {
+            max_resources: 2,
+            ..rule("default/keys/", Generator::Aes { bits: 256 })
+        }]);
+
+        repository
+            .write_secret_resource(resource_desc("keys", "disk"), b"testdata")
+            .await
+            .expect("write secret resource failed");
+        // A resource out of the prefix is not counted.
+        repository
+            .write_secret_resource(resource_desc("certs", "disk"), b"testdata")
+            .await
+            .expect("write secret resource failed");
+
+        let key = repository
+            .read_secret_resource(resource_desc("keys", "swap"))
+            .await
+            .expect("generate resource failed");
+        assert!(repository
+            .read_secret_resource(resource_desc("keys", "home"))
+            .await
+            .is_err());
+
+        // The generated resources are still served.
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("keys", "swap"))
+                .await
+                .unwrap(),
+            key
+        );
+    }
+
+    #[tokio::test]
+    async fn backend_failure() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+        };
+        let unavailable = Arc::new(AtomicBool::new(false));
+        let backend = Arc::new(RwLock::new(FlakyBackend {
+            inner: LocalFs::new(&repo_desc).expect("create local fs failed"),
+            unavailable: unavailable.clone(),
+        }));
+        let mut repository = GeneratedRepository::new(
+            backend,
+            vec![rule("default/", Generator::Aes { bits: 256 })],
+        )
+        .expect("create repository failed");
+
+        repository
+            .write_secret_resource(resource_desc("keys", "disk"), b"testdata")
+            .await
+            .expect("write secret resource failed");
+
+        // An existing resource the backend fails to read is not generated again.
+        unavailable.store(true, Ordering::SeqCst);
+        assert!(repository
+            .read_secret_resource(resource_desc("keys", "disk"))
+            .await
+            .is_err());
+
+        unavailable.store(false, Ordering::SeqCst);
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("keys", "disk"))
+                .await
+                .unwrap(),
+            b"testdata"
+        );
+        assert_eq!(
+            repository
+                .list_resource_versions(resource_desc("keys", "disk"))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[rstest]
+    #[case::p256(EcCurve::P256)]
+    #[case::p384(EcCurve::P384)]
+    fn generate_ec_key(#[case] curve: EcCurve) {
+        let pem = Generator::Ec { curve }.generate_blocking().unwrap();
+        let pem = String::from_utf8(pem).unwrap();
+
+        match curve {
+            EcCurve::P256 => assert!(p256::SecretKey::from_pkcs8_pem(&pem).is_ok()),
+            EcCurve::P384 => assert!(p384::SecretKey::from_pkcs8_pem(&pem).is_ok()),
+        }
+    }
+
+    #[test]
+    fn generate_rsa_key() {
+        let pem = Generator::Rsa { bits: 2048 }.generate_blocking().unwrap();
+
+        let key = RsaPrivateKey::from_pkcs8_pem(std::str::from_utf8(&pem).unwrap()).unwrap();
+        assert_eq!(rsa::traits::PublicKeyParts::size(&key), 256);
+    }
+
+    #[rstest]
+    #[case::aes(Generator::Aes { bits: 512 })]
+    #[case::rsa(Generator::Rsa { bits: 1024 })]
+    #[case::password_length(Generator::Password { length: 0, charset: "ab".into() })]
+    #[case::password_charset(Generator::Password { length: 8, charset: String::new() })]
+    fn invalid_generator(#[case] generator: Generator) {
+        let (_tmp_dir, backend) = generated_repository(Vec::new());
+        assert!(GeneratedRepository::new(
+            Arc::new(RwLock::new(backend)),
+            vec![rule("default/", generator)]
+        )
+        .is_err());
+    }
+}
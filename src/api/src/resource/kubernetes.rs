@@ -0,0 +1,1235 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Repository, ResourceDesc, ResourceMetadata, ResourceNotFound};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::{header::CONTENT_TYPE, Method, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Labels mapping a Secret to a KBS repository and resource type. Every key
+/// of the Secret data is a resource tag.
+pub const LABEL_REPOSITORY: &str = "kbs.confidentialcontainers.org/repository";
+pub const LABEL_TYPE: &str = "kbs.confidentialcontainers.org/type";
+
+/// Label of the Secret recording the resources history of a resource type.
+/// Its `<tag>.<N>` keys are the versions `N`, the latest included, of the
+/// resource `<tag>`. A resource with no history is its version 1.
+pub const LABEL_HISTORY: &str = "kbs.confidentialcontainers.org/history";
+
+/// Annotation of the history Secret recording the modification time of each
+/// version, as a JSON object of the `<tag>.<N>` versions UNIX times.
+const ANNOTATION_MODIFIED: &str = "kbs.confidentialcontainers.org/modified";
+
+const DEFAULT_NAME_PREFIX: &str = "kbs-";
+const DEFAULT_MAX_VERSIONS: usize = 10;
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+fn default_name_prefix() -> String {
+    DEFAULT_NAME_PREFIX.to_string()
+}
+
+fn default_max_versions() -> usize {
+    DEFAULT_MAX_VERSIONS
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct KubernetesRepoDesc {
+    /// Namespace of the resource Secrets. Defaults to the kubeconfig context
+    /// namespace, or to the KBS pod namespace in a cluster.
+    pub namespace: Option<String>,
+    /// Path to a kubeconfig file. When not set, the KBS pod service account
+    /// is used.
+    pub kubeconfig: Option<String>,
+    /// Name prefix of the Secrets created by the KBS.
+    #[serde(default = "default_name_prefix")]
+    pub name_prefix: String,
+    /// Number of versions of each resource kept in the history Secret, the
+    /// older ones are removed. A Secret holds at most 1 MiB of data.
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize,
+}
+
+impl Default for KubernetesRepoDesc {
+    fn default() -> Self {
+        Self {
+            namespace: None,
+            kubeconfig: None,
+            name_prefix: default_name_prefix(),
+            max_versions: default_max_versions(),
+        }
+    }
+}
+
+/// Kubernetes `Secret` object, as used by the KBS. Secrets are modified with
+/// merge patches, keeping their other fields.
+#[derive(Deserialize)]
+struct Secret {
+    metadata: ObjectMeta,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    name: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    resource_version: Option<String>,
+    creation_timestamp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SecretList {
+    items: Vec<Secret>,
+}
+
+impl Secret {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.data
+            .get(key)
+            .map(|data| STANDARD.decode(data).context("decode secret data"))
+            .transpose()
+    }
+
+    /// Sorted versions recorded in a history Secret for a resource tag.
+    fn history(&self, tag: &str) -> Vec<u64> {
+        let mut versions: Vec<u64> = self
+            .data
+            .keys()
+            .filter_map(|key| key.strip_prefix(tag)?.strip_prefix('.')?.parse().ok())
+            .collect();
+        versions.sort_unstable();
+        versions
+    }
+
+    fn modified_times(&self) -> Result<BTreeMap<String, u64>> {
+        self.metadata
+            .annotations
+            .get(ANNOTATION_MODIFIED)
+            .map(|times| serde_json::from_str(times).context("parse secret modification times"))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn creation_time(&self) -> Result<u64> {
+        let timestamp = self
+            .metadata
+            .creation_timestamp
+            .as_deref()
+            .context("no secret creation timestamp")?;
+        Ok(OffsetDateTime::parse(timestamp, &Rfc3339)?.unix_timestamp() as u64)
+    }
+}
+
+/// Changes of a Secret data, a `None` value removing a key.
+type DataPatch = BTreeMap<String, Option<Vec<u8>>>;
+
+pub struct Kubernetes {
+    api: ApiClient,
+    namespace: String,
+    name_prefix: String,
+    max_versions: usize,
+}
+
+#[async_trait::async_trait]
+impl Repository for Kubernetes {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        let (data, _) = self.read(&resource_desc).await?;
+        Ok(data)
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let tag = &resource_desc.resource_tag;
+        let resource = self.find(&resource_desc, false).await?;
+        let history = self.find(&resource_desc, true).await?;
+
+        let mut versions = history
+            .as_ref()
+            .map(|history| history.history(tag))
+            .unwrap_or_default();
+        let mut history_patch = DataPatch::new();
+        let mut modified = history
+            .as_ref()
+            .map(Secret::modified_times)
+            .transpose()?
+            .unwrap_or_default();
+
+        // Record the resource with no history as version 1, before adding
+        // the new version.
+        if let Some(resource) = &resource {
+            if let (true, Some(legacy)) = (versions.is_empty(), resource.get(tag)?) {
+                history_patch.insert(format!("{tag}.1"), Some(legacy));
+                modified.insert(format!("{tag}.1"), resource.creation_time()?);
+                versions.push(1);
+            }
+        }
+
+        let version = versions.last().map_or(1, |latest| latest + 1);
+        history_patch.insert(format!("{tag}.{version}"), Some(data.to_vec()));
+        modified.insert(format!("{tag}.{version}"), now()?);
+        versions.push(version);
+
+        let expired = versions.len().saturating_sub(self.max_versions);
+        for version in &versions[..expired] {
+            history_patch.insert(format!("{tag}.{version}"), None);
+            modified.remove(&format!("{tag}.{version}"));
+        }
+
+        self.update(&resource_desc, true, history, history_patch, Some(modified))
+            .await
+            .context("write kubernetes secret")?;
+        self.update(
+            &resource_desc,
+            false,
+            resource,
+            DataPatch::from([(tag.clone(), Some(data.to_vec()))]),
+            None,
+        )
+        .await
+        .context("write kubernetes secret")
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let version = resource_desc.version.context("no resource version")?;
+        let tag = &resource_desc.resource_tag;
+        let resource = self.find(&resource_desc, false).await?;
+        let history = self.find(&resource_desc, true).await?;
+
+        let versions = history
+            .as_ref()
+            .map(|history| history.history(tag))
+            .unwrap_or_default();
+        let legacy = versions.is_empty()
+            && version == 1
+            && resource.as_ref().map(|r| r.data.contains_key(tag)) == Some(true);
+        if !versions.contains(&version) && !legacy {
+            bail!(ResourceNotFound::Version(version));
+        }
+
+        if !legacy {
+            let mut modified = history
+                .as_ref()
+                .map(Secret::modified_times)
+                .transpose()?
+                .unwrap_or_default();
+            modified.insert(format!("{tag}.{version}"), now()?);
+            self.update(
+                &resource_desc,
+                true,
+                history,
+                DataPatch::from([(format!("{tag}.{version}"), Some(data.to_vec()))]),
+                Some(modified),
+            )
+            .await
+            .context("write kubernetes secret")?;
+        }
+
+        // Keep the latest version copy up to date.
+        if legacy || versions.last() == Some(&version) {
+            self.update(
+                &resource_desc,
+                false,
+                resource,
+                DataPatch::from([(tag.clone(), Some(data.to_vec()))]),
+                None,
+            )
+            .await
+            .context("write kubernetes secret")?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        let tag = &resource_desc.resource_tag;
+        let resource = self
+            .find(&resource_desc, false)
+            .await?
+            .filter(|resource| resource.data.contains_key(tag))
+            .ok_or(ResourceNotFound::Resource)?;
+
+        if let Some(history) = self.find(&resource_desc, true).await? {
+            let versions = history.history(tag);
+            let mut modified = history.modified_times()?;
+            let patch = versions
+                .iter()
+                .map(|version| {
+                    modified.remove(&format!("{tag}.{version}"));
+                    (format!("{tag}.{version}"), None)
+                })
+                .collect();
+            self.update(&resource_desc, true, Some(history), patch, Some(modified))
+                .await
+                .context("delete resource versions from kubernetes")?;
+        }
+
+        self.update(
+            &resource_desc,
+            false,
+            Some(resource),
+            DataPatch::from([(tag.clone(), None)]),
+            None,
+        )
+        .await
+        .context("delete resource from kubernetes")
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        let (data, modified) = self.read(&resource_desc).await?;
+        let version = match resource_desc.version {
+            Some(version) => version,
+            None => *self
+                .versions(&resource_desc)
+                .await?
+                .last()
+                .ok_or(ResourceNotFound::Resource)?,
+        };
+
+        Ok(resource_metadata(version, &data, modified))
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        let versions = self.versions(&resource_desc).await?;
+        if versions.is_empty() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        let mut metadata = Vec::with_capacity(versions.len());
+        for version in versions {
+            let (data, modified) = self
+                .read(&ResourceDesc {
+                    version: Some(version),
+                    ..resource_desc.clone()
+                })
+                .await?;
+            metadata.push(resource_metadata(version, &data, modified));
+        }
+
+        Ok(metadata)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let secrets = self
+            .secrets(&format!("{LABEL_REPOSITORY},{LABEL_TYPE},!{LABEL_HISTORY}"))
+            .await?;
+        Ok(label_values(&secrets, LABEL_REPOSITORY))
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        let types = label_values(
+            &self
+                .secrets(&format!(
+                    "{LABEL_REPOSITORY}={},{LABEL_TYPE},!{LABEL_HISTORY}",
+                    label_value(repository_name)?
+                ))
+                .await?,
+            LABEL_TYPE,
+        );
+        if types.is_empty() {
+            bail!(ResourceNotFound::Repository(repository_name.to_string()));
+        }
+
+        Ok(types)
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        let resource = self
+            .find(
+                &ResourceDesc {
+                    repository_name: repository_name.to_string(),
+                    resource_type: resource_type.to_string(),
+                    resource_tag: String::new(),
+                    version: None,
+                },
+                false,
+            )
+            .await?
+            .ok_or_else(|| {
+                ResourceNotFound::ResourceType(format!("{repository_name}/{resource_type}"))
+            })?;
+
+        // The Secret data keys are sorted.
+        Ok(resource.data.keys().cloned().collect())
+    }
+}
+
+/// Sorted values of a label, on the Secrets with some data.
+fn label_values(secrets: &[Secret], label: &str) -> Vec<String> {
+    let mut values: Vec<String> = secrets
+        .iter()
+        .filter(|secret| !secret.data.is_empty())
+        .filter_map(|secret| secret.metadata.labels.get(label).cloned())
+        .collect();
+    values.sort();
+    values.dedup();
+    values
+}
+
+/// Check that `value` can be used in a label selector: label values are at
+/// most 63 alphanumeric, `-`, `_` or `.` characters, starting and ending with
+/// an alphanumeric one.
+fn label_value(value: &str) -> Result<&str> {
+    let valid = value.len() <= 63
+        && value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("{value} is not a valid kubernetes label value");
+    }
+
+    Ok(value)
+}
+
+fn resource_metadata(version: u64, data: &[u8], modified: u64) -> ResourceMetadata {
+    ResourceMetadata {
+        version,
+        size: data.len() as u64,
+        // The Secret creation time is not the resource one.
+        created: None,
+        modified,
+        sha256: ResourceMetadata::content_digest(data),
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+impl Kubernetes {
+    pub fn new(repo_desc: &KubernetesRepoDesc) -> Result<Self> {
+        if repo_desc.max_versions == 0 {
+            bail!("`max_versions` must be at least 1");
+        }
+
+        let (api, namespace) = match &repo_desc.kubeconfig {
+            Some(kubeconfig) => ApiClient::from_kubeconfig(Path::new(kubeconfig))?,
+            None => ApiClient::in_cluster()?,
+        };
+
+        Ok(Self {
+            api,
+            namespace: repo_desc
+                .namespace
+                .clone()
+                .or(namespace)
+                .unwrap_or("default".to_string()),
+            name_prefix: repo_desc.name_prefix.clone(),
+            max_versions: repo_desc.max_versions,
+        })
+    }
+
+    /// Name of the Secret created for a resource type, or its history.
+    fn secret_name(&self, resource_desc: &ResourceDesc, history: bool) -> String {
+        // Secret names are lowercase DNS subdomains, the digest keeps them unique.
+        let path = format!(
+            "{}/{}",
+            resource_desc.repository_name, resource_desc.resource_type
+        );
+        let sanitized: String = path
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' => c,
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => '-',
+            })
+            .collect();
+        let digest = &ResourceMetadata::content_digest(path.as_bytes())[..8];
+
+        let mut name = format!(
+            "{}{}-{digest}",
+            self.name_prefix,
+            sanitized.trim_matches('-')
+        );
+        if history {
+            name.push_str("-history");
+        }
+        name
+    }
+
+    fn labels(resource_desc: &ResourceDesc, history: bool) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::from([
+            (
+                LABEL_REPOSITORY.to_string(),
+                resource_desc.repository_name.clone(),
+            ),
+            (LABEL_TYPE.to_string(), resource_desc.resource_type.clone()),
+        ]);
+        if history {
+            labels.insert(LABEL_HISTORY.to_string(), "true".to_string());
+        }
+        labels
+    }
+
+    async fn secrets(&self, label_selector: &str) -> Result<Vec<Secret>> {
+        let url = self.api.url(&self.namespace, None, Some(label_selector))?;
+        let list: SecretList = self
+            .api
+            .send(Method::GET, url, None)
+            .await
+            .context("list kubernetes secrets")?
+            .json()
+            .await?;
+        Ok(list.items)
+    }
+
+    /// Find the Secret of a resource type, or its history.
+    async fn find(&self, resource_desc: &ResourceDesc, history: bool) -> Result<Option<Secret>> {
+        let history_selector = match history {
+            true => LABEL_HISTORY.to_string(),
+            false => format!("!{LABEL_HISTORY}"),
+        };
+        let mut secrets = self
+            .secrets(&format!(
+                "{LABEL_REPOSITORY}={},{LABEL_TYPE}={},{history_selector}",
+                label_value(&resource_desc.repository_name)?,
+                label_value(&resource_desc.resource_type)?
+            ))
+            .await?;
+
+        if secrets.len() > 1 {
+            bail!(
+                "several kubernetes secrets hold the {}/{} resources",
+                resource_desc.repository_name,
+                resource_desc.resource_type
+            );
+        }
+        Ok(secrets.pop())
+    }
+
+    /// Sorted versions of a resource, empty if the resource does not exist.
+    async fn versions(&self, resource_desc: &ResourceDesc) -> Result<Vec<u64>> {
+        let tag = &resource_desc.resource_tag;
+        if let Some(history) = self.find(resource_desc, true).await? {
+            let versions = history.history(tag);
+            if !versions.is_empty() {
+                return Ok(versions);
+            }
+        }
+
+        let resource = self.find(resource_desc, false).await?;
+        Ok(match resource.map(|r| r.data.contains_key(tag)) {
+            Some(true) => vec![1],
+            _ => Vec::new(),
+        })
+    }
+
+    /// Read a resource content and modification time.
+    async fn read(&self, resource_desc: &ResourceDesc) -> Result<(Vec<u8>, u64)> {
+        let tag = &resource_desc.resource_tag;
+        let resource = self.find(resource_desc, false).await?;
+
+        if let Some(version) = resource_desc.version {
+            if let Some(history) = self.find(resource_desc, true).await? {
+                let key = format!("{tag}.{version}");
+                if let Some(data) = history.get(&key)? {
+                    let modified = match history.modified_times()?.get(&key) {
+                        Some(modified) => *modified,
+                        None => history.creation_time()?,
+                    };
+                    return Ok((data, modified));
+                }
+                if !history.history(tag).is_empty() {
+                    bail!(ResourceNotFound::Version(version));
+                }
+            }
+            if version != 1 {
+                bail!(ResourceNotFound::Version(version));
+            }
+        }
+
+        let resource = resource.ok_or(ResourceNotFound::Resource)?;
+        let data = resource.get(tag)?.ok_or(ResourceNotFound::Resource)?;
+        let modified = match self.find(resource_desc, true).await? {
+            Some(history) => {
+                let versions = history.history(tag);
+                let latest = versions.last().map(|version| format!("{tag}.{version}"));
+                latest
+                    .and_then(|key| history.modified_times().ok()?.get(&key).copied())
+                    .map_or_else(|| resource.creation_time(), Ok)?
+            }
+            None => resource.creation_time()?,
+        };
+
+        Ok((data, modified))
+    }
+
+    /// Apply `patch` to the Secret of a resource type, or its history.
+    /// The Secret is created when missing, and deleted once empty.
+    async fn update(
+        &self,
+        resource_desc: &ResourceDesc,
+        history: bool,
+        secret: Option<Secret>,
+        patch: DataPatch,
+        modified: Option<BTreeMap<String, u64>>,
+    ) -> Result<()> {
+        let encode = |data: &Vec<u8>| STANDARD.encode(data);
+        let annotations: BTreeMap<String, String> = match &modified {
+            Some(modified) => BTreeMap::from([(
+                ANNOTATION_MODIFIED.to_string(),
+                serde_json::to_string(modified)?,
+            )]),
+            None => BTreeMap::new(),
+        };
+
+        let Some(secret) = secret else {
+            let data: BTreeMap<String, String> = patch
+                .iter()
+                .filter_map(|(key, data)| Some((key.clone(), encode(data.as_ref()?))))
+                .collect();
+            if data.is_empty() {
+                return Ok(());
+            }
+
+            let secret = json!({
+                "apiVersion": "v1",
+                "kind": "Secret",
+                "type": "Opaque",
+                "metadata": {
+                    "name": self.secret_name(resource_desc, history),
+                    "labels": Self::labels(resource_desc, history),
+                    "annotations": annotations,
+                },
+                "data": data,
+            });
+            let url = self.api.url(&self.namespace, None, None)?;
+            self.api.send(Method::POST, url, Some(secret)).await?;
+            return Ok(());
+        };
+
+        let name = &secret.metadata.name;
+        let empty = secret
+            .data
+            .keys()
+            .all(|key| matches!(patch.get(key), Some(None)))
+            && patch.values().all(Option::is_none);
+        if empty {
+            let url = self.api.url(&self.namespace, Some(name), None)?;
+            self.api.send(Method::DELETE, url, None).await?;
+            return Ok(());
+        }
+
+        // The resource version makes the patch fail on a concurrent update.
+        let data: Map<String, Value> = patch
+            .iter()
+            .map(|(key, data)| {
+                let value = data
+                    .as_ref()
+                    .map_or(Value::Null, |d| Value::String(encode(d)));
+                (key.clone(), value)
+            })
+            .collect();
+        let patch = json!({
+            "metadata": {
+                "resourceVersion": secret.metadata.resource_version,
+                "annotations": annotations,
+            },
+            "data": data,
+        });
+        let url = self.api.url(&self.namespace, Some(name), None)?;
+        self.api.send(Method::PATCH, url, Some(patch)).await?;
+        Ok(())
+    }
+}
+
+enum Token {
+    Static(String),
+    /// Token file, read on every request as it is rotated.
+    File(PathBuf),
+}
+
+/// Client of the Kubernetes API server.
+struct ApiClient {
+    server: Url,
+    token: Option<Token>,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    current_context: String,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+}
+
+#[derive(Deserialize)]
+struct NamedContext {
+    name: String,
+    context: KubeContext,
+}
+
+#[derive(Deserialize)]
+struct KubeContext {
+    cluster: String,
+    user: String,
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: Cluster,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Deserialize)]
+struct NamedUser {
+    name: String,
+    user: User,
+}
+
+#[derive(Deserialize)]
+struct User {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<PathBuf>,
+    #[serde(rename = "client-certificate")]
+    client_certificate: Option<PathBuf>,
+    #[serde(rename = "client-certificate-data")]
+    client_certificate_data: Option<String>,
+    #[serde(rename = "client-key")]
+    client_key: Option<PathBuf>,
+    #[serde(rename = "client-key-data")]
+    client_key_data: Option<String>,
+}
+
+/// Read a kubeconfig credential, inlined as base64 `data`, or from `path`,
+/// relative to the kubeconfig directory.
+fn kubeconfig_data(
+    dir: &Path,
+    data: &Option<String>,
+    path: &Option<PathBuf>,
+) -> Result<Option<Vec<u8>>> {
+    if let Some(data) = data {
+        return Ok(Some(STANDARD.decode(data.trim())?));
+    }
+
+    path.as_ref()
+        .map(|path| {
+            std::fs::read(dir.join(path)).with_context(|| format!("read {}", path.display()))
+        })
+        .transpose()
+}
+
+impl ApiClient {
+    /// API client of the KBS pod service account, and the pod namespace.
+    fn in_cluster() -> Result<(Self, Option<String>)> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").context(
+            "KUBERNETES_SERVICE_HOST is not set, no kubeconfig given outside of a cluster",
+        )?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".to_string());
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host,
+        };
+
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let ca = std::fs::read(dir.join("ca.crt")).context("read service account CA")?;
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .build()?;
+        let namespace = std::fs::read_to_string(dir.join("namespace"))
+            .ok()
+            .map(|namespace| namespace.trim().to_string());
+
+        Ok((
+            Self {
+                server: Url::parse(&format!("https://{host}:{port}"))?,
+                token: Some(Token::File(dir.join("token"))),
+                client,
+            },
+            namespace,
+        ))
+    }
+
+    /// API client of a kubeconfig current context, and its namespace.
+    fn from_kubeconfig(path: &Path) -> Result<(Self, Option<String>)> {
+        let kubeconfig: Kubeconfig = serde_yaml::from_slice(
+            &std::fs::read(path).with_context(|| format!("read kubeconfig {}", path.display()))?,
+        )
+        .context("parse kubeconfig")?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        let context = &kubeconfig
+            .contexts
+            .iter()
+            .find(|context| context.name == kubeconfig.current_context)
+            .with_context(|| format!("no {} context in kubeconfig", kubeconfig.current_context))?
+            .context;
+        let cluster = &kubeconfig
+            .clusters
+            .iter()
+            .find(|cluster| cluster.name == context.cluster)
+            .with_context(|| format!("no {} cluster in kubeconfig", context.cluster))?
+            .cluster;
+        let user = &kubeconfig
+            .users
+            .iter()
+            .find(|user| user.name == context.user)
+            .with_context(|| format!("no {} user in kubeconfig", context.user))?
+            .user;
+
+        let mut client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(cluster.insecure_skip_tls_verify);
+        if let Some(ca) = kubeconfig_data(
+            dir,
+            &cluster.certificate_authority_data,
+            &cluster.certificate_authority,
+        )? {
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+        }
+
+        let certificate =
+            kubeconfig_data(dir, &user.client_certificate_data, &user.client_certificate)?;
+        let key = kubeconfig_data(dir, &user.client_key_data, &user.client_key)?;
+        if let (Some(mut certificate), Some(key)) = (certificate, key) {
+            certificate.push(b'\n');
+            certificate.extend(key);
+            client = client.identity(reqwest::Identity::from_pem(&certificate)?);
+        }
+
+        let token = match (&user.token, &user.token_file) {
+            (Some(token), _) => Some(Token::Static(token.clone())),
+            (None, Some(token_file)) => Some(Token::File(dir.join(token_file))),
+            (None, None) => None,
+        };
+
+        Ok((
+            Self {
+                server: Url::parse(&cluster.server).context("parse kubernetes server")?,
+                token,
+                client: client.build()?,
+            },
+            context.namespace.clone(),
+        ))
+    }
+
+    /// URL of the namespace Secrets, or of one of them.
+    fn url(
+        &self,
+        namespace: &str,
+        name: Option<&str>,
+        label_selector: Option<&str>,
+    ) -> Result<Url> {
+        let mut url = self.server.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid kubernetes server"))?
+            .pop_if_empty()
+            .extend(["api", "v1", "namespaces", namespace, "secrets"])
+            .extend(name);
+        if let Some(label_selector) = label_selector {
+            url.query_pairs_mut()
+                .append_pair("labelSelector", label_selector);
+        }
+        Ok(url)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<Value>,
+    ) -> Result<reqwest::Response> {
+        let content_type = match method {
+            Method::PATCH => "application/merge-patch+json",
+            _ => "application/json",
+        };
+
+        let mut request = self.client.request(method, url);
+        match &self.token {
+            Some(Token::Static(token)) => request = request.bearer_auth(token),
+            Some(Token::File(path)) => {
+                let token = std::fs::read_to_string(path).context("read kubernetes token")?;
+                request = request.bearer_auth(token.trim());
+            }
+            None => (),
+        }
+        if let Some(body) = body {
+            request = request
+                .header(CONTENT_TYPE, content_type)
+                .body(body.to_string());
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| anyhow!("Kubernetes request failed: {:?}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            match status {
+                StatusCode::CONFLICT => bail!("Kubernetes secret concurrently modified: {body}"),
+                _ => bail!("Kubernetes request failed: status={status}, {body}"),
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::conformance::{repository_conformance_tests, serve};
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// Secrets of the in-process API server stand-in, by name.
+    type Secrets = Arc<Mutex<BTreeMap<String, Value>>>;
+
+    const NAMESPACE: &str = "coco-tenant";
+    const TOKEN: &str = "test-token";
+    const CREATION_TIMESTAMP: &str = "2023-09-01T08:00:00Z";
+
+    /// Apply a JSON merge patch, RFC 7386.
+    fn merge_patch(target: &mut Value, patch: &Value) {
+        let Value::Object(patch) = patch else {
+            *target = patch.clone();
+            return;
+        };
+        if !target.is_object() {
+            *target = json!({});
+        }
+
+        let target = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            match value {
+                Value::Null => {
+                    target.remove(key);
+                }
+                _ => merge_patch(target.entry(key.clone()).or_insert(Value::Null), value),
+            }
+        }
+    }
+
+    /// Check a Secret labels against a label selector of `key`, `!key` and
+    /// `key=value` requirements.
+    fn matches(secret: &Value, label_selector: &str) -> bool {
+        let labels = &secret["metadata"]["labels"];
+        label_selector.split(',').all(|requirement| {
+            match (requirement.strip_prefix('!'), requirement.split_once('=')) {
+                (Some(key), _) => labels.get(key).is_none(),
+                (None, Some((key, value))) => labels.get(key) == Some(&json!(value)),
+                (None, None) => labels.get(requirement).is_some(),
+            }
+        })
+    }
+
+    /// Minimal Kubernetes API server stand-in, serving the namespace Secrets.
+    async fn stand_in(
+        request: HttpRequest,
+        query: web::Query<HashMap<String, String>>,
+        body: web::Bytes,
+        secrets: web::Data<Secrets>,
+    ) -> HttpResponse {
+        let authorization = request
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        if authorization != Some(&format!("Bearer {TOKEN}")) {
+            return HttpResponse::Unauthorized().finish();
+        }
+
+        let prefix = format!("/api/v1/namespaces/{NAMESPACE}/secrets");
+        let Some(name) = request.path().strip_prefix(&prefix) else {
+            return HttpResponse::NotFound().finish();
+        };
+        let name = name.trim_start_matches('/');
+
+        let mut secrets = secrets.lock().unwrap();
+        match (request.method().as_str(), name) {
+            ("GET", "") => {
+                let label_selector = query.get("labelSelector").cloned().unwrap_or_default();
+                let items: Vec<&Value> = secrets
+                    .values()
+                    .filter(|secret| label_selector.is_empty() || matches(secret, &label_selector))
+                    .collect();
+                HttpResponse::Ok().json(json!({ "kind": "SecretList", "items": items }))
+            }
+            ("POST", "") => {
+                let mut secret: Value = serde_json::from_slice(&body).unwrap();
+                let name = secret["metadata"]["name"].as_str().unwrap().to_string();
+                if secrets.contains_key(&name) {
+                    return HttpResponse::Conflict().finish();
+                }
+                secret["metadata"]["resourceVersion"] = json!("1");
+                secret["metadata"]["creationTimestamp"] = json!(CREATION_TIMESTAMP);
+                secrets.insert(name, secret.clone());
+                HttpResponse::Created().json(secret)
+            }
+            ("PATCH", name) => {
+                let Some(secret) = secrets.get_mut(name) else {
+                    return HttpResponse::NotFound().finish();
+                };
+                let patch: Value = serde_json::from_slice(&body).unwrap();
+                let resource_version = &secret["metadata"]["resourceVersion"];
+                if patch["metadata"]["resourceVersion"] != *resource_version {
+                    return HttpResponse::Conflict().finish();
+                }
+                let next = resource_version.as_str().unwrap().parse::<u64>().unwrap() + 1;
+
+                merge_patch(secret, &patch);
+                secret["metadata"]["resourceVersion"] = json!(next.to_string());
+                HttpResponse::Ok().json(secret.clone())
+            }
+            ("DELETE", name) => match secrets.remove(name) {
+                Some(_) => HttpResponse::Ok().finish(),
+                None => HttpResponse::NotFound().finish(),
+            },
+            _ => HttpResponse::MethodNotAllowed().finish(),
+        }
+    }
+
+    /// Start an API server stand-in, returning its Secrets and a repository
+    /// using it through a kubeconfig file.
+    fn kubernetes_stand_in() -> ((TempDir, Secrets), Kubernetes) {
+        let secrets = Secrets::default();
+        let data = web::Data::new(secrets.clone());
+        let server = serve(move |config| {
+            config
+                .app_data(data.clone())
+                .default_service(web::to(stand_in));
+        });
+
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        std::fs::write(tmp_dir.path().join("token"), TOKEN).unwrap();
+        let kubeconfig = tmp_dir.path().join("kubeconfig");
+        std::fs::write(
+            &kubeconfig,
+            format!(
+                "apiVersion: v1
+kind: Config
+current-context: kind-kbs
+contexts:
+- name: kind-kbs
+  context:
+    cluster: kind-kbs
+    user: kbs
+    namespace: {NAMESPACE}
+clusters:
+- name: kind-kbs
+  cluster:
+    server: {server}
+users:
+- name: kbs
+  user:
+    tokenFile: token
+"
+            ),
+        )
+        .unwrap();
+
+        let kubernetes = Kubernetes::new(&KubernetesRepoDesc {
+            kubeconfig: Some(kubeconfig.to_string_lossy().to_string()),
+            ..Default::default()
+        })
+        .expect("create kubernetes repository failed");
+        ((tmp_dir, secrets), kubernetes)
+    }
+
+    fn resource_desc(tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: tag.into(),
+            version: None,
+        }
+    }
+
+    repository_conformance_tests!(kubernetes_stand_in());
+
+    #[tokio::test]
+    async fn secret_layout() {
+        let ((_tmp_dir, secrets), mut kubernetes) = kubernetes_stand_in();
+
+        for data in [b"v1", b"v2"] {
+            kubernetes
+                .write_secret_resource(resource_desc("1"), data)
+                .await
+                .expect("write secret resource failed");
+        }
+
+        let secrets = secrets.lock().unwrap();
+        let name = kubernetes.secret_name(&resource_desc("1"), false);
+        assert!(name.starts_with("kbs-default-key-"));
+        assert_eq!(
+            secrets.keys().collect::<Vec<_>>(),
+            vec![&name, &format!("{name}-history")]
+        );
+
+        let resource = &secrets[&name];
+        assert_eq!(resource["metadata"]["labels"][LABEL_REPOSITORY], "default");
+        assert_eq!(resource["metadata"]["labels"][LABEL_TYPE], "key");
+        assert_eq!(resource["data"], json!({ "1": STANDARD.encode("v2") }));
+
+        let history = &secrets[&format!("{name}-history")];
+        assert_eq!(history["metadata"]["labels"][LABEL_HISTORY], "true");
+        assert_eq!(
+            history["data"],
+            json!({ "1.1": STANDARD.encode("v1"), "1.2": STANDARD.encode("v2") })
+        );
+    }
+
+    #[tokio::test]
+    async fn gitops_secret() {
+        let ((_tmp_dir, secrets), mut kubernetes) = kubernetes_stand_in();
+
+        // A Secret managed by the operators, with its own name.
+        secrets.lock().unwrap().insert(
+            "workload-keys".into(),
+            json!({
+                "metadata": {
+                    "name": "workload-keys",
+                    "labels": { LABEL_REPOSITORY: "default", LABEL_TYPE: "key" },
+                    "resourceVersion": "1",
+                    "creationTimestamp": CREATION_TIMESTAMP,
+                },
+                "data": { "1": STANDARD.encode("v1") },
+            }),
+        );
+
+        assert_eq!(
+            kubernetes.list_repositories().await.unwrap(),
+            vec!["default"]
+        );
+        assert_eq!(
+            kubernetes
+                .list_resource_tags("default", "key")
+                .await
+                .unwrap(),
+            vec!["1"]
+        );
+        let metadata = kubernetes
+            .get_resource_metadata(resource_desc("1"))
+            .await
+            .unwrap();
+        assert_eq!(metadata.version, 1);
+        assert_eq!(metadata.modified, 1693555200);
+
+        kubernetes
+            .write_secret_resource(resource_desc("1"), b"v2")
+            .await
+            .expect("write secret resource failed");
+
+        // The operators Secret is updated in place.
+        let secret = secrets.lock().unwrap()["workload-keys"].clone();
+        assert_eq!(secret["data"]["1"], STANDARD.encode("v2"));
+        assert_eq!(
+            kubernetes
+                .read_secret_resource(ResourceDesc {
+                    version: Some(1),
+                    ..resource_desc("1")
+                })
+                .await
+                .unwrap(),
+            b"v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn history_retention() {
+        let ((_tmp_dir, secrets), mut kubernetes) = kubernetes_stand_in();
+        kubernetes.max_versions = 2;
+
+        for data in [b"v1", b"v2", b"v3"] {
+            kubernetes
+                .write_secret_resource(resource_desc("1"), data)
+                .await
+                .expect("write secret resource failed");
+        }
+
+        let versions: Vec<u64> = kubernetes
+            .list_resource_versions(resource_desc("1"))
+            .await
+            .unwrap()
+            .iter()
+            .map(|metadata| metadata.version)
+            .collect();
+        assert_eq!(versions, vec![2, 3]);
+
+        let name = kubernetes.secret_name(&resource_desc("1"), true);
+        let history = secrets.lock().unwrap()[&name].clone();
+        assert_eq!(
+            history["data"],
+            json!({ "1.2": STANDARD.encode("v2"), "1.3": STANDARD.encode("v3") })
+        );
+        let modified: BTreeMap<String, u64> = serde_json::from_str(
+            history["metadata"]["annotations"][ANNOTATION_MODIFIED]
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(modified.keys().collect::<Vec<_>>(), vec!["1.2", "1.3"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_label_value() {
+        let ((_tmp_dir, secrets), mut kubernetes) = kubernetes_stand_in();
+
+        for repository_name in ["default,kbs.confidentialcontainers.org/history", "-default"] {
+            let resource_desc = ResourceDesc {
+                repository_name: repository_name.into(),
+                ..resource_desc("1")
+            };
+            assert!(kubernetes
+                .write_secret_resource(resource_desc.clone(), b"v1")
+                .await
+                .is_err());
+            assert!(kubernetes
+                .read_secret_resource(resource_desc)
+                .await
+                .is_err());
+            assert!(kubernetes
+                .list_resource_types(repository_name)
+                .await
+                .is_err());
+        }
+        assert!(secrets.lock().unwrap().is_empty());
+    }
+}
@@ -2,10 +2,14 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::{Repository, ResourceDesc};
-use anyhow::{Context, Result};
+use super::{
+    Repository, ResourceAccess, ResourceDesc, ResourceLimits, ResourceMetadata, ResourceNotFound,
+};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
 
 pub const DEFAULT_REPO_DIR_PATH: &str = "/opt/confidential-containers/kbs/repository";
 
@@ -22,20 +26,31 @@ impl Default for LocalFsRepoDesc {
     }
 }
 
+/// Directory holding the resources history, in each resource type directory.
+///
+/// The latest version of a resource lives in `<repository>/<type>/<tag>`, and
+/// every version `N`, the latest included, in `<repository>/<type>/.versions/<tag>/N`.
+/// A resource file with no history, e.g. created before versioning was
+/// introduced, is its version 1.
+const VERSIONS_DIR: &str = ".versions";
+
+/// Directory holding the resources access limits and read counts, in each
+/// resource type directory, as `<repository>/<type>/.access/<tag>` JSON files.
+const ACCESS_DIR: &str = ".access";
+
 pub struct LocalFs {
     pub repo_dir_path: String,
+    /// Serializes the read count updates.
+    access_lock: Mutex<()>,
 }
 
 #[async_trait::async_trait]
 impl Repository for LocalFs {
     async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
-        let mut resource_path = PathBuf::from(&self.repo_dir_path);
-
-        let ref_resource_path = format!(
-            "{}/{}/{}",
-            resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
-        );
-        resource_path.push(ref_resource_path);
+        let resource_path = match resource_desc.version {
+            Some(version) => self.version_path(&resource_desc, version).await?,
+            None => self.resource_path(&resource_desc),
+        };
 
         let resource_byte = tokio::fs::read(&resource_path)
             .await
@@ -48,31 +63,334 @@ impl Repository for LocalFs {
         resource_desc: ResourceDesc,
         data: &[u8],
     ) -> Result<()> {
-        let mut resource_path = PathBuf::from(&self.repo_dir_path);
-        resource_path.push(resource_desc.repository_name);
-        resource_path.push(resource_desc.resource_type);
+        let resource_path = self.resource_path(&resource_desc);
+        let versions_path = self.versions_path(&resource_desc);
 
-        if !Path::new(&resource_path).exists() {
-            tokio::fs::create_dir_all(&resource_path)
+        // Record the resource file with no history as version 1, before
+        // adding the new version.
+        let legacy = !versions_path.exists() && resource_path.exists();
+        tokio::fs::create_dir_all(&versions_path)
+            .await
+            .context("create new resource path")?;
+        if legacy {
+            tokio::fs::copy(&resource_path, versions_path.join("1"))
                 .await
-                .context("create new resource path")?;
+                .context("record resource version 1")?;
         }
 
-        resource_path.push(resource_desc.resource_tag);
+        let version = self
+            .versions(&resource_desc)
+            .await?
+            .last()
+            .map_or(1, |latest| latest + 1);
 
+        tokio::fs::write(versions_path.join(version.to_string()), data)
+            .await
+            .context("write local fs")?;
         tokio::fs::write(resource_path, data)
             .await
             .context("write local fs")
     }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let version = resource_desc.version.context("no resource version")?;
+        let version_path = self.version_path(&resource_desc, version).await?;
+        tokio::fs::write(&version_path, data)
+            .await
+            .context("write local fs")?;
+
+        // Keep the latest version copy up to date.
+        let resource_path = self.resource_path(&resource_desc);
+        let latest = self.versions(&resource_desc).await?.last() == Some(&version);
+        if latest && version_path != resource_path {
+            tokio::fs::write(resource_path, data)
+                .await
+                .context("write local fs")?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        tokio::fs::remove_file(self.resource_path(&resource_desc))
+            .await
+            .context("delete resource from local fs")?;
+
+        let versions_path = self.versions_path(&resource_desc);
+        if versions_path.exists() {
+            tokio::fs::remove_dir_all(versions_path)
+                .await
+                .context("delete resource versions from local fs")?;
+        }
+
+        let access_path = self.access_path(&resource_desc);
+        if access_path.exists() {
+            tokio::fs::remove_file(access_path)
+                .await
+                .context("delete resource access from local fs")?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        let (version, resource_path) = match resource_desc.version {
+            Some(version) => (version, self.version_path(&resource_desc, version).await?),
+            None => (
+                *self
+                    .versions(&resource_desc)
+                    .await?
+                    .last()
+                    .ok_or(ResourceNotFound::Resource)?,
+                self.resource_path(&resource_desc),
+            ),
+        };
+
+        file_metadata(&resource_path, version).await
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        let versions = self.versions(&resource_desc).await?;
+        if versions.is_empty() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        let mut metadata = Vec::with_capacity(versions.len());
+        for version in versions {
+            let resource_path = self.version_path(&resource_desc, version).await?;
+            metadata.push(file_metadata(&resource_path, version).await?);
+        }
+
+        Ok(metadata)
+    }
+
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        let _guard = self.access_lock.lock().await;
+        let mut access = self.access(&resource_desc).await?;
+        access.limits.check(access.reads, unix_now()?)?;
+
+        let data = self.read_secret_resource(resource_desc.clone()).await?;
+        // Only the limited reads are counted, so that the resources with no
+        // limits can be served from a read-only directory.
+        if access.limits.max_reads.is_some() {
+            access.reads += 1;
+            self.write_access(&resource_desc, &access).await?;
+        }
+
+        Ok(data)
+    }
+
+    async fn get_resource_access(&self, resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        if !self.resource_path(&resource_desc).exists() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        self.access(&resource_desc).await
+    }
+
+    async fn set_resource_limits(
+        &mut self,
+        resource_desc: ResourceDesc,
+        limits: ResourceLimits,
+    ) -> Result<()> {
+        if !self.resource_path(&resource_desc).exists() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        let _guard = self.access_lock.lock().await;
+        self.write_access(&resource_desc, &ResourceAccess { limits, reads: 0 })
+            .await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        list_dir(PathBuf::from(&self.repo_dir_path), true).await
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        let mut path = PathBuf::from(&self.repo_dir_path);
+        path.push(repository_name);
+        if !path.is_dir() {
+            bail!(ResourceNotFound::Repository(repository_name.to_string()));
+        }
+        list_dir(path, true).await
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        let mut path = PathBuf::from(&self.repo_dir_path);
+        path.push(repository_name);
+        path.push(resource_type);
+        if !path.is_dir() {
+            bail!(ResourceNotFound::ResourceType(format!(
+                "{repository_name}/{resource_type}"
+            )));
+        }
+        list_dir(path, false).await
+    }
+}
+
+/// List the sorted names of the sub-directories (`dirs` is true) or files of
+/// a directory.
+async fn list_dir(path: PathBuf, dirs: bool) -> Result<Vec<String>> {
+    let mut entries = tokio::fs::read_dir(&path)
+        .await
+        .with_context(|| format!("list local fs directory {}", path.display()))?;
+
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() != dirs {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+async fn file_metadata(path: &Path, version: u64) -> Result<ResourceMetadata> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .context("read resource metadata from local fs")?;
+    let data = tokio::fs::read(path)
+        .await
+        .context("read resource from local fs")?;
+
+    Ok(ResourceMetadata {
+        version,
+        size: metadata.len(),
+        // Not all the filesystems record the creation time.
+        created: metadata.created().ok().and_then(unix_time),
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(unix_time)
+            .context("read resource modification time")?,
+        sha256: ResourceMetadata::content_digest(&data),
+    })
+}
+
+fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn unix_now() -> Result<u64> {
+    unix_time(SystemTime::now()).context("read current time")
 }
 
 impl LocalFs {
+    fn resource_path(&self, resource_desc: &ResourceDesc) -> PathBuf {
+        let mut resource_path = PathBuf::from(&self.repo_dir_path);
+        resource_path.push(&resource_desc.repository_name);
+        resource_path.push(&resource_desc.resource_type);
+        resource_path.push(&resource_desc.resource_tag);
+        resource_path
+    }
+
+    fn versions_path(&self, resource_desc: &ResourceDesc) -> PathBuf {
+        let mut versions_path = PathBuf::from(&self.repo_dir_path);
+        versions_path.push(&resource_desc.repository_name);
+        versions_path.push(&resource_desc.resource_type);
+        versions_path.push(VERSIONS_DIR);
+        versions_path.push(&resource_desc.resource_tag);
+        versions_path
+    }
+
+    fn access_path(&self, resource_desc: &ResourceDesc) -> PathBuf {
+        let mut access_path = PathBuf::from(&self.repo_dir_path);
+        access_path.push(&resource_desc.repository_name);
+        access_path.push(&resource_desc.resource_type);
+        access_path.push(ACCESS_DIR);
+        access_path.push(&resource_desc.resource_tag);
+        access_path
+    }
+
+    /// Access limits and read count of a resource, none if not recorded yet.
+    async fn access(&self, resource_desc: &ResourceDesc) -> Result<ResourceAccess> {
+        let access_path = self.access_path(resource_desc);
+        if !access_path.exists() {
+            return Ok(ResourceAccess::default());
+        }
+
+        let access = tokio::fs::read(&access_path)
+            .await
+            .context("read resource access from local fs")?;
+        serde_json::from_slice(&access).context("illegal resource access")
+    }
+
+    async fn write_access(
+        &self,
+        resource_desc: &ResourceDesc,
+        access: &ResourceAccess,
+    ) -> Result<()> {
+        let access_path = self.access_path(resource_desc);
+        if let Some(parent) = access_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("create resource access path")?;
+        }
+
+        // Replace the file at once, so that a crash cannot truncate it.
+        let tmp_path = access_path.with_file_name(format!(".{}.tmp", resource_desc.resource_tag));
+        tokio::fs::write(&tmp_path, serde_json::to_vec(access)?)
+            .await
+            .context("write resource access to local fs")?;
+        tokio::fs::rename(&tmp_path, &access_path)
+            .await
+            .context("write resource access to local fs")
+    }
+
+    /// Sorted versions of a resource, empty if the resource does not exist.
+    async fn versions(&self, resource_desc: &ResourceDesc) -> Result<Vec<u64>> {
+        let versions_path = self.versions_path(resource_desc);
+        if !versions_path.exists() {
+            return Ok(match self.resource_path(resource_desc).exists() {
+                true => vec![1],
+                false => Vec::new(),
+            });
+        }
+
+        let mut versions: Vec<u64> = list_dir(versions_path, false)
+            .await?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        versions.sort_unstable();
+
+        Ok(versions)
+    }
+
+    async fn version_path(&self, resource_desc: &ResourceDesc, version: u64) -> Result<PathBuf> {
+        if !self.versions(resource_desc).await?.contains(&version) {
+            bail!(ResourceNotFound::Version(version));
+        }
+
+        let versions_path = self.versions_path(resource_desc);
+        match versions_path.exists() {
+            true => Ok(versions_path.join(version.to_string())),
+            false => Ok(self.resource_path(resource_desc)),
+        }
+    }
+
     pub fn new(repo_desc: &LocalFsRepoDesc) -> Result<Self> {
         Ok(Self {
             repo_dir_path: repo_desc
                 .dir_path
                 .clone()
                 .unwrap_or(DEFAULT_REPO_DIR_PATH.to_string()),
+            access_lock: Mutex::new(()),
         })
     }
 }
@@ -80,35 +398,101 @@ impl LocalFs {
 #[cfg(test)]
 mod tests {
     use crate::resource::{
-        local_fs::{LocalFs, LocalFsRepoDesc},
+        conformance::{repository_conformance_tests, resource_access_conformance_tests},
+        local_fs::{LocalFs, LocalFsRepoDesc, ACCESS_DIR},
         Repository, ResourceDesc,
     };
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+    use tempfile::TempDir;
 
-    const TEST_DATA: &[u8] = b"testdata";
-
-    #[tokio::test]
-    async fn write_and_read_resource() {
+    fn local_fs() -> (TempDir, LocalFs) {
         let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
         let repo_desc = LocalFsRepoDesc {
             dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
         };
 
-        let mut local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        let local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        (tmp_dir, local_fs)
+    }
+
+    repository_conformance_tests!(local_fs());
+
+    resource_access_conformance_tests!(local_fs());
+
+    #[tokio::test]
+    async fn legacy_resource_version() {
+        let (tmp_dir, mut local_fs) = local_fs();
+
+        // A resource file with no history is the version 1.
+        std::fs::create_dir_all(tmp_dir.path().join("default/test")).unwrap();
+        std::fs::write(tmp_dir.path().join("default/test/test"), b"v1").unwrap();
+
         let resource_desc = ResourceDesc {
             repository_name: "default".into(),
             resource_type: "test".into(),
             resource_tag: "test".into(),
+            version: None,
         };
+        let version = |version| ResourceDesc {
+            version: Some(version),
+            ..resource_desc.clone()
+        };
+
+        assert_eq!(
+            local_fs.read_secret_resource(version(1)).await.unwrap(),
+            b"v1"
+        );
+        assert_eq!(
+            local_fs
+                .get_resource_metadata(resource_desc.clone())
+                .await
+                .unwrap()
+                .version,
+            1
+        );
 
         local_fs
-            .write_secret_resource(resource_desc.clone(), TEST_DATA)
+            .write_secret_resource(resource_desc.clone(), b"v2")
             .await
             .expect("write secret resource failed");
-        let data = local_fs
-            .read_secret_resource(resource_desc)
-            .await
-            .expect("read secret resource failed");
 
-        assert_eq!(&data[..], TEST_DATA);
+        assert_eq!(
+            local_fs
+                .read_secret_resource(resource_desc.clone())
+                .await
+                .unwrap(),
+            b"v2"
+        );
+        assert_eq!(
+            local_fs.read_secret_resource(version(1)).await.unwrap(),
+            b"v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_release() {
+        let (tmp_dir, local_fs) = local_fs();
+
+        // A resource mounted read-only, e.g. from a Kubernetes Secret.
+        let type_path = tmp_dir.path().join("default/test");
+        std::fs::create_dir_all(&type_path).unwrap();
+        std::fs::write(type_path.join("test"), b"testdata").unwrap();
+        std::fs::set_permissions(&type_path, Permissions::from_mode(0o555)).unwrap();
+
+        let released = local_fs
+            .release_secret_resource(ResourceDesc {
+                repository_name: "default".into(),
+                resource_type: "test".into(),
+                resource_tag: "test".into(),
+                version: None,
+            })
+            .await;
+        std::fs::set_permissions(&type_path, Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(
+            released.expect("release secret resource failed"),
+            b"testdata"
+        );
+        assert!(!type_path.join(ACCESS_DIR).exists());
     }
 }
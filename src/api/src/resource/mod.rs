@@ -3,29 +3,258 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::*;
+pub use derived::KeyDerivationConfig;
+use encryption::EncryptedRepository;
+pub use encryption::RepositoryEncryptionConfig;
+pub use generated::GeneratedRepoDesc;
+use generated::GeneratedRepository;
+#[cfg(feature = "kubernetes")]
+use kubernetes::Kubernetes;
+#[cfg(feature = "kubernetes")]
+pub use kubernetes::KubernetesRepoDesc;
 use local_fs::LocalFs;
 pub use local_fs::LocalFsRepoDesc;
-use serde::Deserialize;
+pub use routed::RoutedRepoDesc;
+use routed::RoutedRepository;
+#[cfg(feature = "s3")]
+pub use s3::S3RepoDesc;
+#[cfg(feature = "s3")]
+use s3::S3;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "sqlite")]
+use sqlite::Sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteRepoDesc;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 use std::sync::Arc;
 use strum_macros::EnumString;
 use tokio::sync::RwLock;
+#[cfg(feature = "vault")]
+use vault::Vault;
+#[cfg(feature = "vault")]
+pub use vault::VaultRepoDesc;
 
+#[cfg(test)]
+mod conformance;
+mod derived;
+mod encryption;
+mod generated;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
 mod local_fs;
+mod routed;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "vault")]
+mod vault;
 
 /// Interface of a `Repository`.
 #[async_trait::async_trait]
 pub trait Repository {
     /// Read secret resource from repository.
+    /// The latest version is read, unless `resource_desc` selects a version.
     async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>>;
 
-    /// Write secret resource into repository
+    /// Write secret resource into repository.
+    /// Every write creates a new version of the resource, the previous
+    /// versions are kept. The `resource_desc` version is ignored.
     async fn write_secret_resource(
         &mut self,
         resource_desc: ResourceDesc,
         data: &[u8],
     ) -> Result<()>;
+
+    /// Replace the content of an existing version of a secret resource, selected
+    /// by `resource_desc`, without creating a new version. This is only meant
+    /// to re-encode the stored resources, e.g. to re-encrypt them.
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()>;
+
+    /// Delete secret resource, with all its versions, from repository.
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()>;
+
+    /// Get the metadata of a secret resource.
+    /// The latest version is inspected, unless `resource_desc` selects a version.
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata>;
+
+    /// List the metadata of all the versions of a secret resource, oldest first.
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>>;
+
+    /// Roll a secret resource back to `version`, by writing the content of
+    /// that version as a new version. Returns the new version.
+    async fn rollback_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        version: u64,
+    ) -> Result<u64> {
+        let data = self
+            .read_secret_resource(ResourceDesc {
+                version: Some(version),
+                ..resource_desc.clone()
+            })
+            .await?;
+        self.write_secret_resource(resource_desc.clone(), &data)
+            .await?;
+
+        let metadata = self
+            .get_resource_metadata(ResourceDesc {
+                version: None,
+                ..resource_desc
+            })
+            .await?;
+        Ok(metadata.version)
+    }
+
+    /// Read a secret resource to release it to a requester, enforcing its
+    /// access limits and counting the read atomically. Repositories which do
+    /// not support access limits only read the resource.
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        self.read_secret_resource(resource_desc).await
+    }
+
+    /// Get the access limits and the read count of a secret resource.
+    async fn get_resource_access(&self, _resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        bail!("resource access limits are not supported by this repository")
+    }
+
+    /// Set the access limits of a secret resource, and reset its read count.
+    async fn set_resource_limits(
+        &mut self,
+        _resource_desc: ResourceDesc,
+        _limits: ResourceLimits,
+    ) -> Result<()> {
+        bail!("resource access limits are not supported by this repository")
+    }
+
+    /// List the repository names.
+    async fn list_repositories(&self) -> Result<Vec<String>>;
+
+    /// List the resource types of a repository.
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>>;
+
+    /// List the resource tags of a repository resource type.
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>>;
+}
+
+/// Metadata of a secret resource, as returned by the admin API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ResourceMetadata {
+    /// Resource version, starting from 1.
+    pub version: u64,
+
+    /// Resource size, in bytes.
+    pub size: u64,
+
+    /// Creation time, in seconds since the UNIX epoch, when the repository
+    /// keeps track of it.
+    pub created: Option<u64>,
+
+    /// Last modification time, in seconds since the UNIX epoch.
+    pub modified: u64,
+
+    /// Hex encoded SHA-256 digest of the resource content.
+    pub sha256: String,
+}
+
+impl ResourceMetadata {
+    pub(crate) fn content_digest(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+/// Access limits of a secret resource, enforced when it is released.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    /// Number of times the resource can be read, unlimited when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_reads: Option<u64>,
+
+    /// Time from which the resource can be read, in seconds since the UNIX
+    /// epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+
+    /// Time after which the resource cannot be read anymore, in seconds since
+    /// the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Check that a resource already read `reads` times can be read `now`.
+    pub(crate) fn check(&self, reads: u64, now: u64) -> Result<(), ResourceUnavailable> {
+        if let Some(not_before) = self.not_before.filter(|not_before| now < *not_before) {
+            return Err(ResourceUnavailable::NotYetValid(not_before));
+        }
+        if let Some(not_after) = self.not_after.filter(|not_after| now > *not_after) {
+            return Err(ResourceUnavailable::Expired(not_after));
+        }
+        if self.max_reads.is_some_and(|max_reads| reads >= max_reads) {
+            return Err(ResourceUnavailable::Exhausted(reads));
+        }
+
+        // `anyhow::Ok` would force an `anyhow::Error`.
+        std::result::Result::Ok(())
+    }
+}
+
+/// Access limits of a secret resource, and its read count, as returned by
+/// the admin API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceAccess {
+    #[serde(flatten)]
+    pub limits: ResourceLimits,
+
+    /// Number of times the resource was released.
+    #[serde(default)]
+    pub reads: u64,
+}
+
+/// A secret resource cannot be released because of its access limits.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ResourceUnavailable {
+    #[error("resource is exhausted, it was already read {0} times")]
+    Exhausted(u64),
+
+    #[error("resource is not available before {0}")]
+    NotYetValid(u64),
+
+    #[error("resource expired at {0}")]
+    Expired(u64),
+}
+
+/// A repository, resource type, resource or resource version does not exist.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ResourceNotFound {
+    #[error("repository {0} not found")]
+    Repository(String),
+
+    #[error("resource type {0} not found")]
+    ResourceType(String),
+
+    #[error("resource not found")]
+    Resource,
+
+    #[error("resource version {0} not found")]
+    Version(u64),
 }
 
 #[derive(Debug, Clone)]
@@ -33,29 +262,79 @@ pub struct ResourceDesc {
     pub repository_name: String,
     pub resource_type: String,
     pub resource_tag: String,
+    /// Resource version, `None` for the latest one.
+    pub version: Option<u64>,
 }
 
 impl ResourceDesc {
     pub fn is_valid(&self) -> bool {
-        if &self.repository_name == "."
-            || &self.repository_name == ".."
-            || &self.resource_type == "."
-            || &self.resource_type == ".."
-        {
-            return false;
-        }
-        true
+        is_valid_path_component(&self.repository_name)
+            && is_valid_path_component(&self.resource_type)
+            && is_valid_path_component(&self.resource_tag)
     }
 }
 
+/// Check that a repository name, resource type or tag cannot escape its
+/// parent in a path based repository. The names starting with `.` are
+/// reserved to the repositories, e.g. for the `.versions` and `.access`
+/// directories of `LocalFs`.
+pub(crate) fn is_valid_path_component(component: &str) -> bool {
+    !component.is_empty() && !component.starts_with('.')
+}
+
 #[derive(Clone, Debug, Deserialize, EnumString)]
 #[serde(tag = "type")]
 pub enum RepositoryConfig {
     LocalFs(local_fs::LocalFsRepoDesc),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteRepoDesc),
+    #[cfg(feature = "s3")]
+    S3(S3RepoDesc),
+    #[cfg(feature = "vault")]
+    Vault(VaultRepoDesc),
+    #[cfg(feature = "kubernetes")]
+    Kubernetes(KubernetesRepoDesc),
+    Routed(RoutedRepoDesc),
+    Generated(GeneratedRepoDesc),
 }
 
 impl RepositoryConfig {
-    pub fn initialize(&self) -> Result<Arc<RwLock<dyn Repository + Send + Sync>>> {
+    pub async fn initialize(
+        &self,
+        encryption_config: Option<&RepositoryEncryptionConfig>,
+    ) -> Result<Arc<RwLock<dyn Repository + Send + Sync>>> {
+        let Self::Routed(desc) = self else {
+            return self.initialize_backend(encryption_config).await;
+        };
+
+        let mut routes = BTreeMap::new();
+        for (repository_name, config) in &desc.routes {
+            let backend = config
+                .initialize_backend(encryption_config)
+                .await
+                .with_context(|| format!("initialize repository {repository_name} backend"))?;
+            routes.insert(repository_name.clone(), backend);
+        }
+        let fallback = match &desc.fallback {
+            Some(config) => Some(
+                config
+                    .initialize_backend(encryption_config)
+                    .await
+                    .context("initialize fallback repository backend")?,
+            ),
+            None => None,
+        };
+
+        Ok(
+            Arc::new(RwLock::new(RoutedRepository::new(routes, fallback)))
+                as Arc<RwLock<dyn Repository + Send + Sync>>,
+        )
+    }
+
+    async fn initialize_backend(
+        &self,
+        encryption_config: Option<&RepositoryEncryptionConfig>,
+    ) -> Result<Arc<RwLock<dyn Repository + Send + Sync>>> {
         match self {
             Self::LocalFs(desc) => {
                 // Create repository dir.
@@ -72,13 +351,60 @@ impl RepositoryConfig {
                     fs::create_dir_all(format!("{}/default", &dir_path))?;
                 }
 
-                Ok(Arc::new(RwLock::new(LocalFs::new(desc)?))
+                with_encryption(LocalFs::new(desc)?, encryption_config).await
+            }
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(desc) => with_encryption(Sqlite::new(desc)?, encryption_config).await,
+            #[cfg(feature = "s3")]
+            Self::S3(desc) => with_encryption(S3::new(desc)?, encryption_config).await,
+            #[cfg(feature = "vault")]
+            Self::Vault(desc) => {
+                // The KV v2 secret versions cannot be re-written once created.
+                if matches!(encryption_config, Some(config) if config.rewrap) {
+                    bail!("`rewrap` of the repository encryption is not supported with the Vault backend");
+                }
+                with_encryption(Vault::new(desc)?, encryption_config).await
+            }
+            #[cfg(feature = "kubernetes")]
+            Self::Kubernetes(desc) => {
+                with_encryption(Kubernetes::new(desc)?, encryption_config).await
+            }
+            Self::Generated(desc) => {
+                let backend = Box::pin(desc.backend.initialize_backend(encryption_config))
+                    .await
+                    .context("initialize generated resources backend")?;
+                Ok(Arc::new(RwLock::new(GeneratedRepository::new(
+                    backend,
+                    desc.generators.clone(),
+                )?))
                     as Arc<RwLock<dyn Repository + Send + Sync>>)
             }
+            Self::Routed(_) => bail!("repository routes cannot be nested"),
         }
     }
 }
 
+/// Wrap `repository` to encrypt its resources at rest, when configured.
+async fn with_encryption<R: Repository + Send + Sync + 'static>(
+    repository: R,
+    encryption_config: Option<&RepositoryEncryptionConfig>,
+) -> Result<Arc<RwLock<dyn Repository + Send + Sync>>> {
+    let Some(encryption_config) = encryption_config else {
+        return Ok(Arc::new(RwLock::new(repository)) as Arc<RwLock<dyn Repository + Send + Sync>>);
+    };
+
+    let mut repository = EncryptedRepository::new(Box::new(repository), encryption_config)?;
+    if encryption_config.rewrap {
+        let updated = repository
+            .rewrap()
+            .await
+            .context("re-wrap the repository resources")?;
+        log::info!("Re-wrapped {updated} resource versions with the current KEK");
+    }
+
+    Ok(Arc::new(RwLock::new(repository)) as Arc<RwLock<dyn Repository + Send + Sync>>)
+}
+
 impl Default for RepositoryConfig {
     fn default() -> Self {
         Self::LocalFs(local_fs::LocalFsRepoDesc::default())
@@ -96,3 +422,63 @@ pub(crate) async fn set_secret_resource(
         .write_secret_resource(resource_desc, data)
         .await
 }
+
+pub(crate) async fn delete_secret_resource(
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    resource_desc: ResourceDesc,
+) -> Result<()> {
+    repository
+        .write()
+        .await
+        .delete_secret_resource(resource_desc)
+        .await
+}
+
+pub(crate) async fn rollback_secret_resource(
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    resource_desc: ResourceDesc,
+    version: u64,
+) -> Result<u64> {
+    repository
+        .write()
+        .await
+        .rollback_secret_resource(resource_desc, version)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("key", true)]
+    #[case("key.pem", true)]
+    #[case("", false)]
+    #[case(".", false)]
+    #[case("..", false)]
+    #[case(".versions", false)]
+    #[case(".access", false)]
+    fn path_component(#[case] component: &str, #[case] valid: bool) {
+        assert_eq!(is_valid_path_component(component), valid);
+    }
+
+    #[cfg(feature = "vault")]
+    #[tokio::test]
+    async fn vault_rewrap_rejected() {
+        let encryption_config = RepositoryEncryptionConfig {
+            kek: encryption::KekConfig::KeyFile {
+                id: "kek-1".to_string(),
+                path: "/nonexistent/kek".into(),
+            },
+            previous_keks: Vec::new(),
+            rewrap: true,
+        };
+        let error = RepositoryConfig::Vault(VaultRepoDesc::default())
+            .initialize(Some(&encryption_config))
+            .await
+            .err()
+            .expect("Vault repository initialized with rewrap");
+        assert!(error.to_string().contains("`rewrap`"));
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{
+    Repository, RepositoryConfig, ResourceAccess, ResourceDesc, ResourceLimits, ResourceMetadata,
+    ResourceNotFound,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+type Backend = Arc<RwLock<dyn Repository + Send + Sync>>;
+
+/// Backends of the repositories, by repository name.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RoutedRepoDesc {
+    #[serde(default)]
+    pub routes: BTreeMap<String, RepositoryConfig>,
+    /// Backend of the repositories with no route. Without a fallback, the
+    /// repositories with no route cannot be accessed.
+    pub fallback: Option<Box<RepositoryConfig>>,
+}
+
+/// Repository dispatching every request to the backend of its repository
+/// name. A backend keeps the resources under their repository name.
+pub struct RoutedRepository {
+    routes: BTreeMap<String, Backend>,
+    fallback: Option<Backend>,
+}
+
+#[async_trait::async_trait]
+impl Repository for RoutedRepository {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        self.route(&resource_desc.repository_name)?
+            .read()
+            .await
+            .read_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.route(&resource_desc.repository_name)?
+            .write()
+            .await
+            .write_secret_resource(resource_desc, data)
+            .await
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.route(&resource_desc.repository_name)?
+            .write()
+            .await
+            .replace_secret_resource_version(resource_desc, data)
+            .await
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        self.route(&resource_desc.repository_name)?
+            .write()
+            .await
+            .delete_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        self.route(&resource_desc.repository_name)?
+            .read()
+            .await
+            .get_resource_metadata(resource_desc)
+            .await
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        self.route(&resource_desc.repository_name)?
+            .read()
+            .await
+            .list_resource_versions(resource_desc)
+            .await
+    }
+
+    async fn rollback_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        version: u64,
+    ) -> Result<u64> {
+        self.route(&resource_desc.repository_name)?
+            .write()
+            .await
+            .rollback_secret_resource(resource_desc, version)
+            .await
+    }
+
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        self.route(&resource_desc.repository_name)?
+            .read()
+            .await
+            .release_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn get_resource_access(&self, resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        self.route(&resource_desc.repository_name)?
+            .read()
+            .await
+            .get_resource_access(resource_desc)
+            .await
+    }
+
+    async fn set_resource_limits(
+        &mut self,
+        resource_desc: ResourceDesc,
+        limits: ResourceLimits,
+    ) -> Result<()> {
+        self.route(&resource_desc.repository_name)?
+            .write()
+            .await
+            .set_resource_limits(resource_desc, limits)
+            .await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        // Only list the repositories of a backend that are routed to it.
+        let mut repositories = Vec::new();
+        for (name, backend) in &self.routes {
+            let backend_repositories = backend.read().await.list_repositories().await?;
+            if backend_repositories.contains(name) {
+                repositories.push(name.clone());
+            }
+        }
+        if let Some(fallback) = &self.fallback {
+            let fallback_repositories = fallback.read().await.list_repositories().await?;
+            repositories.extend(
+                fallback_repositories
+                    .into_iter()
+                    .filter(|name| !self.routes.contains_key(name)),
+            );
+        }
+        repositories.sort();
+
+        Ok(repositories)
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        self.route(repository_name)?
+            .read()
+            .await
+            .list_resource_types(repository_name)
+            .await
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        self.route(repository_name)?
+            .read()
+            .await
+            .list_resource_tags(repository_name, resource_type)
+            .await
+    }
+}
+
+impl RoutedRepository {
+    pub fn new(routes: BTreeMap<String, Backend>, fallback: Option<Backend>) -> Self {
+        Self { routes, fallback }
+    }
+
+    fn route(&self, repository_name: &str) -> Result<&Backend> {
+        self.routes
+            .get(repository_name)
+            .or(self.fallback.as_ref())
+            .ok_or_else(|| ResourceNotFound::Repository(repository_name.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{
+        conformance::{repository_conformance_tests, resource_access_conformance_tests},
+        local_fs::{LocalFs, LocalFsRepoDesc},
+    };
+    use tempfile::TempDir;
+
+    fn local_fs(tmp_dir: &TempDir) -> Backend {
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+        };
+        Arc::new(RwLock::new(
+            LocalFs::new(&repo_desc).expect("create local fs failed"),
+        ))
+    }
+
+    /// A repository routing `default` and `prod` to their own backend, the
+    /// other repositories to the fallback one.
+    fn routed_repository() -> ([TempDir; 3], RoutedRepository) {
+        let tmp_dirs = [(); 3].map(|_| tempfile::tempdir().expect("create temp dir failed"));
+        let routes = BTreeMap::from([
+            ("default".to_string(), local_fs(&tmp_dirs[0])),
+            ("prod".to_string(), local_fs(&tmp_dirs[1])),
+        ]);
+
+        let repository = RoutedRepository::new(routes, Some(local_fs(&tmp_dirs[2])));
+        (tmp_dirs, repository)
+    }
+
+    fn resource_desc(repository_name: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: repository_name.into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+            version: None,
+        }
+    }
+
+    repository_conformance_tests!(routed_repository());
+
+    resource_access_conformance_tests!(routed_repository());
+
+    #[tokio::test]
+    async fn route_repositories() {
+        let (tmp_dirs, mut repository) = routed_repository();
+
+        for repository_name in ["default", "prod", "archive"] {
+            repository
+                .write_secret_resource(resource_desc(repository_name), repository_name.as_bytes())
+                .await
+                .expect("write secret resource failed");
+        }
+
+        assert!(tmp_dirs[0].path().join("default/key/1").exists());
+        assert!(tmp_dirs[1].path().join("prod/key/1").exists());
+        assert!(tmp_dirs[2].path().join("archive/key/1").exists());
+        assert_eq!(
+            repository
+                .read_secret_resource(resource_desc("prod"))
+                .await
+                .unwrap(),
+            b"prod"
+        );
+
+        // A backend repository which is routed elsewhere is not listed.
+        std::fs::create_dir_all(tmp_dirs[2].path().join("prod/key")).unwrap();
+        assert_eq!(
+            repository.list_repositories().await.unwrap(),
+            vec!["archive", "default", "prod"]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_fallback() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let mut repository = RoutedRepository::new(
+            BTreeMap::from([("default".to_string(), local_fs(&tmp_dir))]),
+            None,
+        );
+
+        assert!(repository
+            .write_secret_resource(resource_desc("archive"), b"archive")
+            .await
+            .is_err());
+        assert!(repository.list_resource_types("archive").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn initialize_routes() {
+        let tmp_dirs = [(); 2].map(|_| tempfile::tempdir().expect("create temp dir failed"));
+        let config: RepositoryConfig = serde_json::from_value(serde_json::json!({
+            "type": "Routed",
+            "routes": {
+                "prod": {"type": "LocalFs", "dir_path": tmp_dirs[0].path()},
+            },
+            "fallback": {"type": "LocalFs", "dir_path": tmp_dirs[1].path()},
+        }))
+        .expect("parse routed repository config failed");
+        let repository = config
+            .initialize(None)
+            .await
+            .expect("initialize routed repository failed");
+
+        for repository_name in ["prod", "default"] {
+            repository
+                .write()
+                .await
+                .write_secret_resource(resource_desc(repository_name), b"testdata")
+                .await
+                .expect("write secret resource failed");
+        }
+        assert!(tmp_dirs[0].path().join("prod/key/1").exists());
+        assert!(tmp_dirs[1].path().join("default/key/1").exists());
+
+        let nested: RepositoryConfig = serde_json::from_value(serde_json::json!({
+            "type": "Routed",
+            "fallback": {"type": "Routed"},
+        }))
+        .expect("parse routed repository config failed");
+        assert!(nested.initialize(None).await.is_err());
+    }
+}
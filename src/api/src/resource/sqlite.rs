@@ -0,0 +1,542 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{
+    Repository, ResourceAccess, ResourceDesc, ResourceLimits, ResourceMetadata, ResourceNotFound,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_DB_PATH: &str = "/opt/confidential-containers/kbs/repository.db";
+
+/// Every version of every resource is a row of the `resources` table, and
+/// the access limits and read count of a resource a row of the
+/// `resource_access` table.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS resources (
+    repository TEXT NOT NULL,
+    type TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    sha256 TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    PRIMARY KEY (repository, type, tag, version)
+);
+CREATE TABLE IF NOT EXISTS resource_access (
+    repository TEXT NOT NULL,
+    type TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    max_reads INTEGER,
+    not_before INTEGER,
+    not_after INTEGER,
+    reads INTEGER NOT NULL,
+    PRIMARY KEY (repository, type, tag)
+);";
+
+const METADATA_COLUMNS: &str = "version, length(data), sha256, created, modified";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SqliteRepoDesc {
+    pub path: Option<String>,
+}
+
+impl Default for SqliteRepoDesc {
+    fn default() -> Self {
+        Self {
+            path: Some(DEFAULT_DB_PATH.to_string()),
+        }
+    }
+}
+
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+#[async_trait::async_trait]
+impl Repository for Sqlite {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        self.call(move |connection| read_data(connection, &resource_desc))
+            .await
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let data = data.to_vec();
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let now = now()?;
+
+            let transaction = connection.transaction()?;
+            let version: u64 = transaction.query_row(
+                "SELECT COALESCE(MAX(version), 0) + 1 FROM resources
+                 WHERE repository = ?1 AND type = ?2 AND tag = ?3",
+                params![repository, resource_type, tag],
+                |row| row.get(0),
+            )?;
+            transaction
+                .execute(
+                    "INSERT INTO resources
+                     (repository, type, tag, version, data, sha256, created, modified)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+                    params![
+                        repository,
+                        resource_type,
+                        tag,
+                        version,
+                        data,
+                        ResourceMetadata::content_digest(&data),
+                        now
+                    ],
+                )
+                .context("write sqlite")?;
+            transaction.commit().context("write sqlite")
+        })
+        .await
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        let version = resource_desc.version.context("no resource version")?;
+        let data = data.to_vec();
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let updated = connection
+                .execute(
+                    "UPDATE resources SET data = ?5, sha256 = ?6, modified = ?7
+                     WHERE repository = ?1 AND type = ?2 AND tag = ?3 AND version = ?4",
+                    params![
+                        repository,
+                        resource_type,
+                        tag,
+                        version,
+                        data,
+                        ResourceMetadata::content_digest(&data),
+                        now()?
+                    ],
+                )
+                .context("write sqlite")?;
+            if updated == 0 {
+                bail!(ResourceNotFound::Version(version));
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let transaction = connection.transaction()?;
+            let deleted = transaction
+                .execute(
+                    "DELETE FROM resources WHERE repository = ?1 AND type = ?2 AND tag = ?3",
+                    params![repository, resource_type, tag],
+                )
+                .context("delete resource from sqlite")?;
+            if deleted == 0 {
+                bail!(ResourceNotFound::Resource);
+            }
+            transaction
+                .execute(
+                    "DELETE FROM resource_access WHERE repository = ?1 AND type = ?2 AND tag = ?3",
+                    params![repository, resource_type, tag],
+                )
+                .context("delete resource access from sqlite")?;
+
+            transaction.commit().context("delete resource from sqlite")
+        })
+        .await
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let metadata = match resource_desc.version {
+                Some(version) => connection
+                    .query_row(
+                        &format!(
+                            "SELECT {METADATA_COLUMNS} FROM resources
+                             WHERE repository = ?1 AND type = ?2 AND tag = ?3 AND version = ?4"
+                        ),
+                        params![repository, resource_type, tag, version],
+                        row_metadata,
+                    )
+                    .optional(),
+                None => connection
+                    .query_row(
+                        &format!(
+                            "SELECT {METADATA_COLUMNS} FROM resources
+                             WHERE repository = ?1 AND type = ?2 AND tag = ?3
+                             ORDER BY version DESC LIMIT 1"
+                        ),
+                        params![repository, resource_type, tag],
+                        row_metadata,
+                    )
+                    .optional(),
+            }
+            .context("read resource metadata from sqlite")?;
+
+            Ok(metadata.ok_or(ResourceNotFound::Resource)?)
+        })
+        .await
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let mut statement = connection.prepare(&format!(
+                "SELECT {METADATA_COLUMNS} FROM resources
+                 WHERE repository = ?1 AND type = ?2 AND tag = ?3
+                 ORDER BY version"
+            ))?;
+            let versions = statement
+                .query_map(params![repository, resource_type, tag], row_metadata)?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context("read resource metadata from sqlite")?;
+            if versions.is_empty() {
+                bail!(ResourceNotFound::Resource);
+            }
+
+            Ok(versions)
+        })
+        .await
+    }
+
+    async fn release_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let transaction = connection.transaction()?;
+            let access = read_access(&transaction, &resource_desc)?;
+            access.limits.check(access.reads, now()?)?;
+
+            let data = read_data(&transaction, &resource_desc)?;
+            // Only the limited reads are counted.
+            if access.limits.max_reads.is_some() {
+                transaction
+                    .execute(
+                        "UPDATE resource_access SET reads = reads + 1
+                         WHERE repository = ?1 AND type = ?2 AND tag = ?3",
+                        params![repository, resource_type, tag],
+                    )
+                    .context("count resource read in sqlite")?;
+                transaction
+                    .commit()
+                    .context("count resource read in sqlite")?;
+            }
+
+            Ok(data)
+        })
+        .await
+    }
+
+    async fn get_resource_access(&self, resource_desc: ResourceDesc) -> Result<ResourceAccess> {
+        self.call(move |connection| {
+            if !resource_exists(connection, &resource_desc)? {
+                bail!(ResourceNotFound::Resource);
+            }
+
+            read_access(connection, &resource_desc)
+        })
+        .await
+    }
+
+    async fn set_resource_limits(
+        &mut self,
+        resource_desc: ResourceDesc,
+        limits: ResourceLimits,
+    ) -> Result<()> {
+        self.call(move |connection| {
+            let (repository, resource_type, tag) = key(&resource_desc);
+            let transaction = connection.transaction()?;
+            if !resource_exists(&transaction, &resource_desc)? {
+                bail!(ResourceNotFound::Resource);
+            }
+
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO resource_access
+                     (repository, type, tag, max_reads, not_before, not_after, reads)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0)",
+                    params![
+                        repository,
+                        resource_type,
+                        tag,
+                        limits.max_reads,
+                        limits.not_before,
+                        limits.not_after
+                    ],
+                )
+                .context("write resource access to sqlite")?;
+            transaction
+                .commit()
+                .context("write resource access to sqlite")
+        })
+        .await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        self.call(|connection| {
+            list_names(
+                connection,
+                "SELECT DISTINCT repository FROM resources ORDER BY repository",
+                params![],
+            )
+        })
+        .await
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        let repository_name = repository_name.to_string();
+        self.call(move |connection| {
+            let types = list_names(
+                connection,
+                "SELECT DISTINCT type FROM resources WHERE repository = ?1 ORDER BY type",
+                params![repository_name],
+            )?;
+            if types.is_empty() {
+                bail!(ResourceNotFound::Repository(repository_name.to_string()));
+            }
+
+            Ok(types)
+        })
+        .await
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        let repository_name = repository_name.to_string();
+        let resource_type = resource_type.to_string();
+        self.call(move |connection| {
+            let tags = list_names(
+                connection,
+                "SELECT DISTINCT tag FROM resources
+                 WHERE repository = ?1 AND type = ?2 ORDER BY tag",
+                params![repository_name, resource_type],
+            )?;
+            if tags.is_empty() {
+                bail!(ResourceNotFound::ResourceType(format!(
+                    "{repository_name}/{resource_type}"
+                )));
+            }
+
+            Ok(tags)
+        })
+        .await
+    }
+}
+
+fn key(resource_desc: &ResourceDesc) -> (&str, &str, &str) {
+    (
+        &resource_desc.repository_name,
+        &resource_desc.resource_type,
+        &resource_desc.resource_tag,
+    )
+}
+
+fn read_data(connection: &Connection, resource_desc: &ResourceDesc) -> Result<Vec<u8>> {
+    let (repository, resource_type, tag) = key(resource_desc);
+    let data = match resource_desc.version {
+        Some(version) => connection
+            .query_row(
+                "SELECT data FROM resources
+                 WHERE repository = ?1 AND type = ?2 AND tag = ?3 AND version = ?4",
+                params![repository, resource_type, tag, version],
+                |row| row.get(0),
+            )
+            .optional(),
+        None => connection
+            .query_row(
+                "SELECT data FROM resources
+                 WHERE repository = ?1 AND type = ?2 AND tag = ?3
+                 ORDER BY version DESC LIMIT 1",
+                params![repository, resource_type, tag],
+                |row| row.get(0),
+            )
+            .optional(),
+    }
+    .context("read resource from sqlite")?;
+
+    Ok(data.ok_or(ResourceNotFound::Resource)?)
+}
+
+fn resource_exists(connection: &Connection, resource_desc: &ResourceDesc) -> Result<bool> {
+    let (repository, resource_type, tag) = key(resource_desc);
+    let exists = connection
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM resources
+             WHERE repository = ?1 AND type = ?2 AND tag = ?3)",
+            params![repository, resource_type, tag],
+            |row| row.get(0),
+        )
+        .context("read resource from sqlite")?;
+
+    Ok(exists)
+}
+
+/// Access limits and read count of a resource, none if not recorded yet.
+fn read_access(connection: &Connection, resource_desc: &ResourceDesc) -> Result<ResourceAccess> {
+    let (repository, resource_type, tag) = key(resource_desc);
+    let access = connection
+        .query_row(
+            "SELECT max_reads, not_before, not_after, reads FROM resource_access
+             WHERE repository = ?1 AND type = ?2 AND tag = ?3",
+            params![repository, resource_type, tag],
+            |row| {
+                Ok(ResourceAccess {
+                    limits: ResourceLimits {
+                        max_reads: row.get(0)?,
+                        not_before: row.get(1)?,
+                        not_after: row.get(2)?,
+                    },
+                    reads: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .context("read resource access from sqlite")?;
+
+    Ok(access.unwrap_or_default())
+}
+
+fn list_names(
+    connection: &Connection,
+    query: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<Vec<String>> {
+    let mut statement = connection.prepare(query)?;
+    let names = statement
+        .query_map(params, |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .context("list sqlite resources")?;
+
+    Ok(names)
+}
+
+fn row_metadata(row: &Row) -> rusqlite::Result<ResourceMetadata> {
+    Ok(ResourceMetadata {
+        version: row.get(0)?,
+        size: row.get(1)?,
+        sha256: row.get(2)?,
+        created: Some(row.get(3)?),
+        modified: row.get(4)?,
+    })
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+impl Sqlite {
+    pub fn new(repo_desc: &SqliteRepoDesc) -> Result<Self> {
+        let path = repo_desc
+            .path
+            .clone()
+            .unwrap_or(DEFAULT_DB_PATH.to_string());
+        if let Some(parent) = Path::new(&path).parent() {
+            std::fs::create_dir_all(parent).context("create sqlite database directory")?;
+        }
+
+        let connection =
+            Connection::open(&path).with_context(|| format!("open sqlite database {path}"))?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("create sqlite resources tables")?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run a database operation on the blocking thread pool.
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| anyhow!("sqlite connection lock poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resource::{
+        conformance::{repository_conformance_tests, resource_access_conformance_tests},
+        sqlite::{Sqlite, SqliteRepoDesc},
+        Repository, ResourceDesc,
+    };
+    use tempfile::TempDir;
+
+    fn sqlite(tmp_dir: &TempDir) -> Sqlite {
+        let repo_desc = SqliteRepoDesc {
+            path: Some(
+                tmp_dir
+                    .path()
+                    .join("repository.db")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        };
+
+        Sqlite::new(&repo_desc).expect("create sqlite repository failed")
+    }
+
+    repository_conformance_tests!({
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let sqlite = sqlite(&tmp_dir);
+        (tmp_dir, sqlite)
+    });
+
+    resource_access_conformance_tests!({
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let sqlite = sqlite(&tmp_dir);
+        (tmp_dir, sqlite)
+    });
+
+    #[tokio::test]
+    async fn reopen_database() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let resource_desc = ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "test".into(),
+            resource_tag: "test".into(),
+            version: None,
+        };
+
+        sqlite(&tmp_dir)
+            .write_secret_resource(resource_desc.clone(), b"testdata")
+            .await
+            .expect("write secret resource failed");
+
+        let data = sqlite(&tmp_dir)
+            .read_secret_resource(resource_desc)
+            .await
+            .expect("read secret resource failed");
+        assert_eq!(data, b"testdata");
+    }
+}
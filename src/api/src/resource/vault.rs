@@ -0,0 +1,733 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Repository, ResourceDesc, ResourceMetadata, ResourceNotFound};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::Mutex;
+
+pub const DEFAULT_VAULT_ADDRESS: &str = "http://127.0.0.1:8200";
+const DEFAULT_MOUNT: &str = "secret";
+const DEFAULT_APPROLE_MOUNT: &str = "approle";
+const DEFAULT_FIELD: &str = "value";
+
+/// An AppRole token is renewed by logging in again, this long before it expires.
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(30);
+
+fn default_mount() -> String {
+    DEFAULT_MOUNT.to_string()
+}
+
+fn default_approle_mount() -> String {
+    DEFAULT_APPROLE_MOUNT.to_string()
+}
+
+fn default_field() -> String {
+    DEFAULT_FIELD.to_string()
+}
+
+/// A resource `<repository>/<type>/<tag>` is the `<prefix>/<repository>/<type>/<tag>`
+/// secret of the KV v2 secrets engine mounted at `mount`, and its content the
+/// `field` of the secret data. The KBS resource versions are the secret versions.
+#[derive(Debug, Deserialize, Clone)]
+pub struct VaultRepoDesc {
+    /// Vault server address, e.g. `https://vault.example.com:8200`.
+    pub address: String,
+    /// Mount path of the KV v2 secrets engine.
+    #[serde(default = "default_mount")]
+    pub mount: String,
+    /// Secret path prefix of the resources.
+    #[serde(default)]
+    pub prefix: String,
+    /// Secret data field holding the resource content.
+    #[serde(default = "default_field")]
+    pub field: String,
+    pub auth: VaultAuth,
+    /// Path to a PEM CA certificate verifying the Vault server certificate.
+    pub ca_cert: Option<String>,
+}
+
+impl Default for VaultRepoDesc {
+    fn default() -> Self {
+        Self {
+            address: DEFAULT_VAULT_ADDRESS.to_string(),
+            mount: default_mount(),
+            prefix: String::new(),
+            field: default_field(),
+            auth: VaultAuth::Token {
+                token: String::new(),
+            },
+            ca_cert: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum VaultAuth {
+    Token {
+        token: String,
+    },
+    AppRole {
+        #[serde(default = "default_approle_mount")]
+        mount: String,
+        role_id: String,
+        secret_id: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct VaultResponse<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct SecretVersion {
+    data: Map<String, Value>,
+    metadata: VersionMetadata,
+}
+
+#[derive(Deserialize)]
+struct VersionMetadata {
+    version: u64,
+    created_time: String,
+}
+
+#[derive(Deserialize)]
+struct SecretMetadata {
+    versions: HashMap<String, VersionState>,
+}
+
+#[derive(Deserialize)]
+struct VersionState {
+    #[serde(default)]
+    deletion_time: String,
+    #[serde(default)]
+    destroyed: bool,
+}
+
+#[derive(Deserialize)]
+struct KeyList {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: u64,
+}
+
+pub struct Vault {
+    desc: VaultRepoDesc,
+    client: reqwest::Client,
+    /// AppRole client token, with its expiration time.
+    approle_token: Mutex<Option<(String, Instant)>>,
+}
+
+#[async_trait::async_trait]
+impl Repository for Vault {
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+        let (data, _) = self
+            .read_secret(&resource_desc, resource_desc.version)
+            .await
+            .context("read resource from vault")?;
+        Ok(data)
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        // Keep the resource text readable in Vault, the binary ones are
+        // base64 encoded.
+        let field = &self.desc.field;
+        let secret = match std::str::from_utf8(data) {
+            Ok(text) => json!({ field: text }),
+            Err(_) => json!({
+                field: STANDARD.encode(data),
+                format!("{field}_encoding"): "base64",
+            }),
+        };
+
+        let url = self.secret_url("data", &resource_desc)?;
+        self.send(Method::POST, url, Some(json!({ "data": secret })))
+            .await
+            .context("write vault")?;
+        Ok(())
+    }
+
+    async fn replace_secret_resource_version(
+        &mut self,
+        _resource_desc: ResourceDesc,
+        _data: &[u8],
+    ) -> Result<()> {
+        bail!("Vault KV v2 secret versions cannot be modified")
+    }
+
+    async fn delete_secret_resource(&mut self, resource_desc: ResourceDesc) -> Result<()> {
+        // Deleting a missing secret succeeds in Vault.
+        if self.versions(&resource_desc).await?.is_empty() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        let url = self.secret_url("metadata", &resource_desc)?;
+        self.send(Method::DELETE, url, None)
+            .await
+            .context("delete resource from vault")?;
+        Ok(())
+    }
+
+    async fn get_resource_metadata(&self, resource_desc: ResourceDesc) -> Result<ResourceMetadata> {
+        let (data, metadata) = self
+            .read_secret(&resource_desc, resource_desc.version)
+            .await
+            .context("read resource metadata from vault")?;
+        resource_metadata(&data, &metadata)
+    }
+
+    async fn list_resource_versions(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Vec<ResourceMetadata>> {
+        let versions = self.versions(&resource_desc).await?;
+        if versions.is_empty() {
+            bail!(ResourceNotFound::Resource);
+        }
+
+        let mut metadata = Vec::with_capacity(versions.len());
+        for version in versions {
+            let (data, version) = self.read_secret(&resource_desc, Some(version)).await?;
+            metadata.push(resource_metadata(&data, &version)?);
+        }
+
+        Ok(metadata)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let (folders, _) = self.list(&[]).await?.unwrap_or_default();
+        Ok(folders)
+    }
+
+    async fn list_resource_types(&self, repository_name: &str) -> Result<Vec<String>> {
+        let (folders, _) = self
+            .list(&[repository_name])
+            .await?
+            .ok_or_else(|| ResourceNotFound::Repository(repository_name.to_string()))?;
+        Ok(folders)
+    }
+
+    async fn list_resource_tags(
+        &self,
+        repository_name: &str,
+        resource_type: &str,
+    ) -> Result<Vec<String>> {
+        let (_, secrets) = self
+            .list(&[repository_name, resource_type])
+            .await?
+            .ok_or_else(|| {
+                ResourceNotFound::ResourceType(format!("{repository_name}/{resource_type}"))
+            })?;
+        Ok(secrets)
+    }
+}
+
+fn resource_metadata(data: &[u8], metadata: &VersionMetadata) -> Result<ResourceMetadata> {
+    let created = OffsetDateTime::parse(&metadata.created_time, &Rfc3339)
+        .context("parse vault secret creation time")?
+        .unix_timestamp() as u64;
+
+    // A secret version cannot be modified once created.
+    Ok(ResourceMetadata {
+        version: metadata.version,
+        size: data.len() as u64,
+        created: Some(created),
+        modified: created,
+        sha256: ResourceMetadata::content_digest(data),
+    })
+}
+
+impl Vault {
+    pub fn new(repo_desc: &VaultRepoDesc) -> Result<Self> {
+        Url::parse(&repo_desc.address).context("parse vault address")?;
+
+        let mut client = reqwest::Client::builder();
+        if let Some(ca_cert) = &repo_desc.ca_cert {
+            let pem = std::fs::read(ca_cert).context("read vault CA certificate")?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        Ok(Self {
+            desc: repo_desc.clone(),
+            client: client.build()?,
+            approle_token: Mutex::new(None),
+        })
+    }
+
+    /// URL of a Vault API, from its path segments.
+    fn url<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Result<Url> {
+        let mut url = Url::parse(&self.desc.address)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid vault address"))?
+            .pop_if_empty()
+            .push("v1")
+            .extend(segments.into_iter().filter(|segment| !segment.is_empty()));
+        Ok(url)
+    }
+
+    /// URL of a KV v2 `api` (`data` or `metadata`), for the secrets under
+    /// the resources prefix.
+    fn kv_url(&self, api: &str, path: &[&str]) -> Result<Url> {
+        self.url(
+            self.desc
+                .mount
+                .split('/')
+                .chain([api])
+                .chain(self.desc.prefix.split('/'))
+                .chain(path.iter().copied()),
+        )
+    }
+
+    fn secret_url(&self, api: &str, resource_desc: &ResourceDesc) -> Result<Url> {
+        self.kv_url(
+            api,
+            &[
+                &resource_desc.repository_name,
+                &resource_desc.resource_type,
+                &resource_desc.resource_tag,
+            ],
+        )
+    }
+
+    async fn token(&self) -> Result<String> {
+        let (mount, role_id, secret_id) = match &self.desc.auth {
+            VaultAuth::Token { token } => return Ok(token.clone()),
+            VaultAuth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => (mount, role_id, secret_id),
+        };
+
+        let mut approle_token = self.approle_token.lock().await;
+        if let Some((token, expiration)) = &*approle_token {
+            if Instant::now() + TOKEN_RENEWAL_MARGIN < *expiration {
+                return Ok(token.clone());
+            }
+        }
+
+        let url = self.url(
+            ["auth"]
+                .into_iter()
+                .chain(mount.split('/'))
+                .chain(["login"]),
+        )?;
+        let response = self
+            .client
+            .post(url)
+            .json(&json!({ "role_id": role_id, "secret_id": secret_id }))
+            .send()
+            .await
+            .map_err(|e| anyhow!("Vault AppRole login failed: {:?}", e))?;
+        let login: Login = check_status(response)
+            .await
+            .context("vault AppRole login")?
+            .json()
+            .await?;
+
+        let expiration = Instant::now() + Duration::from_secs(login.auth.lease_duration);
+        *approle_token = Some((login.auth.client_token.clone(), expiration));
+        Ok(login.auth.client_token)
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<Value>,
+    ) -> Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("X-Vault-Token", self.token().await?);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        request
+            .send()
+            .await
+            .map_err(|e| anyhow!("Vault request failed: {:?}", e))
+    }
+
+    async fn send(&self, method: Method, url: Url, body: Option<Value>) -> Result<()> {
+        check_status(self.request(method, url, body).await?).await?;
+        Ok(())
+    }
+
+    /// Get a Vault API response data, `None` if not found.
+    async fn get<T: DeserializeOwned>(&self, url: Url) -> Result<Option<T>> {
+        let response = self.request(Method::GET, url, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response: VaultResponse<T> = check_status(response).await?.json().await?;
+        Ok(Some(response.data))
+    }
+
+    /// Read a resource content, at `version` or the latest one.
+    async fn read_secret(
+        &self,
+        resource_desc: &ResourceDesc,
+        version: Option<u64>,
+    ) -> Result<(Vec<u8>, VersionMetadata)> {
+        let mut url = self.secret_url("data", resource_desc)?;
+        if let Some(version) = version {
+            url.query_pairs_mut()
+                .append_pair("version", &version.to_string());
+        }
+
+        let secret: SecretVersion = self.get(url).await?.ok_or(ResourceNotFound::Resource)?;
+        let field = &self.desc.field;
+        let value = secret
+            .data
+            .get(field)
+            .and_then(Value::as_str)
+            .with_context(|| format!("no {field} field in vault secret"))?;
+        let data = match secret
+            .data
+            .get(&format!("{field}_encoding"))
+            .and_then(Value::as_str)
+        {
+            Some("base64") => STANDARD.decode(value)?,
+            Some(encoding) => bail!("unsupported vault secret encoding {encoding}"),
+            None => value.as_bytes().to_vec(),
+        };
+
+        Ok((data, secret.metadata))
+    }
+
+    /// Sorted live versions of a resource, empty if the resource does not exist.
+    async fn versions(&self, resource_desc: &ResourceDesc) -> Result<Vec<u64>> {
+        let url = self.secret_url("metadata", resource_desc)?;
+        let Some(metadata) = self.get::<SecretMetadata>(url).await? else {
+            return Ok(Vec::new());
+        };
+
+        let mut versions: Vec<u64> = metadata
+            .versions
+            .iter()
+            .filter(|(_, state)| state.deletion_time.is_empty() && !state.destroyed)
+            .filter_map(|(version, _)| version.parse().ok())
+            .collect();
+        versions.sort_unstable();
+
+        Ok(versions)
+    }
+
+    /// List the sorted sub-folders and secrets of a folder, `None` if the
+    /// folder does not exist.
+    async fn list(&self, path: &[&str]) -> Result<Option<(Vec<String>, Vec<String>)>> {
+        let mut url = self.kv_url("metadata", path)?;
+        url.query_pairs_mut().append_pair("list", "true");
+        let Some(list) = self.get::<KeyList>(url).await? else {
+            return Ok(None);
+        };
+
+        let (mut folders, mut secrets): (Vec<String>, Vec<String>) =
+            list.keys.into_iter().partition(|key| key.ends_with('/'));
+        for folder in &mut folders {
+            folder.pop();
+        }
+        folders.sort();
+        secrets.sort();
+
+        Ok(Some((folders, secrets)))
+    }
+}
+
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        bail!("Vault request failed: status={status}, {body}");
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::conformance::{repository_conformance_tests, serve};
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// Secrets of the in-process Vault stand-in, with the data of each of
+    /// their versions.
+    type Secrets = Arc<std::sync::Mutex<BTreeMap<String, Vec<Value>>>>;
+
+    const ROOT_TOKEN: &str = "root-token";
+    const APPROLE_TOKEN: &str = "approle-token";
+    const ROLE_ID: &str = "kbs-role";
+    const SECRET_ID: &str = "kbs-secret";
+    const CREATED_TIME: &str = "2023-09-01T08:00:00.123456789Z";
+
+    /// Minimal Vault stand-in, serving the AppRole login and the KV v2 APIs
+    /// used by the `Vault` repository, with the engine mounted at `secret`.
+    async fn stand_in(
+        request: HttpRequest,
+        query: web::Query<HashMap<String, String>>,
+        body: web::Bytes,
+        secrets: web::Data<Secrets>,
+    ) -> HttpResponse {
+        let path = request.path();
+        if path == "/v1/auth/approle/login" {
+            let login: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if login["role_id"] != ROLE_ID || login["secret_id"] != SECRET_ID {
+                return HttpResponse::BadRequest().finish();
+            }
+            return HttpResponse::Ok().json(json!({
+                "auth": { "client_token": APPROLE_TOKEN, "lease_duration": 3600 }
+            }));
+        }
+
+        let token = request
+            .headers()
+            .get("X-Vault-Token")
+            .and_then(|value| value.to_str().ok());
+        if token != Some(ROOT_TOKEN) && token != Some(APPROLE_TOKEN) {
+            return HttpResponse::Forbidden().finish();
+        }
+
+        let mut secrets = secrets.lock().unwrap();
+        let method = request.method().as_str();
+        if let Some(secret) = path.strip_prefix("/v1/secret/data/") {
+            return match method {
+                "POST" => {
+                    let body: Value = serde_json::from_slice(&body).unwrap();
+                    let versions = secrets.entry(secret.to_string()).or_default();
+                    versions.push(body["data"].clone());
+                    HttpResponse::Ok().json(json!({ "data": { "version": versions.len() } }))
+                }
+                _ => {
+                    let versions = secrets.get(secret).cloned().unwrap_or_default();
+                    let version = query
+                        .get("version")
+                        .map(|version| version.parse().unwrap())
+                        .unwrap_or(versions.len());
+                    match version.checked_sub(1).and_then(|i| versions.get(i)) {
+                        Some(data) => HttpResponse::Ok().json(json!({
+                            "data": {
+                                "data": data,
+                                "metadata": { "version": version, "created_time": CREATED_TIME },
+                            }
+                        })),
+                        None => HttpResponse::NotFound().json(json!({ "errors": [] })),
+                    }
+                }
+            };
+        }
+
+        let Some(secret) = path.strip_prefix("/v1/secret/metadata") else {
+            return HttpResponse::NotFound().finish();
+        };
+        let secret = secret.trim_matches('/');
+        match (method, query.contains_key("list")) {
+            ("GET", true) => {
+                let folder = match secret.is_empty() {
+                    true => String::new(),
+                    false => format!("{secret}/"),
+                };
+                let mut keys: Vec<String> = secrets
+                    .keys()
+                    .filter_map(|key| key.strip_prefix(&folder))
+                    .map(|key| match key.split_once('/') {
+                        Some((name, _)) => format!("{name}/"),
+                        None => key.to_string(),
+                    })
+                    .collect();
+                keys.dedup();
+                match keys.is_empty() {
+                    true => HttpResponse::NotFound().json(json!({ "errors": [] })),
+                    false => HttpResponse::Ok().json(json!({ "data": { "keys": keys } })),
+                }
+            }
+            ("GET", false) => match secrets.get(secret) {
+                Some(versions) => {
+                    let versions: Map<String, Value> = (1..=versions.len())
+                        .map(|version| {
+                            (
+                                version.to_string(),
+                                json!({ "deletion_time": "", "destroyed": false }),
+                            )
+                        })
+                        .collect();
+                    HttpResponse::Ok().json(json!({ "data": { "versions": versions } }))
+                }
+                None => HttpResponse::NotFound().json(json!({ "errors": [] })),
+            },
+            ("DELETE", _) => {
+                secrets.remove(secret);
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::MethodNotAllowed().finish(),
+        }
+    }
+
+    /// Start a Vault stand-in, returning its secrets and a repository using it.
+    fn vault_stand_in(auth: VaultAuth) -> (Secrets, Vault) {
+        let secrets = Secrets::default();
+        let data = web::Data::new(secrets.clone());
+        let address = serve(move |config| {
+            config
+                .app_data(data.clone())
+                .default_service(web::to(stand_in));
+        });
+
+        let repo_desc = VaultRepoDesc {
+            address,
+            auth,
+            ..Default::default()
+        };
+        let vault = Vault::new(&repo_desc).expect("create vault repository failed");
+        (secrets, vault)
+    }
+
+    fn root_token() -> VaultAuth {
+        VaultAuth::Token {
+            token: ROOT_TOKEN.into(),
+        }
+    }
+
+    fn resource_desc() -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+            version: None,
+        }
+    }
+
+    repository_conformance_tests!(immutable_versions: vault_stand_in(root_token()));
+
+    #[tokio::test]
+    async fn approle_login() {
+        let (_secrets, mut vault) = vault_stand_in(VaultAuth::AppRole {
+            mount: default_approle_mount(),
+            role_id: ROLE_ID.into(),
+            secret_id: SECRET_ID.into(),
+        });
+
+        vault
+            .write_secret_resource(resource_desc(), b"testdata")
+            .await
+            .expect("write secret resource failed");
+        assert_eq!(
+            vault.read_secret_resource(resource_desc()).await.unwrap(),
+            b"testdata"
+        );
+
+        let (_secrets, vault) = vault_stand_in(VaultAuth::AppRole {
+            mount: default_approle_mount(),
+            role_id: ROLE_ID.into(),
+            secret_id: "bad".into(),
+        });
+        assert!(vault.read_secret_resource(resource_desc()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn secret_layout() {
+        let (secrets, mut vault) = vault_stand_in(root_token());
+        let binary = [0xffu8, 0x00, 0x01];
+
+        vault
+            .write_secret_resource(resource_desc(), b"text")
+            .await
+            .unwrap();
+        vault
+            .write_secret_resource(resource_desc(), &binary)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            secrets.lock().unwrap().get("default/key/1").unwrap(),
+            &vec![
+                json!({ "value": "text" }),
+                json!({ "value": "/wAB", "value_encoding": "base64" }),
+            ]
+        );
+        assert_eq!(
+            vault.read_secret_resource(resource_desc()).await.unwrap(),
+            binary
+        );
+
+        let metadata = vault.get_resource_metadata(resource_desc()).await.unwrap();
+        assert_eq!(metadata.version, 2);
+        assert_eq!(metadata.created, Some(1693555200));
+        assert_eq!(metadata.modified, 1693555200);
+    }
+
+    #[test]
+    fn kv_urls() {
+        let vault = Vault::new(&VaultRepoDesc {
+            address: "https://vault.example.com:8200/".into(),
+            mount: "kv/kbs".into(),
+            prefix: "/confidential/".into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            vault.secret_url("data", &resource_desc()).unwrap().as_str(),
+            "https://vault.example.com:8200/v1/kv/kbs/data/confidential/default/key/1"
+        );
+        assert_eq!(
+            vault.kv_url("metadata", &[]).unwrap().as_str(),
+            "https://vault.example.com:8200/v1/kv/kbs/metadata/confidential"
+        );
+    }
+
+    /// Run the conformance suite against a Vault server, e.g. `vault server -dev`,
+    /// given by the `VAULT_ADDR` and `VAULT_TOKEN` environment variables.
+    #[tokio::test]
+    #[ignore]
+    async fn vault_dev_server() {
+        let address = std::env::var("VAULT_ADDR").unwrap_or(DEFAULT_VAULT_ADDRESS.into());
+        let token = std::env::var("VAULT_TOKEN").expect("VAULT_TOKEN is not set");
+        let new_vault = || {
+            Vault::new(&VaultRepoDesc {
+                address: address.clone(),
+                // Start from an empty repository.
+                prefix: format!("kbs-test-{}", uuid::Uuid::new_v4()),
+                auth: VaultAuth::Token {
+                    token: token.clone(),
+                },
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        crate::resource::conformance::write_and_read_resource(&mut new_vault()).await;
+        crate::resource::conformance::list_inspect_and_delete_resource(&mut new_vault()).await;
+        crate::resource::conformance::versions_and_rollback(&mut new_vault()).await;
+        crate::resource::conformance::immutable_resource_version(&mut new_vault()).await;
+        crate::resource::conformance::missing_resource(&mut new_vault()).await;
+    }
+}
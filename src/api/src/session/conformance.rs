@@ -0,0 +1,192 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conformance suite that every `SessionStore` implementation must pass.
+//!
+//! A backend runs the suite with `session_store_conformance_tests!`, given an
+//! expression creating an empty store that keeps at most 2 pending sessions,
+//! and optionally attributes added to every test, e.g. `#[ignore]`.
+
+use super::{Session, SessionStore};
+use kbs_types::{Request, Tee};
+
+macro_rules! session_store_conformance_tests {
+    ($new_store:expr $(, #[$attr:meta])*) => {
+        mod conformance {
+            use super::*;
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn insert_get_and_update_session() {
+                let store = $new_store;
+                $crate::session::conformance::insert_get_and_update_session(&store).await;
+            }
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn evict_least_recently_used_pending_session() {
+                let store = $new_store;
+                $crate::session::conformance::evict_least_recently_used_pending_session(&store)
+                    .await;
+            }
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn authenticated_sessions_are_not_evicted() {
+                let store = $new_store;
+                $crate::session::conformance::authenticated_sessions_are_not_evicted(&store).await;
+            }
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn reap_expired_sessions() {
+                let store = $new_store;
+                $crate::session::conformance::reap_expired_sessions(&store).await;
+            }
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn list_and_remove_sessions() {
+                let store = $new_store;
+                $crate::session::conformance::list_and_remove_sessions(&store).await;
+            }
+
+            #[tokio::test]
+            $(#[$attr])*
+            async fn record_proofs() {
+                let store = $new_store;
+                $crate::session::conformance::record_proofs(&store).await;
+            }
+        }
+    };
+}
+
+pub(crate) use session_store_conformance_tests;
+
+fn session(timeout: i64, pending_timeout: i64) -> Session {
+    let request = Request {
+        version: "0.1.0".to_string(),
+        tee: Tee::Sample,
+        extra_params: String::new(),
+    };
+    Session::from_request(&request, timeout, pending_timeout).unwrap()
+}
+
+async fn authenticate(store: &dyn SessionStore, id: &str) {
+    let mut session = store.get(id).await.unwrap().unwrap();
+    session.set_authenticated();
+    session.set_attestation_claims("{}".to_string());
+    store.update(session).await.unwrap();
+}
+
+pub(crate) async fn insert_get_and_update_session(store: &dyn SessionStore) {
+    let session = session(5, 60);
+    let id = session.id().to_string();
+    store.insert(session.clone()).await.unwrap();
+
+    let stored = store.get(&id).await.unwrap().unwrap();
+    assert_eq!(stored.nonce(), session.nonce());
+    assert!(!stored.is_authenticated());
+
+    authenticate(store, &id).await;
+    let stored = store.get(&id).await.unwrap().unwrap();
+    assert!(stored.is_valid());
+    assert_eq!(stored.attestation_claims(), Some("{}".to_string()));
+
+    assert!(store.get("missing").await.unwrap().is_none());
+}
+
+pub(crate) async fn evict_least_recently_used_pending_session(store: &dyn SessionStore) {
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let session = session(5, 60);
+        ids.push(session.id().to_string());
+        store.insert(session).await.unwrap();
+    }
+
+    // The first session is used, and the second one evicted.
+    store.touch_pending(&ids[0]).await.unwrap();
+    let session = session(5, 60);
+    let id = session.id().to_string();
+    store.insert(session).await.unwrap();
+
+    assert!(store.get(&ids[0]).await.unwrap().is_some());
+    assert!(store.get(&ids[1]).await.unwrap().is_none());
+    assert!(store.get(&id).await.unwrap().is_some());
+}
+
+pub(crate) async fn authenticated_sessions_are_not_evicted(store: &dyn SessionStore) {
+    let session = session(5, 60);
+    let id = session.id().to_string();
+    store.insert(session).await.unwrap();
+    authenticate(store, &id).await;
+
+    for _ in 0..3 {
+        store.insert(self::session(5, 60)).await.unwrap();
+    }
+
+    assert!(store.get(&id).await.unwrap().is_some());
+}
+
+pub(crate) async fn reap_expired_sessions(store: &dyn SessionStore) {
+    let pending = session(5, 0);
+    let pending_id = pending.id().to_string();
+    store.insert(pending).await.unwrap();
+
+    let authenticated = session(5, 60);
+    let authenticated_id = authenticated.id().to_string();
+    store.insert(authenticated).await.unwrap();
+    authenticate(store, &authenticated_id).await;
+
+    let expired = session(0, 60);
+    let expired_id = expired.id().to_string();
+    store.insert(expired).await.unwrap();
+
+    store.reap().await.unwrap();
+
+    assert!(store.get(&pending_id).await.unwrap().is_none());
+    assert!(store.get(&authenticated_id).await.unwrap().is_some());
+    assert!(store.get(&expired_id).await.unwrap().is_none());
+}
+
+pub(crate) async fn list_and_remove_sessions(store: &dyn SessionStore) {
+    let authenticated = session(5, 60);
+    let authenticated_id = authenticated.id().to_string();
+    store.insert(authenticated).await.unwrap();
+    authenticate(store, &authenticated_id).await;
+
+    let pending = session(5, 60);
+    let pending_id = pending.id().to_string();
+    store.insert(pending).await.unwrap();
+
+    store.insert(session(0, 60)).await.unwrap();
+
+    let mut ids: Vec<String> = store
+        .list()
+        .await
+        .unwrap()
+        .iter()
+        .map(|session| session.id().to_string())
+        .collect();
+    ids.sort();
+    let mut expected = vec![authenticated_id.clone(), pending_id.clone()];
+    expected.sort();
+    assert_eq!(ids, expected);
+
+    assert!(store.remove(&authenticated_id).await.unwrap());
+    assert!(!store.remove(&authenticated_id).await.unwrap());
+    assert!(store.get(&authenticated_id).await.unwrap().is_none());
+
+    assert!(store.remove(&pending_id).await.unwrap());
+    assert!(store.list().await.unwrap().is_empty());
+}
+
+pub(crate) async fn record_proofs(store: &dyn SessionStore) {
+    assert!(store.record_proof("session", "proof", 60).await.unwrap());
+    assert!(!store.record_proof("session", "proof", 60).await.unwrap());
+
+    // The proof ids are recorded per session.
+    assert!(store.record_proof("other", "proof", 60).await.unwrap());
+    assert!(store.record_proof("session", "other", 60).await.unwrap());
+}
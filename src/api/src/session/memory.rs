@@ -0,0 +1,169 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{now, Session, SessionStore};
+use crate::metrics::SESSIONS;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{Mutex, RwLock};
+
+/// Sessions kept in the KBS process memory. They are lost on restart, and
+/// not shared with other KBS replicas.
+pub(crate) struct MemorySessionStore {
+    sessions: RwLock<HashMap<String, Session>>,
+    /// Ids of the sessions that did not complete `/attest` yet, least
+    /// recently used first.
+    pending: Mutex<VecDeque<String>>,
+    /// Expiry time of the proof ids recorded on each session.
+    proofs: Mutex<HashMap<(String, String), i64>>,
+    max_pending_sessions: usize,
+}
+
+impl MemorySessionStore {
+    pub(crate) fn new(max_pending_sessions: usize) -> Self {
+        Self {
+            sessions: RwLock::new(HashMap::new()),
+            pending: Mutex::new(VecDeque::new()),
+            proofs: Mutex::new(HashMap::new()),
+            max_pending_sessions,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn insert(&self, session: Session) -> Result<()> {
+        let mut sessions = self.sessions.write().await;
+        let mut pending = self.pending.lock().await;
+
+        while pending.len() >= self.max_pending_sessions.max(1) {
+            let Some(id) = pending.pop_front() else {
+                break;
+            };
+            log::info!("Evict pending session {id}");
+            sessions.remove(&id);
+        }
+
+        pending.push_back(session.id().to_string());
+        sessions.insert(session.id().to_string(), session);
+        SESSIONS.set(sessions.len() as i64);
+
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Session>> {
+        Ok(self.sessions.read().await.get(id).cloned())
+    }
+
+    async fn update(&self, session: Session) -> Result<()> {
+        let mut sessions = self.sessions.write().await;
+        let Some(stored) = sessions.get_mut(session.id()) else {
+            return Ok(());
+        };
+
+        if session.is_authenticated() {
+            self.pending
+                .lock()
+                .await
+                .retain(|pending_id| pending_id != session.id());
+        }
+        *stored = session;
+
+        Ok(())
+    }
+
+    async fn touch_pending(&self, id: &str) -> Result<()> {
+        let mut pending = self.pending.lock().await;
+        if let Some(index) = pending.iter().position(|pending_id| pending_id == id) {
+            if let Some(id) = pending.remove(index) {
+                pending.push_back(id);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn reap(&self) -> Result<()> {
+        let now = now();
+        self.proofs
+            .lock()
+            .await
+            .retain(|_, expires_at| *expires_at > now);
+
+        let mut sessions = self.sessions.write().await;
+        let count = sessions.len();
+        sessions.retain(|_, session| !session.is_expired());
+
+        let expired = count - sessions.len();
+        if expired == 0 {
+            return Ok(());
+        }
+
+        self.pending
+            .lock()
+            .await
+            .retain(|id| sessions.contains_key(id));
+        SESSIONS.set(sessions.len() as i64);
+
+        log::info!("Evicted {expired} expired sessions");
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Session>> {
+        Ok(self
+            .sessions
+            .read()
+            .await
+            .values()
+            .filter(|session| !session.is_expired())
+            .cloned()
+            .collect())
+    }
+
+    async fn remove(&self, id: &str) -> Result<bool> {
+        let mut sessions = self.sessions.write().await;
+        if sessions.remove(id).is_none() {
+            return Ok(false);
+        }
+
+        self.pending
+            .lock()
+            .await
+            .retain(|pending_id| pending_id != id);
+        SESSIONS.set(sessions.len() as i64);
+
+        Ok(true)
+    }
+
+    async fn record_proof(&self, id: &str, proof_id: &str, ttl: u64) -> Result<bool> {
+        let mut proofs = self.proofs.lock().await;
+        let now = now();
+        let key = (id.to_string(), proof_id.to_string());
+        if proofs.get(&key).is_some_and(|expires_at| *expires_at > now) {
+            return Ok(false);
+        }
+
+        proofs.insert(key, now + i64::try_from(ttl)?);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::conformance::session_store_conformance_tests;
+
+    session_store_conformance_tests!(MemorySessionStore::new(2));
+
+    #[tokio::test]
+    async fn reap_expired_proofs() {
+        let store = MemorySessionStore::new(2);
+        assert!(store.record_proof("session", "proof", 0).await.unwrap());
+        assert!(store.record_proof("session", "proof", 0).await.unwrap());
+
+        store.reap().await.unwrap();
+        assert!(store.proofs.lock().await.is_empty());
+    }
+}
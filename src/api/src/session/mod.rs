@@ -0,0 +1,448 @@
+// Copyright (c) 2022 by Rivos Inc.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::tee_key::TeeKey;
+use actix_web::cookie::{time::OffsetDateTime, Cookie, SameSite};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use kbs_types::{Request, Tee};
+use rand::{thread_rng, Rng};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod memory;
+#[cfg(feature = "resource")]
+mod proof;
+#[cfg(feature = "redis")]
+mod redis;
+
+#[cfg(test)]
+mod conformance;
+
+#[cfg(feature = "redis")]
+use self::redis::RedisSessionStore;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisSessionStoreDesc;
+use memory::MemorySessionStore;
+#[cfg(feature = "resource")]
+pub(crate) use proof::verify_proof;
+
+pub(crate) static KBS_SESSION_ID: &str = "kbs-session-id";
+
+/// Header carrying the session id of the clients using the header transport.
+pub(crate) static KBS_SESSION_ID_HEADER: &str = "KBS-Session-Id";
+
+/// Extra parameter of the `Challenge` carrying the session id of the clients
+/// using the header transport.
+pub(crate) static SESSION_ID_PARAM: &str = "session-id";
+
+lazy_static! {
+    /// Protocol versions of the clients using the header transport.
+    static ref HEADER_TRANSPORT_VERSION_REQ: VersionReq = VersionReq::parse(">=0.2.0").unwrap();
+}
+
+const DEFAULT_MAX_PENDING_SESSIONS: usize = 10000;
+const DEFAULT_PENDING_TIMEOUT: i64 = 60;
+const DEFAULT_REAP_INTERVAL: u64 = 60;
+
+fn default_max_pending_sessions() -> usize {
+    DEFAULT_MAX_PENDING_SESSIONS
+}
+
+fn default_pending_timeout() -> i64 {
+    DEFAULT_PENDING_TIMEOUT
+}
+
+fn default_reap_interval() -> u64 {
+    DEFAULT_REAP_INTERVAL
+}
+
+fn default_cookie_path() -> String {
+    crate::KBS_PREFIX.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Limits of the sessions kept by the KBS, and where they are kept.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionConfig {
+    /// Maximum number of sessions that did not complete `/attest` yet. The
+    /// least recently used one is evicted to make room for a new session.
+    #[serde(default = "default_max_pending_sessions")]
+    pub max_pending_sessions: usize,
+
+    /// Time in seconds given to a session to complete `/attest`, after which
+    /// it expires.
+    #[serde(default = "default_pending_timeout")]
+    pub pending_timeout: i64,
+
+    /// Interval in seconds between two evictions of the expired sessions.
+    #[serde(default = "default_reap_interval")]
+    pub reap_interval: u64,
+
+    /// Session store, shared by the KBS replicas unless kept in memory.
+    #[serde(default)]
+    pub store: SessionStoreConfig,
+
+    /// Attributes of the session cookies.
+    #[serde(default)]
+    pub cookie: CookieConfig,
+
+    /// Bind the sessions to the TEE public key given at `/attest`. The
+    /// requests on a bound session must then carry a proof signed with the
+    /// TEE private key, so that the session id alone cannot be replayed.
+    #[serde(default)]
+    pub bind_tee_key: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            max_pending_sessions: DEFAULT_MAX_PENDING_SESSIONS,
+            pending_timeout: DEFAULT_PENDING_TIMEOUT,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+            store: SessionStoreConfig::default(),
+            cookie: CookieConfig::default(),
+            bind_tee_key: false,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub(crate) async fn initialize(&self) -> Result<Arc<dyn SessionStore>> {
+        match &self.store {
+            SessionStoreConfig::Memory => {
+                Ok(Arc::new(MemorySessionStore::new(self.max_pending_sessions)))
+            }
+            #[cfg(feature = "redis")]
+            SessionStoreConfig::Redis(desc) => Ok(Arc::new(
+                RedisSessionStore::new(desc, self.max_pending_sessions).await?,
+            )),
+        }
+    }
+}
+
+/// Attributes of the session cookies.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CookieConfig {
+    /// Only send the cookie over HTTPS. When omitted, it is set when the KBS
+    /// serves HTTPS.
+    pub secure: Option<bool>,
+
+    /// Hide the cookie from scripts.
+    #[serde(default = "default_true")]
+    pub http_only: bool,
+
+    /// Whether the cookie is sent along cross-site requests.
+    #[serde(default)]
+    pub same_site: CookieSameSite,
+
+    /// Path the cookie is sent to.
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: None,
+            http_only: true,
+            same_site: CookieSameSite::default(),
+            path: default_cookie_path(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum CookieSameSite {
+    #[default]
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type")]
+pub enum SessionStoreConfig {
+    /// Sessions kept in the KBS process memory.
+    #[default]
+    Memory,
+    /// Sessions kept by a server speaking the Redis protocol.
+    #[cfg(feature = "redis")]
+    Redis(RedisSessionStoreDesc),
+}
+
+/// Keeps the KBS sessions, from `/auth` to their expiry.
+#[async_trait]
+pub(crate) trait SessionStore: Send + Sync {
+    /// Insert a new, not yet authenticated, session. The least recently used
+    /// pending sessions are evicted beyond the maximum number of pending
+    /// sessions.
+    async fn insert(&self, session: Session) -> Result<()>;
+
+    /// Get the session `id`, if it exists.
+    async fn get(&self, id: &str) -> Result<Option<Session>>;
+
+    /// Store the updated `session`, unless it was removed in the meantime.
+    /// An authenticated session stops counting as pending.
+    async fn update(&self, session: Session) -> Result<()>;
+
+    /// Mark the pending session `id` as the most recently used one.
+    async fn touch_pending(&self, id: &str) -> Result<()>;
+
+    /// Remove the expired sessions.
+    async fn reap(&self) -> Result<()>;
+
+    /// List the sessions that did not expire.
+    async fn list(&self) -> Result<Vec<Session>>;
+
+    /// Remove the session `id`, returning whether it existed.
+    async fn remove(&self, id: &str) -> Result<bool>;
+
+    /// Record the id of a proof on the session `id` for `ttl` seconds,
+    /// returning whether it was not recorded yet, i.e. the proof is not
+    /// replayed.
+    async fn record_proof(&self, id: &str, proof_id: &str, ttl: u64) -> Result<bool>;
+}
+
+/// Remove the expired sessions of `store` every `interval` seconds.
+pub(crate) async fn reap_sessions(store: Arc<dyn SessionStore>, interval: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        if let Err(e) = store.reap().await {
+            log::warn!("Evict expired sessions failed: {e:#}");
+        }
+    }
+}
+
+fn nonce() -> Result<String> {
+    let mut nonce: Vec<u8> = vec![0; 32];
+
+    thread_rng()
+        .try_fill(&mut nonce[..])
+        .map_err(anyhow::Error::from)?;
+
+    Ok(STANDARD.encode(&nonce))
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// How the session id is carried between the client and the KBS.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub(crate) enum SessionTransport {
+    /// The `kbs-session-id` cookie.
+    #[default]
+    Cookie,
+    /// The `Challenge` extra parameters, then the `KBS-Session-Id` header or
+    /// a bearer `Authorization` header. For the clients behind proxies
+    /// stripping cookies.
+    Header,
+}
+
+/// A KBS session. The times are Unix timestamps, in seconds.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Session {
+    id: String,
+    created: i64,
+    expires: i64,
+    pending_expires: i64,
+    nonce: String,
+    tee: Tee,
+    tee_extra_params: Option<String>,
+    tee_pub_key: Option<TeeKey>,
+    authenticated: bool,
+    attestation_claims: Option<String>,
+    #[serde(default)]
+    transport: SessionTransport,
+    #[serde(default)]
+    tee_key_bound: bool,
+}
+
+#[allow(dead_code)]
+impl Session {
+    /// Create a session for `req`, expiring after `timeout` minutes, or after
+    /// `pending_timeout` seconds if it is not authenticated by then. The
+    /// clients of protocol version 0.2.0 and later use the header transport.
+    pub fn from_request(req: &Request, timeout: i64, pending_timeout: i64) -> Result<Self> {
+        let version = Version::parse(&req.version).map_err(anyhow::Error::from)?;
+        if !crate::VERSION_REQ.matches(&version) {
+            return Err(anyhow!("Invalid Request version {}", req.version));
+        }
+        let id = Uuid::new_v4().as_simple().to_string();
+        let tee_extra_params = if req.extra_params.is_empty() {
+            None
+        } else {
+            Some(req.extra_params.clone())
+        };
+
+        let transport = match HEADER_TRANSPORT_VERSION_REQ.matches(&version) {
+            true => SessionTransport::Header,
+            false => SessionTransport::Cookie,
+        };
+
+        let created = now();
+
+        Ok(Session {
+            id,
+            created,
+            expires: created + timeout * 60,
+            pending_expires: created + pending_timeout,
+            nonce: nonce()?,
+            tee: req.tee.clone(),
+            tee_extra_params,
+            tee_pub_key: None,
+            authenticated: false,
+            attestation_claims: None,
+            transport,
+            tee_key_bound: false,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn cookie(&self, config: &CookieConfig) -> Cookie<'static> {
+        let expires =
+            OffsetDateTime::from_unix_timestamp(self.expires).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        Cookie::build(KBS_SESSION_ID, self.id.clone())
+            .expires(expires)
+            .secure(config.secure.unwrap_or(true))
+            .http_only(config.http_only)
+            .same_site(config.same_site.into())
+            .path(config.path.clone())
+            .finish()
+    }
+
+    pub fn transport(&self) -> SessionTransport {
+        self.transport
+    }
+
+    pub fn nonce(&self) -> &str {
+        &self.nonce
+    }
+
+    pub fn tee(&self) -> Tee {
+        self.tee.clone()
+    }
+
+    pub fn tee_public_key(&self) -> Option<TeeKey> {
+        self.tee_pub_key.clone()
+    }
+
+    pub fn attestation_claims(&self) -> Option<String> {
+        self.attestation_claims.clone()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    pub fn set_authenticated(&mut self) {
+        self.authenticated = true
+    }
+
+    /// Unix time the session was created at.
+    pub fn created_at(&self) -> i64 {
+        self.created
+    }
+
+    /// Unix time the session expires at.
+    pub fn expires_at(&self) -> i64 {
+        match self.authenticated {
+            true => self.expires,
+            false => self.expires.min(self.pending_expires),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now() >= self.expires_at()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.is_authenticated() && !self.is_expired()
+    }
+
+    pub fn set_tee_public_key(&mut self, key: TeeKey) {
+        self.tee_pub_key = Some(key)
+    }
+
+    pub fn set_attestation_claims(&mut self, claims: String) {
+        self.attestation_claims = Some(claims)
+    }
+
+    /// Whether the requests on the session must carry a proof signed with
+    /// the TEE private key.
+    pub fn is_tee_key_bound(&self) -> bool {
+        self.tee_key_bound
+    }
+
+    pub fn bind_tee_key(&mut self) {
+        self.tee_key_bound = true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn request(version: &str) -> Request {
+        Request {
+            version: version.to_string(),
+            tee: Tee::Sample,
+            extra_params: String::new(),
+        }
+    }
+
+    #[rstest]
+    #[case("0.1.0", Some(SessionTransport::Cookie))]
+    #[case("0.2.0", Some(SessionTransport::Header))]
+    #[case("0.3.0", None)]
+    fn negotiate_transport(#[case] version: &str, #[case] transport: Option<SessionTransport>) {
+        let session = Session::from_request(&request(version), 5, 60);
+        assert_eq!(session.ok().map(|session| session.transport()), transport);
+    }
+
+    #[test]
+    fn cookie_attributes() {
+        let session = Session::from_request(&request("0.1.0"), 5, 60).unwrap();
+
+        let cookie = session.cookie(&CookieConfig::default());
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.path(), Some("/kbs"));
+
+        let cookie = session.cookie(&CookieConfig {
+            secure: Some(false),
+            same_site: CookieSameSite::Lax,
+            path: "/".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(cookie.secure(), Some(false));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/"));
+    }
+}
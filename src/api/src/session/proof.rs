@@ -0,0 +1,243 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Proof that a request on a key bound session comes from the holder of the
+//! TEE key the session was attested with.
+//!
+//! The proof is a JWT signed with the TEE private key, `RS256` for an `RSA`
+//! key, `ES256` or `ES384` for a `P-256` or `P-384` `EC` key. Its `htm` and
+//! `htu` claims are the method and path, with its query, of the request, its
+//! `iat` claim must be recent, and its `jti` claim must not have been used on
+//! the session yet.
+
+use super::SessionStore;
+use crate::jwe::TeeKey;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jwt_simple::prelude::{
+    Duration, ECDSAP256PublicKeyLike, ECDSAP384PublicKeyLike, ES256PublicKey, ES384PublicKey,
+    JWTClaims, RS256PublicKey, RSAPublicKeyLike, VerificationOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header carrying the proof of the requests on key bound sessions.
+pub(crate) static KBS_SESSION_PROOF_HEADER: &str = "KBS-Session-Proof";
+
+/// Maximum age of a proof, in seconds.
+const MAX_PROOF_AGE: u64 = 60;
+
+/// Tolerated clock drift between the KBS and the clients, in seconds.
+const MAX_CLOCK_DRIFT: u64 = 60;
+
+/// Maximum length of the `jti` claim.
+const MAX_PROOF_ID_LENGTH: usize = 128;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ProofClaims {
+    /// HTTP method of the request.
+    pub htm: String,
+    /// Path of the request, followed by its query if any.
+    pub htu: String,
+}
+
+/// Verify the proof carried by `request` on the session `id`, signed with the
+/// private key of `tee_key`, and record it in `store` so that it cannot be
+/// replayed.
+pub(crate) async fn verify_proof(
+    request: &HttpRequest,
+    tee_key: &TeeKey,
+    store: &dyn SessionStore,
+    id: &str,
+) -> Result<()> {
+    let (proof_id, ttl) = check_proof(request, tee_key)?;
+    if !store
+        .record_proof(id, &proof_id, ttl)
+        .await
+        .context("record proof")?
+    {
+        bail!("proof {proof_id} already used");
+    }
+
+    Ok(())
+}
+
+/// Check the proof carried by `request`, returning its id and the number of
+/// seconds during which it would still be accepted.
+fn check_proof(request: &HttpRequest, tee_key: &TeeKey) -> Result<(String, u64)> {
+    let proof = request
+        .headers()
+        .get(KBS_SESSION_PROOF_HEADER)
+        .ok_or_else(|| anyhow!("no {KBS_SESSION_PROOF_HEADER} header"))?
+        .to_str()
+        .context("illegal proof")?;
+
+    let options = VerificationOptions {
+        max_validity: Some(Duration::from_secs(MAX_PROOF_AGE)),
+        time_tolerance: Some(Duration::from_secs(MAX_CLOCK_DRIFT)),
+        ..Default::default()
+    };
+
+    let claims: JWTClaims<ProofClaims> = match tee_key {
+        TeeKey::Rsa { n, e, .. } => RS256PublicKey::from_components(&decode(n)?, &decode(e)?)
+            .map_err(|e| anyhow!("illegal RSA TEE key: {e}"))?
+            .verify_token(proof, Some(options)),
+        TeeKey::Ec { crv, x, y, .. } => {
+            // SEC1 uncompressed point.
+            let point = [&[0x04][..], &decode(x)?, &decode(y)?].concat();
+            match crv.as_str() {
+                "P-256" => ES256PublicKey::from_bytes(&point)
+                    .map_err(|e| anyhow!("illegal EC TEE key: {e}"))?
+                    .verify_token(proof, Some(options)),
+                "P-384" => ES384PublicKey::from_bytes(&point)
+                    .map_err(|e| anyhow!("illegal EC TEE key: {e}"))?
+                    .verify_token(proof, Some(options)),
+                crv => bail!("unsupported TEE key curve {crv} for session proofs"),
+            }
+        }
+        TeeKey::Okp { .. } => bail!("OKP TEE keys cannot sign session proofs"),
+    }
+    .map_err(|e| anyhow!("proof verification failed: {e}"))?;
+
+    let Some(issued_at) = claims.issued_at else {
+        bail!("no `iat` in the proof");
+    };
+    let proof_id = match claims.jwt_id {
+        Some(proof_id) if !proof_id.is_empty() => proof_id,
+        _ => bail!("no `jti` in the proof"),
+    };
+    if proof_id.len() > MAX_PROOF_ID_LENGTH {
+        bail!("`jti` longer than {MAX_PROOF_ID_LENGTH} bytes in the proof");
+    }
+    // The query selects e.g. the resource version.
+    let htu = match request.query_string() {
+        "" => request.path().to_string(),
+        query => format!("{}?{query}", request.path()),
+    };
+    if claims.custom.htm != request.method().as_str() || claims.custom.htu != htu {
+        bail!(
+            "proof issued for {} {}",
+            claims.custom.htm,
+            claims.custom.htu
+        );
+    }
+
+    // The proof is accepted until it is `MAX_PROOF_AGE` old, give or take
+    // the clock drift.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let ttl = (issued_at.as_secs() + MAX_PROOF_AGE + MAX_CLOCK_DRIFT).saturating_sub(now);
+
+    Ok((proof_id, ttl.max(1)))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .context("illegal TEE key base64url component")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::memory::MemorySessionStore;
+    use actix_web::test::TestRequest;
+    use jwt_simple::prelude::{Claims, ECDSAP256KeyPairLike, ES256KeyPair};
+
+    const PATH: &str = "/kbs/v0/resource/default/key/1";
+
+    fn tee_key(key_pair: &ES256KeyPair) -> TeeKey {
+        let point = key_pair.public_key().to_bytes_uncompressed();
+        TeeKey::Ec {
+            alg: "ECDH-ES".to_string(),
+            crv: "P-256".to_string(),
+            x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+            y: URL_SAFE_NO_PAD.encode(&point[33..]),
+        }
+    }
+
+    fn claims(htm: &str, htu: &str) -> JWTClaims<ProofClaims> {
+        Claims::with_custom_claims(
+            ProofClaims {
+                htm: htm.to_string(),
+                htu: htu.to_string(),
+            },
+            Duration::from_secs(MAX_PROOF_AGE),
+        )
+    }
+
+    fn proof_request(
+        key_pair: &ES256KeyPair,
+        uri: &str,
+        claims: JWTClaims<ProofClaims>,
+    ) -> HttpRequest {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header((KBS_SESSION_PROOF_HEADER, key_pair.sign(claims).unwrap()))
+            .to_http_request()
+    }
+
+    fn signed_request(key_pair: &ES256KeyPair, uri: &str, htm: &str, htu: &str) -> HttpRequest {
+        let claims = claims(htm, htu).with_jwt_id(uuid::Uuid::new_v4().to_string());
+        proof_request(key_pair, uri, claims)
+    }
+
+    #[test]
+    fn valid_proof() {
+        let key_pair = ES256KeyPair::generate();
+        let request = signed_request(&key_pair, PATH, "GET", PATH);
+        let (_, ttl) = check_proof(&request, &tee_key(&key_pair)).unwrap();
+        assert!(ttl > MAX_PROOF_AGE && ttl <= MAX_PROOF_AGE + MAX_CLOCK_DRIFT);
+
+        let uri = format!("{PATH}?version=2");
+        let request = signed_request(&key_pair, &uri, "GET", &uri);
+        check_proof(&request, &tee_key(&key_pair)).unwrap();
+    }
+
+    #[test]
+    fn invalid_proofs() {
+        let key_pair = ES256KeyPair::generate();
+
+        // Signed by another key.
+        let request = signed_request(&ES256KeyPair::generate(), PATH, "GET", PATH);
+        assert!(check_proof(&request, &tee_key(&key_pair)).is_err());
+
+        // Issued for another request.
+        let request = signed_request(&key_pair, PATH, "GET", "/kbs/v0/resource/default/key/2");
+        assert!(check_proof(&request, &tee_key(&key_pair)).is_err());
+        let request = signed_request(&key_pair, &format!("{PATH}?version=1"), "GET", PATH);
+        assert!(check_proof(&request, &tee_key(&key_pair)).is_err());
+
+        // Without id.
+        let request = proof_request(&key_pair, PATH, claims("GET", PATH));
+        assert!(check_proof(&request, &tee_key(&key_pair)).is_err());
+
+        // Missing.
+        let request = TestRequest::get().uri(PATH).to_http_request();
+        assert!(check_proof(&request, &tee_key(&key_pair)).is_err());
+    }
+
+    #[tokio::test]
+    async fn replayed_proof() {
+        let key_pair = ES256KeyPair::generate();
+        let store = MemorySessionStore::new(2);
+        let request = signed_request(&key_pair, PATH, "GET", PATH);
+
+        verify_proof(&request, &tee_key(&key_pair), &store, "session")
+            .await
+            .unwrap();
+        assert!(
+            verify_proof(&request, &tee_key(&key_pair), &store, "session")
+                .await
+                .is_err()
+        );
+
+        // A new proof is accepted.
+        let request = signed_request(&key_pair, PATH, "GET", PATH);
+        verify_proof(&request, &tee_key(&key_pair), &store, "session")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{Session, SessionStore};
+use crate::metrics::SESSIONS;
+use actix_web::cookie::time::OffsetDateTime;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+
+pub const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+const DEFAULT_KEY_PREFIX: &str = "kbs";
+
+fn default_url() -> String {
+    DEFAULT_REDIS_URL.to_string()
+}
+
+fn default_key_prefix() -> String {
+    DEFAULT_KEY_PREFIX.to_string()
+}
+
+/// A session is the `<key_prefix>:session:<id>` key, expiring with the
+/// session. The pending sessions are ranked by last use in the
+/// `<key_prefix>:pending` sorted set, and the proof ids recorded on a session
+/// are the `<key_prefix>:proof:<id>:<proof id>` keys.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedisSessionStoreDesc {
+    /// Server URL, e.g. `redis://:password@redis.example.com:6379/0`.
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// Prefix of the keys, to share a server between several KBS clusters.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+}
+
+impl Default for RedisSessionStoreDesc {
+    fn default() -> Self {
+        Self {
+            url: default_url(),
+            key_prefix: default_key_prefix(),
+        }
+    }
+}
+
+/// Sessions kept by a server speaking the Redis protocol, and shared by all
+/// the KBS replicas using it.
+pub(crate) struct RedisSessionStore {
+    // Cloning the connection manager is cheap and shares the underlying
+    // multiplexed connection. Each command works on its own clone.
+    connection: ConnectionManager,
+    key_prefix: String,
+    max_pending_sessions: usize,
+}
+
+impl RedisSessionStore {
+    pub(crate) async fn new(
+        desc: &RedisSessionStoreDesc,
+        max_pending_sessions: usize,
+    ) -> Result<Self> {
+        let client = redis::Client::open(desc.url.as_str()).context("invalid Redis URL")?;
+        let connection = client
+            .get_tokio_connection_manager()
+            .await
+            .context("connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            key_prefix: desc.key_prefix.clone(),
+            max_pending_sessions,
+        })
+    }
+
+    fn session_key(&self, id: &str) -> String {
+        format!("{}:session:{id}", self.key_prefix)
+    }
+
+    fn proof_key(&self, id: &str, proof_id: &str) -> String {
+        format!("{}:proof:{id}:{proof_id}", self.key_prefix)
+    }
+
+    fn pending_key(&self) -> String {
+        format!("{}:pending", self.key_prefix)
+    }
+
+    /// Counter ranking the pending sessions by last use, shared by the KBS
+    /// replicas whose clocks may differ.
+    fn clock_key(&self) -> String {
+        format!("{}:clock", self.key_prefix)
+    }
+
+    async fn tick(&self) -> Result<i64> {
+        redis::cmd("INCR")
+            .arg(self.clock_key())
+            .query_async(&mut self.connection.clone())
+            .await
+            .context("increment Redis session clock")
+    }
+
+    /// Keys of the stored sessions, of all the KBS replicas.
+    async fn session_keys(&self) -> Result<Vec<String>> {
+        let mut connection = self.connection.clone();
+        let pattern = self.session_key("*");
+        let mut keys: Vec<String> = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, mut batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut connection)
+                .await
+                .context("scan Redis sessions")?;
+            keys.append(&mut batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        // A key may be returned several times by the scan.
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Remove the expired sessions from the pending sessions ranking.
+    async fn reap_pending(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let pending: Vec<String> = redis::cmd("ZRANGE")
+            .arg(self.pending_key())
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut connection)
+            .await
+            .context("list Redis pending sessions")?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in &pending {
+            pipe.cmd("EXISTS").arg(self.session_key(id));
+        }
+        let exists: Vec<bool> = pipe
+            .query_async(&mut connection)
+            .await
+            .context("check Redis pending sessions")?;
+
+        let expired: Vec<&String> = pending
+            .iter()
+            .zip(exists)
+            .filter_map(|(id, exists)| (!exists).then_some(id))
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        redis::cmd("ZREM")
+            .arg(self.pending_key())
+            .arg(&expired)
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .context("remove Redis expired pending sessions")?;
+
+        log::info!("Evicted {} expired pending sessions", expired.len());
+        Ok(())
+    }
+}
+
+/// Time to live of `session` in milliseconds, not positive once expired.
+fn time_to_live(session: &Session) -> i64 {
+    let now = OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+    (session.expires_at() as i128 * 1000 - now) as i64
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn insert(&self, session: Session) -> Result<()> {
+        let ttl = time_to_live(&session);
+        if ttl <= 0 {
+            return Ok(());
+        }
+
+        let value = serde_json::to_string(&session)?;
+        let clock = self.tick().await?;
+        let mut connection = self.connection.clone();
+        redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(self.session_key(session.id()))
+            .arg(value)
+            .arg("PX")
+            .arg(ttl)
+            .ignore()
+            .cmd("ZADD")
+            .arg(self.pending_key())
+            .arg(clock)
+            .arg(session.id())
+            .ignore()
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .context("store Redis session")?;
+
+        let pending: usize = redis::cmd("ZCARD")
+            .arg(self.pending_key())
+            .query_async(&mut connection)
+            .await
+            .context("count Redis pending sessions")?;
+        let excess = pending.saturating_sub(self.max_pending_sessions.max(1));
+        if excess == 0 {
+            return Ok(());
+        }
+
+        let evicted: Vec<(String, f64)> = redis::cmd("ZPOPMIN")
+            .arg(self.pending_key())
+            .arg(excess)
+            .query_async(&mut connection)
+            .await
+            .context("evict Redis pending sessions")?;
+        for (id, _) in evicted {
+            log::info!("Evict pending session {id}");
+            redis::cmd("DEL")
+                .arg(self.session_key(&id))
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .context("delete Redis session")?;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Session>> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(self.session_key(id))
+            .query_async(&mut self.connection.clone())
+            .await
+            .context("get Redis session")?;
+
+        value
+            .map(|value| serde_json::from_str(&value).context("illegal Redis session"))
+            .transpose()
+    }
+
+    async fn update(&self, session: Session) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let ttl = time_to_live(&session);
+        if ttl <= 0 {
+            redis::pipe()
+                .atomic()
+                .cmd("DEL")
+                .arg(self.session_key(session.id()))
+                .ignore()
+                .cmd("ZREM")
+                .arg(self.pending_key())
+                .arg(session.id())
+                .ignore()
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .context("delete Redis session")?;
+            return Ok(());
+        }
+
+        let value = serde_json::to_string(&session)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("SET")
+            .arg(self.session_key(session.id()))
+            .arg(value)
+            .arg("PX")
+            .arg(ttl)
+            .arg("XX")
+            .ignore();
+        if session.is_authenticated() {
+            pipe.cmd("ZREM")
+                .arg(self.pending_key())
+                .arg(session.id())
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut connection)
+            .await
+            .context("update Redis session")
+    }
+
+    async fn touch_pending(&self, id: &str) -> Result<()> {
+        let clock = self.tick().await?;
+        redis::cmd("ZADD")
+            .arg(self.pending_key())
+            .arg("XX")
+            .arg(clock)
+            .arg(id)
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await
+            .context("touch Redis pending session")
+    }
+
+    /// The session keys expire on their own. Only the pending sessions
+    /// ranking is cleaned up, and the sessions of all the replicas counted.
+    async fn reap(&self) -> Result<()> {
+        self.reap_pending().await?;
+        SESSIONS.set(self.session_keys().await?.len() as i64);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Session>> {
+        let keys = self.session_keys().await?;
+        if keys.is_empty() {
+            SESSIONS.set(0);
+            return Ok(Vec::new());
+        }
+
+        // The sessions removed since the scan have no value.
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut self.connection.clone())
+            .await
+            .context("get Redis sessions")?;
+
+        let mut sessions = Vec::new();
+        for value in values.into_iter().flatten() {
+            let session: Session = serde_json::from_str(&value).context("illegal Redis session")?;
+            if !session.is_expired() {
+                sessions.push(session);
+            }
+        }
+        SESSIONS.set(sessions.len() as i64);
+
+        Ok(sessions)
+    }
+
+    async fn remove(&self, id: &str) -> Result<bool> {
+        let (deleted, _): (usize, usize) = redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg(self.session_key(id))
+            .cmd("ZREM")
+            .arg(self.pending_key())
+            .arg(id)
+            .query_async(&mut self.connection.clone())
+            .await
+            .context("delete Redis session")?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn record_proof(&self, id: &str, proof_id: &str, ttl: u64) -> Result<bool> {
+        let recorded: Option<String> = redis::cmd("SET")
+            .arg(self.proof_key(id, proof_id))
+            .arg(1)
+            .arg("EX")
+            .arg(ttl)
+            .arg("NX")
+            .query_async(&mut self.connection.clone())
+            .await
+            .context("record Redis session proof")?;
+
+        Ok(recorded.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::conformance::session_store_conformance_tests;
+
+    /// Create an empty store on a Redis server, e.g. `redis-server`, given by
+    /// the `REDIS_URL` environment variable.
+    async fn redis_server() -> RedisSessionStore {
+        let url = std::env::var("REDIS_URL").unwrap_or(DEFAULT_REDIS_URL.into());
+
+        RedisSessionStore::new(
+            &RedisSessionStoreDesc {
+                url,
+                // Start from an empty store.
+                key_prefix: format!("kbs-test-{}", uuid::Uuid::new_v4()),
+            },
+            2,
+        )
+        .await
+        .unwrap()
+    }
+
+    session_store_conformance_tests!(
+        redis_server().await,
+        #[ignore = "requires a Redis server, given by REDIS_URL"]
+    );
+}
@@ -0,0 +1,56 @@
+// Copyright (c) 2023 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+
+/// TEE public key, in JWK format.
+///
+/// `RSA` keys wrap the content encryption key, while `EC` and `OKP` keys
+/// agree on it through ECDH-ES (RFC 7518 section 4.6).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kty")]
+pub(crate) enum TeeKey {
+    #[serde(rename = "RSA")]
+    Rsa { alg: String, n: String, e: String },
+
+    #[serde(rename = "EC")]
+    Ec {
+        alg: String,
+        crv: String,
+        x: String,
+        y: String,
+    },
+
+    #[serde(rename = "OKP")]
+    Okp { alg: String, crv: String, x: String },
+}
+
+impl TeeKey {
+    #[cfg_attr(not(feature = "resource"), allow(dead_code))]
+    pub(crate) fn alg(&self) -> &str {
+        match self {
+            TeeKey::Rsa { alg, .. } | TeeKey::Ec { alg, .. } | TeeKey::Okp { alg, .. } => alg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tee_key_from_jwk() {
+        let key: TeeKey = serde_json::from_value(
+            json!({"kty": "OKP", "alg": "ECDH-ES", "crv": "X25519", "x": "AA"}),
+        )
+        .unwrap();
+        assert!(matches!(key, TeeKey::Okp { .. }));
+        let key: TeeKey = serde_json::from_value(
+            json!({"kty": "RSA", "alg": "RSA-OAEP", "n": "AA", "e": "AQAB"}),
+        )
+        .unwrap();
+        assert!(matches!(key, TeeKey::Rsa { .. }));
+    }
+}
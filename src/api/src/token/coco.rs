@@ -2,20 +2,211 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::token::AttestationTokenVerifier;
+use crate::token::{AttestationTokenVerifier, AttestationTokenVerifierConfig};
 use anyhow::*;
-use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
 use base64::Engine;
+use log::warn;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
 use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
 use serde_json::Value;
+use std::path::Path;
+use x509_parser::parse_x509_certificate;
+use x509_parser::pem::Pem;
 
-#[derive(Default)]
-pub struct CoCoAttestationTokenVerifier {}
+pub struct CoCoAttestationTokenVerifier {
+    /// DER encoded trusted certificates, used to verify `x5c` chains.
+    trusted_certs: Vec<Vec<u8>>,
+
+    /// Pinned token signing keys, used to verify embedded JWKs.
+    /// This includes the RSA public keys of the trusted certificates.
+    trusted_keys: Vec<RsaPublicKey>,
+
+    /// Accept the tokens signed by their embedded key when no trust anchor
+    /// is configured.
+    insecure_embedded_jwk: bool,
+}
+
+impl CoCoAttestationTokenVerifier {
+    pub fn new(config: &AttestationTokenVerifierConfig) -> Result<Self> {
+        let mut verifier = Self {
+            trusted_certs: Vec::new(),
+            trusted_keys: Vec::new(),
+            insecure_embedded_jwk: config.insecure_embedded_jwk,
+        };
+
+        for path in &config.trusted_certs_paths {
+            verifier
+                .load_pem_file(path)
+                .with_context(|| format!("load trusted certs from {}", path.display()))?;
+        }
+
+        if let Some(path) = &config.trusted_jwks_path {
+            verifier
+                .load_jwks_file(path)
+                .with_context(|| format!("load trusted JWKS from {}", path.display()))?;
+        }
+
+        if !verifier.has_trust_anchors() {
+            match verifier.insecure_embedded_jwk {
+                true => warn!("No trusted certificates or keys configured, CoCo attestation tokens are verified with their embedded public key only"),
+                false => warn!("No trusted certificates or keys configured, CoCo attestation tokens are rejected"),
+            }
+        }
+
+        Ok(verifier)
+    }
+
+    fn has_trust_anchors(&self) -> bool {
+        !self.trusted_certs.is_empty() || !self.trusted_keys.is_empty()
+    }
+
+    /// Whether tokens are only verified with their own key or certificate.
+    fn is_self_signed_trusted(&self) -> Result<bool> {
+        match (self.has_trust_anchors(), self.insecure_embedded_jwk) {
+            (true, _) => Ok(false),
+            (false, true) => Ok(true),
+            (false, false) => {
+                bail!("no trusted certificates or keys configured for attestation tokens")
+            }
+        }
+    }
+
+    fn load_pem_file(&mut self, path: &Path) -> Result<()> {
+        let data = std::fs::read(path)?;
+        let mut found = false;
+        for pem in Pem::iter_from_buffer(&data) {
+            let pem = pem.map_err(|e| anyhow!("illegal PEM: {e}"))?;
+            match pem.label.as_str() {
+                "CERTIFICATE" => {
+                    let (_, cert) = parse_x509_certificate(&pem.contents)
+                        .map_err(|e| anyhow!("illegal certificate: {e}"))?;
+                    // Any certificate anchors `x5c` chains, but only an RSA
+                    // one can sign the tokens with an embedded JWK.
+                    if let Some(key) = RsaPublicKey::from_public_key_der(cert.public_key().raw).ok()
+                    {
+                        self.trusted_keys.push(key);
+                    }
+                    self.trusted_certs.push(pem.contents.clone());
+                }
+                // Public keys, either as `PUBLIC KEY` or `RSA PUBLIC KEY`.
+                _ => {
+                    let key = RsaPublicKey::from_public_key_der(&pem.contents)
+                        .or_else(|_| RsaPublicKey::from_pkcs1_der(&pem.contents))
+                        .map_err(|_| {
+                            anyhow!("PEM item is neither a certificate nor an RSA public key")
+                        })?;
+                    self.trusted_keys.push(key);
+                }
+            }
+            found = true;
+        }
+
+        if !found {
+            bail!("no certificate or public key found");
+        }
+
+        Ok(())
+    }
+
+    fn load_jwks_file(&mut self, path: &Path) -> Result<()> {
+        let jwks = serde_json::from_slice::<Value>(&std::fs::read(path)?)?;
+        let keys = jwks["keys"]
+            .as_array()
+            .ok_or_else(|| anyhow!("JWKS must contain a `keys` array"))?;
+
+        for key in keys {
+            if key["kty"].as_str() != Some("RSA") {
+                warn!("Skipping non RSA key in trusted JWKS: {}", key["kid"]);
+                continue;
+            }
+            let jwk = serde_json::from_value::<RsaJWK>(key.clone())?;
+            self.trusted_keys.push(jwk.public_key()?);
+        }
+
+        Ok(())
+    }
+
+    /// Verify the `x5c` certificate chain of the token header, and return the
+    /// public key of its leaf certificate.
+    fn verify_cert_chain(&self, x5c: &[Value]) -> Result<RsaPublicKey> {
+        // `x5c` items are base64 (not base64url) encoded DER certificates.
+        let chain = x5c
+            .iter()
+            .map(|c| {
+                let c = c
+                    .as_str()
+                    .ok_or_else(|| anyhow!("`x5c` item must be a string"))?;
+                STANDARD.decode(c).map_err(Error::from)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let certs = chain
+            .iter()
+            .map(|der| {
+                parse_x509_certificate(der)
+                    .map(|(_, cert)| cert)
+                    .map_err(|e| anyhow!("illegal `x5c` certificate: {e}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (Some(leaf), Some(top)) = (certs.first(), certs.last()) else {
+            bail!("empty `x5c` certificate chain");
+        };
+
+        if certs.iter().any(|cert| !cert.validity().is_valid()) {
+            bail!("`x5c` certificate chain is expired or not yet valid");
+        }
+
+        for pair in certs.windows(2) {
+            if !pair[1].is_ca() {
+                bail!("`x5c` intermediate certificate is not a CA");
+            }
+            pair[0]
+                .verify_signature(Some(pair[1].public_key()))
+                .map_err(|e| anyhow!("`x5c` certificate chain verification failed: {e}"))?;
+        }
+
+        if !self.is_self_signed_trusted()? {
+            let top_der = chain.last().expect("chain has as many items as certs");
+            let anchored = self.trusted_certs.iter().any(|anchor| {
+                anchor == top_der
+                    || parse_x509_certificate(anchor)
+                        .map(|(_, anchor)| top.verify_signature(Some(anchor.public_key())).is_ok())
+                        .unwrap_or(false)
+            });
+            if !anchored {
+                bail!("`x5c` certificate chain does not chain to a trusted certificate");
+            }
+        }
+
+        RsaPublicKey::from_public_key_der(leaf.public_key().raw)
+            .map_err(|e| anyhow!("`x5c` leaf certificate must hold an RSA key: {e}"))
+    }
+
+    /// Get the public key from the JWK embedded in the token claims. It must
+    /// be one of the trusted keys, unless insecure embedded JWKs are allowed.
+    fn verify_embedded_jwk(&self, claims_value: &Value) -> Result<RsaPublicKey> {
+        let jwk_value = claims_value["jwk"].as_object().ok_or_else(|| anyhow!("CoCo Attestation Token Claims must contain public key (JWK format) to verify signature"))?;
+        let jwk = serde_json::from_value::<RsaJWK>(Value::Object(jwk_value.clone()))?;
+        if jwk.alg.as_deref() != Some("RS384") {
+            bail!("Unmatched RSA JWK alg");
+        }
+
+        let public_key = jwk.public_key()?;
+        if !self.is_self_signed_trusted()? && !self.trusted_keys.contains(&public_key) {
+            bail!("Token signing key is not trusted");
+        }
+
+        Ok(public_key)
+    }
+}
 
 impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
     fn verify(&self, token: String) -> Result<String> {
         let split_token: Vec<&str> = token.split('.').collect();
-        if !split_token.len() == 3 {
+        if split_token.len() != 3 {
             bail!("Illegal JWT format")
         }
 
@@ -39,14 +230,16 @@ impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
             }
         }
 
-        let jwk_value = claims_value["jwk"].as_object().ok_or_else(|| anyhow!("CoCo Attestation Token Claims must contain public key (JWK format) to verify signature"))?;
-        let jwk = serde_json::to_string(&jwk_value)?;
+        let public_key = match header_value["x5c"].as_array() {
+            Some(x5c) => self.verify_cert_chain(x5c)?,
+            None => self.verify_embedded_jwk(&claims_value)?,
+        };
         let payload = format!("{}.{}", &split_token[0], &split_token[1])
             .as_bytes()
             .to_vec();
 
         match header_value["alg"].as_str() {
-            Some("RS384") => rs384_verify(&payload, &signature, &jwk)?,
+            Some("RS384") => rs384_verify(&payload, &signature, public_key)?,
             None => {
                 bail!("Miss `alg` in JWT header")
             }
@@ -63,25 +256,192 @@ impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
 #[derive(serde::Deserialize, Clone, Debug)]
 struct RsaJWK {
     kty: String,
-    alg: String,
+    alg: Option<String>,
     n: String,
     e: String,
 }
 
-fn rs384_verify(payload: &[u8], signature: &[u8], jwk: &str) -> Result<()> {
-    let jwk = serde_json::from_str::<RsaJWK>(jwk)?;
-    if jwk.alg != *"RS384" {
-        bail!("Unmatched RSA JWK alg");
+impl RsaJWK {
+    fn public_key(&self) -> Result<RsaPublicKey> {
+        let n = rsa::BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&self.n)?);
+        let e = rsa::BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&self.e)?);
+        RsaPublicKey::new(n, e).map_err(Error::from)
     }
+}
 
+fn rs384_verify(payload: &[u8], signature: &[u8], public_key: RsaPublicKey) -> Result<()> {
     let pkcs1v15_signature = rsa::pkcs1v15::Signature::try_from(signature)?;
-
-    let n = rsa::BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&jwk.n)?);
-    let e = rsa::BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&jwk.e)?);
-    let rsa_public_key = rsa::RsaPublicKey::new(n, e)?;
-    let verify_key = rsa::pkcs1v15::VerifyingKey::<rsa::sha2::Sha384>::new(rsa_public_key);
+    let verify_key = rsa::pkcs1v15::VerifyingKey::<rsa::sha2::Sha384>::new(public_key);
 
     verify_key
         .verify(payload, &pkcs1v15_signature)
         .map_err(|e| anyhow!("RS384 verify failed: {e}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::signature::{SignatureEncoding, Signer};
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    const TEST_DATA_DIR: &str = "../../test/data/token";
+
+    fn test_data(name: &str) -> PathBuf {
+        PathBuf::from(TEST_DATA_DIR).join(name)
+    }
+
+    fn signer_jwk(key: &RsaPrivateKey) -> Value {
+        json!({
+            "kty": "RSA",
+            "alg": "RS384",
+            "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        })
+    }
+
+    /// Build a token signed by the test signer key, either embedding its JWK
+    /// in the claims or its certificate in the `x5c` header.
+    fn signed_token(with_x5c: bool) -> String {
+        match with_x5c {
+            true => x5c_token("signer.key", &["signer.pem"]),
+            false => token("signer.key", None),
+        }
+    }
+
+    /// Build a token signed by the `key` file, with the `x5c` header made of
+    /// the `chain` certificate files.
+    fn x5c_token(key: &str, chain: &[&str]) -> String {
+        let x5c = chain
+            .iter()
+            .map(|cert| {
+                let cert = std::fs::read(test_data(cert)).unwrap();
+                let cert = Pem::iter_from_buffer(&cert).next().unwrap().unwrap();
+                STANDARD.encode(cert.contents)
+            })
+            .collect();
+        token(key, Some(x5c))
+    }
+
+    fn token(key: &str, x5c: Option<Vec<String>>) -> String {
+        let key_pem = std::fs::read_to_string(test_data(key)).unwrap();
+        let key = RsaPrivateKey::from_pkcs8_pem(&key_pem).unwrap();
+
+        let mut header = json!({ "alg": "RS384", "typ": "JWT" });
+        let mut claims = json!({
+            "exp": time::OffsetDateTime::now_utc().unix_timestamp() + 600,
+            "tcb-status": {},
+        });
+        match x5c {
+            Some(x5c) => header["x5c"] = json!(x5c),
+            None => claims["jwk"] = signer_jwk(&key),
+        }
+
+        let payload = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signing_key = rsa::pkcs1v15::SigningKey::<rsa::sha2::Sha384>::new(key);
+        let signature = signing_key.sign(payload.as_bytes()).to_vec();
+
+        format!("{payload}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn verifier(trusted_certs: &[&str]) -> CoCoAttestationTokenVerifier {
+        CoCoAttestationTokenVerifier::new(&AttestationTokenVerifierConfig {
+            trusted_certs_paths: trusted_certs.iter().map(|c| test_data(c)).collect(),
+            ..Default::default()
+        })
+        .expect("create verifier failed")
+    }
+
+    fn insecure_verifier() -> CoCoAttestationTokenVerifier {
+        CoCoAttestationTokenVerifier::new(&AttestationTokenVerifierConfig {
+            insecure_embedded_jwk: true,
+            ..Default::default()
+        })
+        .expect("create verifier failed")
+    }
+
+    #[test]
+    fn embedded_jwk_without_trust_anchors() {
+        assert!(verifier(&[]).verify(signed_token(false)).is_err());
+        assert!(verifier(&[]).verify(signed_token(true)).is_err());
+        assert!(insecure_verifier().verify(signed_token(false)).is_ok());
+    }
+
+    #[test]
+    fn embedded_jwk_must_be_trusted() {
+        assert!(verifier(&["signer.pub"])
+            .verify(signed_token(false))
+            .is_ok());
+        assert!(verifier(&["signer.pem"])
+            .verify(signed_token(false))
+            .is_ok());
+        assert!(verifier(&["other-ca.pem"])
+            .verify(signed_token(false))
+            .is_err());
+    }
+
+    #[test]
+    fn embedded_jwk_trusted_by_jwks() {
+        let key_pem = std::fs::read_to_string(test_data("signer.key")).unwrap();
+        let key = RsaPrivateKey::from_pkcs8_pem(&key_pem).unwrap();
+        let jwks = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            jwks.path(),
+            json!({ "keys": [signer_jwk(&key)] }).to_string(),
+        )
+        .unwrap();
+
+        let verifier = CoCoAttestationTokenVerifier::new(&AttestationTokenVerifierConfig {
+            trusted_certs_paths: vec![],
+            trusted_jwks_path: Some(jwks.path().to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(verifier.verify(signed_token(false)).is_ok());
+    }
+
+    #[test]
+    fn x5c_must_chain_to_trusted_cert() {
+        assert!(verifier(&["ca.pem"]).verify(signed_token(true)).is_ok());
+        assert!(verifier(&["other-ca.pem"])
+            .verify(signed_token(true))
+            .is_err());
+    }
+
+    #[test]
+    fn x5c_chains_to_ec_cert() {
+        assert!(verifier(&["ec-ca.pem"])
+            .verify(x5c_token("signer.key", &["ec-signer.pem"]))
+            .is_ok());
+        // An EC certificate cannot sign an embedded JWK.
+        assert!(verifier(&["ec-ca.pem"])
+            .verify(signed_token(false))
+            .is_err());
+    }
+
+    #[test]
+    fn x5c_intermediates_must_be_ca() {
+        assert!(verifier(&["ca.pem"])
+            .verify(x5c_token("leaf.key", &["leaf.pem", "signer.pem"]))
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_token() {
+        let token = signed_token(false);
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let mut claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        claims["tcb-status"] = json!({ "svn": "99" });
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        parts[1] = &claims;
+        assert!(insecure_verifier().verify(parts.join(".")).is_err());
+    }
+}
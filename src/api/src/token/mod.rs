@@ -5,6 +5,7 @@
 use anyhow::*;
 use serde::Deserialize;
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
 use strum_macros::EnumString;
 use tokio::sync::RwLock;
@@ -22,13 +23,36 @@ pub enum AttestationTokenVerifierType {
     CoCo,
 }
 
+/// Attestation token verifier configuration.
+///
+/// The configured certificates and keys are the trust anchors of the token
+/// signatures. When none is configured, tokens are rejected, unless
+/// `insecure_embedded_jwk` is set.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AttestationTokenVerifierConfig {
+    /// Paths to PEM files holding trusted certificates or public keys.
+    /// A token is accepted if its embedded JWK is one of the trusted keys,
+    /// or if its `x5c` certificate chain chains to a trusted certificate.
+    #[serde(default)]
+    pub trusted_certs_paths: Vec<PathBuf>,
+
+    /// Path to a JWKS file holding trusted token signing keys.
+    pub trusted_jwks_path: Option<PathBuf>,
+
+    /// Without trust anchors, verify the tokens with the key they embed,
+    /// which only proves their integrity, not their origin.
+    #[serde(default)]
+    pub insecure_embedded_jwk: bool,
+}
+
 impl AttestationTokenVerifierType {
     pub fn to_token_verifier(
         &self,
+        config: &AttestationTokenVerifierConfig,
     ) -> Result<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>> {
         match self {
             AttestationTokenVerifierType::CoCo => Ok(Arc::new(RwLock::new(
-                coco::CoCoAttestationTokenVerifier::default(),
+                coco::CoCoAttestationTokenVerifier::new(config)?,
             ))
                 as Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>),
         }
@@ -62,12 +62,27 @@ async fn main() -> Result<()> {
         kbs_config.insecure_http,
         #[cfg(feature = "as")]
         &attestation_service,
+        #[cfg(feature = "as")]
+        kbs_config.session_config.unwrap_or_default(),
         kbs_config.timeout,
         kbs_config.insecure_api,
+        kbs_config.metrics_config,
         #[cfg(feature = "resource")]
         kbs_config.repository_config.unwrap_or_default(),
         #[cfg(feature = "resource")]
+        kbs_config.repository_encryption_config,
+        #[cfg(feature = "resource")]
         kbs_config.attestation_token_type,
+        #[cfg(feature = "resource")]
+        kbs_config.attestation_token_config.unwrap_or_default(),
+        #[cfg(feature = "resource")]
+        kbs_config.jwe_config.unwrap_or_default(),
+        #[cfg(feature = "resource")]
+        kbs_config.key_derivation_config.unwrap_or_default(),
+        #[cfg(feature = "resource")]
+        kbs_config.audit_config,
+        #[cfg(feature = "certificate")]
+        kbs_config.certificate_issuer_config,
         #[cfg(feature = "opa")]
         kbs_config.policy_engine_config.unwrap_or_default(),
     )?;
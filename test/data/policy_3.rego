@@ -0,0 +1,16 @@
+package policy
+
+default allow = false
+
+path := split(data["resource-path"], "/")
+input_tcb := input["tcb-status"]
+
+allow {
+    count(path) == 3
+    input_tcb.productId == path[1]
+}
+
+reasons[msg] {
+    input_tcb.productId != path[1]
+    msg := sprintf("product %v cannot access %v", [input_tcb.productId, path[1]])
+}